@@ -0,0 +1,241 @@
+//! Compares Order dispatch through the `Brewery` work queue against the previous design, where
+//! every Brewer locked a shared `Arc<Mutex<mpsc::Receiver>>` to receive its next Order.
+//!
+//! The workload mirrors `examples/main.rs`: two sources producing 1,000,000 and 100,000 records
+//! run through a Steep, Skim, and Pour. Each design is run at several batch sizes and Brewer
+//! counts, since lock contention grows as batches shrink.
+//!
+//! Run with `cargo bench --bench brewery`.
+use rettle::brewery::{Brewery, make_tea};
+use rettle::ingredient::{Steep, Skim, Pour, Argument, SharedRecipe};
+
+use std::any::Any;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+const SOURCE_SIZES: [usize; 2] = [1000000, 100000];
+const BATCH_SIZES: [usize; 3] = [200, 50, 10];
+const BREWER_COUNTS: [usize; 3] = [2, 4, 8];
+const RUNS: u32 = 3;
+
+#[derive(Debug, PartialEq, Default, Clone)]
+struct TextTea {
+    x: Option<i32>,
+    str_val: Option<String>,
+    y: Option<bool>,
+}
+
+struct SteepArgs {
+    increment: i32,
+}
+
+impl Argument for SteepArgs {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct PourArgs {
+    counter: Arc<AtomicUsize>,
+}
+
+impl Argument for PourArgs {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Previous Brewery design, kept here as the baseline for comparison.
+mod mutex_brewery {
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+
+    type Order = Box<dyn FnOnce() + Send + 'static>;
+
+    enum OrderTea {
+        NewOrder(Order),
+        Terminate,
+    }
+
+    pub struct MutexBrewery {
+        brewers: Vec<Option<thread::JoinHandle<()>>>,
+        sender: mpsc::Sender<OrderTea>,
+    }
+
+    impl MutexBrewery {
+        pub fn new(size: usize) -> MutexBrewery {
+            let (sender, plain_rx) = mpsc::channel();
+            let rx: Arc<Mutex<mpsc::Receiver<OrderTea>>> = Arc::new(Mutex::new(plain_rx));
+
+            let brewers = (0 .. size)
+                .map(|_| {
+                    let receiver = Arc::clone(&rx);
+                    Some(thread::spawn(move || loop {
+                        let make_tea = receiver.lock().unwrap().recv().unwrap();
+                        match make_tea {
+                            OrderTea::NewOrder(order) => order(),
+                            OrderTea::Terminate => break,
+                        }
+                    }))
+                })
+                .collect();
+
+            MutexBrewery { brewers, sender }
+        }
+
+        pub fn take_order<F>(&self, f: F)
+            where F: FnOnce() + Send + 'static
+        {
+            self.sender.send(OrderTea::NewOrder(Box::new(f))).unwrap();
+        }
+    }
+
+    impl Drop for MutexBrewery {
+        fn drop(&mut self) {
+            for _ in &self.brewers {
+                self.sender.send(OrderTea::Terminate).unwrap();
+            }
+            for brewer in &mut self.brewers {
+                if let Some(thread) = brewer.take() {
+                    thread.join().unwrap();
+                }
+            }
+        }
+    }
+}
+
+use mutex_brewery::MutexBrewery;
+
+/// Builds the Steep, Skim, Pour recipe used by `examples/main.rs`.
+fn build_recipe(counter: Arc<AtomicUsize>) -> SharedRecipe<TextTea> {
+    let recipe: SharedRecipe<TextTea> = Arc::new(RwLock::new(Vec::new()));
+    {
+        let mut steps = recipe.write().unwrap();
+        steps.push(Box::new(Steep {
+            name: String::from("steep1"),
+            computation: Box::new(|tea_batch: Vec<TextTea>, args| {
+                let increment = match args {
+                    None => 0,
+                    Some(box_args) => box_args.as_any().downcast_ref::<SteepArgs>().unwrap().increment,
+                };
+                tea_batch
+                    .into_iter()
+                    .map(|mut tea| {
+                        tea.x = tea.x.map(|x| x - increment);
+                        tea
+                    })
+                    .collect()
+            }),
+            params: Some(Box::new(SteepArgs { increment: 10000 })),
+        }));
+        steps.push(Box::new(Skim {
+            name: String::from("skim1"),
+            computation: Box::new(|tea_batch: Vec<TextTea>, _args| {
+                tea_batch
+                    .into_iter()
+                    .map(|mut tea| {
+                        tea.y = None;
+                        tea
+                    })
+                    .collect()
+            }),
+            params: None,
+        }));
+        steps.push(Box::new(Pour {
+            name: String::from("pour1"),
+            computation: Box::new(|tea_batch: Vec<TextTea>, args| {
+                if let Some(box_args) = args {
+                    let box_args = box_args.as_any().downcast_ref::<PourArgs>().unwrap();
+                    box_args.counter.fetch_add(1, Ordering::Relaxed);
+                }
+                black_box(tea_batch)
+            }),
+            params: Some(Box::new(PourArgs { counter })),
+        }));
+    }
+    recipe
+}
+
+/// Produces every batch for the workload, handing each to `dispatch` as it would a Brewery.
+fn fill<D>(batch_size: usize, recipe: &SharedRecipe<TextTea>, dispatch: D)
+    where D: Fn(Vec<TextTea>, SharedRecipe<TextTea>)
+{
+    for total_data in SOURCE_SIZES.iter() {
+        for _ in 0 .. total_data / batch_size {
+            let mut tea_batch = Vec::with_capacity(batch_size);
+            for _ in 0 .. batch_size {
+                tea_batch.push(TextTea { x: Some(0), str_val: Some(String::new()), y: Some(true) });
+            }
+            dispatch(tea_batch, Arc::clone(recipe));
+        }
+    }
+}
+
+fn run_queue(brewers: usize, batch_size: usize) -> Duration {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let recipe = build_recipe(Arc::clone(&counter));
+    let start = Instant::now();
+    {
+        let brewery = Brewery::new(brewers);
+        fill(batch_size, &recipe, |tea_batch, recipe| {
+            brewery.take_order(|| {
+                make_tea(tea_batch, recipe);
+            });
+        });
+    }
+    let elapsed = start.elapsed();
+    assert_eq!(counter.load(Ordering::SeqCst), expected_batches(batch_size));
+    elapsed
+}
+
+fn run_mutex(brewers: usize, batch_size: usize) -> Duration {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let recipe = build_recipe(Arc::clone(&counter));
+    let start = Instant::now();
+    {
+        let brewery = MutexBrewery::new(brewers);
+        fill(batch_size, &recipe, |tea_batch, recipe| {
+            brewery.take_order(|| {
+                make_tea(tea_batch, recipe);
+            });
+        });
+    }
+    let elapsed = start.elapsed();
+    assert_eq!(counter.load(Ordering::SeqCst), expected_batches(batch_size));
+    elapsed
+}
+
+fn expected_batches(batch_size: usize) -> usize {
+    SOURCE_SIZES.iter().map(|total_data| total_data / batch_size).sum()
+}
+
+/// Returns the fastest of several runs to reduce scheduling noise.
+fn best_of<F: Fn() -> Duration>(run: F) -> Duration {
+    (0 .. RUNS).map(|_| run()).min().unwrap()
+}
+
+fn main() {
+    let mut results = Vec::new();
+    for &batch_size in BATCH_SIZES.iter() {
+        for &brewers in BREWER_COUNTS.iter() {
+            let mutex = best_of(|| run_mutex(brewers, batch_size));
+            let queue = best_of(|| run_queue(brewers, batch_size));
+            results.push((brewers, batch_size, mutex, queue));
+        }
+    }
+
+    // Results are printed last so they are not interleaved with the Brewery shutdown output.
+    println!("{:>8} {:>8} {:>14} {:>14} {:>8}", "brewers", "batch", "mutex (ms)", "queue (ms)", "speedup");
+    for (brewers, batch_size, mutex, queue) in results {
+        println!(
+            "{:>8} {:>8} {:>14} {:>14} {:>7.2}x",
+            brewers,
+            batch_size,
+            mutex.as_millis(),
+            queue.as_millis(),
+            mutex.as_secs_f64() / queue.as_secs_f64(),
+        );
+    }
+}
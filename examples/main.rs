@@ -79,6 +79,10 @@ fn main() {
             println!("Testing {} iterations", total_data);
 
             for _ in 0 .. num_iterations {
+                // Stop filling once the brew has been cancelled.
+                if brewery.is_cancelled() {
+                    break;
+                }
                 let mut tea_batch = Vec::with_capacity(batch_size);
                 for _ in 0 .. batch_size {
                     tea_batch.push(TextTea { x: Some(0), str_val: Some(String::new()), y: Some(true) });
@@ -111,6 +115,10 @@ fn main() {
             println!("Testing {} iterations", total_data);
 
             for _ in 0 .. num_iterations {
+                // Stop filling once the brew has been cancelled.
+                if brewery.is_cancelled() {
+                    break;
+                }
                 let mut tea_batch = Vec::with_capacity(batch_size);
                 for _ in 0 .. batch_size {
                     tea_batch.push(TextTea { x: Some(0), str_val: Some(String::new()), y: Some(true) });
@@ -136,6 +144,7 @@ fn main() {
                         None => panic!("No params passed, not editing object!"),
                         Some(box_args) => {
                             let box_args = box_args.as_any().downcast_ref::<SteepArgs>().unwrap();
+                            #[allow(clippy::manual_map)]
                             let new_val: Option<i32> = match tea.x {
                                 Some(x) => Some(x - box_args.increment),
                                 None => None
@@ -187,9 +196,18 @@ fn main() {
     }));
     
     // Process Tea
-    new_pot.brew(&brewery);
+    let report = match new_pot.brew(&brewery) {
+        Ok(report) => report,
+        Err(problems) => {
+            for problem in problems {
+                println!("Error: {}", problem);
+            }
+            return;
+        },
+    };
 
     // Display information
+    report.print();
     brewery.get_brewer_info();
     println!("Number of sources: {}", new_pot.get_sources().len());
     println!("Number of steps: {}", new_pot.get_recipe().read().unwrap().len());
@@ -0,0 +1,246 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+///
+/// Outcome of a batch sent with `Brewery::take_order_acked`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ack {
+    /// Every step of the recipe brewed the batch, so the source can commit it.
+    Acked,
+    /// The batch was not fully brewed, for the given reason, so the source should redeliver it.
+    Nacked(String),
+}
+
+///
+/// Handle to the outcome of a batch, such as a message from a queue, completed once the batch
+/// leaves the Brewery. Sources wait on it to commit or redeliver the batch.
+#[derive(Clone, Default)]
+pub struct AckHandle {
+    outcome: Arc<(Mutex<Option<Ack>>, Condvar)>,
+}
+
+impl AckHandle {
+    pub(crate) fn new() -> AckHandle {
+        AckHandle::default()
+    }
+
+    ///
+    /// Returns the outcome of the batch, or None while it is still brewing.
+    pub fn status(&self) -> Option<Ack> {
+        self.outcome.0.lock().unwrap().clone()
+    }
+
+    ///
+    /// Waits for the batch to leave the Brewery, returning its outcome.
+    pub fn wait(&self) -> Ack {
+        let (outcome, completed) = &*self.outcome;
+        let mut outcome = outcome.lock().unwrap();
+        loop {
+            if let Some(ack) = &*outcome {
+                return ack.clone();
+            }
+            outcome = completed.wait(outcome).unwrap();
+        }
+    }
+
+    ///
+    /// Waits up to `timeout` for the batch to leave the Brewery, returning its outcome, or None
+    /// if it is still brewing.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - longest time to wait
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<Ack> {
+        let (outcome, completed) = &*self.outcome;
+        let outcome = outcome.lock().unwrap();
+        let (outcome, _) = completed.wait_timeout_while(outcome, timeout, |outcome| outcome.is_none()).unwrap();
+        outcome.clone()
+    }
+
+    ///
+    /// Completes the handle. Only the first outcome is kept.
+    pub(crate) fn complete(&self, ack: Ack) {
+        let (outcome, completed) = &*self.outcome;
+        let mut outcome = outcome.lock().unwrap();
+        if outcome.is_none() {
+            *outcome = Some(ack);
+            completed.notify_all();
+        }
+    }
+}
+
+///
+/// Nacks a batch sent outside a brew if its Order is dropped, or panics, before it is brewed.
+pub(crate) struct Unbrewed(pub(crate) AckHandle);
+
+impl Drop for Unbrewed {
+    fn drop(&mut self) {
+        self.0.complete(Ack::Nacked(String::from("batch was not brewed")));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ack, AckHandle};
+    use super::super::branch::Branch;
+    use super::super::brewery::{Brewery, make_tea};
+    use super::super::cancel::{CancelToken, CancelMode};
+    use super::super::ingredient::{Fill, Steep, Pour, Argument};
+    use super::super::pot::Pot;
+    use std::any::Any;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq, Default, Clone)]
+    struct TestTea {
+        id: u32,
+        delivery: u32,
+    }
+
+    ///
+    /// Queue of messages that are removed once acked, and put back on the queue once nacked.
+    #[derive(Default)]
+    struct MemoryQueue {
+        messages: Mutex<VecDeque<TestTea>>,
+        committed: Mutex<Vec<u32>>,
+        nacked: Mutex<Vec<(u32, String)>>,
+    }
+
+    impl MemoryQueue {
+        fn new(ids: std::ops::Range<u32>) -> Arc<MemoryQueue> {
+            let queue = MemoryQueue::default();
+            queue.messages.lock().unwrap().extend(ids.map(|id| TestTea { id, delivery: 0 }));
+            Arc::new(queue)
+        }
+
+        fn receive(&self) -> Option<TestTea> {
+            let mut message = self.messages.lock().unwrap().pop_front()?;
+            message.delivery += 1;
+            Some(message)
+        }
+
+        fn settle(&self, message: TestTea, ack: Ack) {
+            match ack {
+                Ack::Acked => self.committed.lock().unwrap().push(message.id),
+                Ack::Nacked(reason) => {
+                    self.nacked.lock().unwrap().push((message.id, reason));
+                    self.messages.lock().unwrap().push_back(message);
+                },
+            }
+        }
+    }
+
+    struct QueueArgs {
+        pub queue: Arc<MemoryQueue>,
+    }
+
+    impl Argument for QueueArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct CancelArgs {
+        pub token: CancelToken,
+    }
+
+    impl Argument for CancelArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    /// Source receiving one message per batch until the queue is empty or the brew is cancelled,
+    /// settling each once its batch leaves the Brewery.
+    fn queue_fill(queue: &Arc<MemoryQueue>) -> Box<Fill<TestTea>> {
+        Box::new(Fill {
+            name: String::from("queue"),
+            source: String::from("memory"),
+            computation: Box::new(|args, brewery, recipe| {
+                let queue = &args.as_ref().unwrap().as_any().downcast_ref::<QueueArgs>().unwrap().queue;
+                while !brewery.is_cancelled() {
+                    let message = match queue.receive() {
+                        Some(message) => message,
+                        None => break,
+                    };
+                    let recipe = Arc::clone(&recipe);
+                    let tea_batch = vec![message.clone()];
+                    let ack = brewery.take_order_acked(move || {
+                        make_tea(tea_batch, recipe);
+                    });
+                    queue.settle(message, ack.wait());
+                }
+            }),
+            params: Some(Box::new(QueueArgs { queue: Arc::clone(queue) })),
+        })
+    }
+
+    fn pour() -> Box<Pour<TestTea>> {
+        Box::new(Pour {
+            name: String::from("pour"),
+            computation: Box::new(|tea_batch, _args| tea_batch),
+            params: None,
+        })
+    }
+
+    #[test]
+    fn failed_batches_are_redelivered() {
+        let queue = MemoryQueue::new(0 .. 5);
+        let flaky = Branch::new("archive").branch("flaky", vec![Box::new(Steep {
+            name: String::from("fails_first_delivery"),
+            computation: Box::new(|tea_batch: Vec<TestTea>, _args| {
+                if tea_batch[0].id == 3 && tea_batch[0].delivery == 1 {
+                    panic!("archive unavailable");
+                }
+                tea_batch
+            }),
+            params: None,
+        })]);
+        let new_pot = Pot::new()
+            .add_source(queue_fill(&queue))
+            .add_ingredient(Box::new(flaky))
+            .add_ingredient(pour());
+        let report = new_pot.brew(&Brewery::new(2)).unwrap();
+
+        assert_eq!(*queue.committed.lock().unwrap(), vec![0, 1, 2, 4, 3]);
+        assert_eq!(*queue.nacked.lock().unwrap(), vec![
+            (3, String::from("branch flaky failed: archive unavailable")),
+        ]);
+        assert!(queue.messages.lock().unwrap().is_empty());
+        assert_eq!(report.steps["pour"].records_in, 6);
+    }
+
+    #[test]
+    fn interrupted_batches_are_nacked() {
+        let queue = MemoryQueue::new(0 .. 3);
+        let token = CancelToken::new();
+        let new_pot = Pot::new()
+            .add_source(queue_fill(&queue))
+            .add_ingredient(Box::new(Steep {
+                name: String::from("cancel"),
+                computation: Box::new(|tea_batch: Vec<TestTea>, args| {
+                    if tea_batch[0].id == 1 {
+                        args.as_ref().unwrap().as_any().downcast_ref::<CancelArgs>().unwrap().token.cancel(CancelMode::Immediate);
+                    }
+                    tea_batch
+                }),
+                params: Some(Box::new(CancelArgs { token: token.clone() })),
+            }))
+            .add_ingredient(pour());
+        new_pot.brew_with_token(&Brewery::new(1), &token).unwrap();
+
+        assert_eq!(*queue.committed.lock().unwrap(), vec![0]);
+        assert_eq!(*queue.nacked.lock().unwrap(), vec![(1, String::from("batch was interrupted by a cancel"))]);
+        let waiting: Vec<u32> = queue.messages.lock().unwrap().iter().map(|message| message.id).collect();
+        assert_eq!(waiting, vec![2, 1]);
+    }
+
+    #[test]
+    fn orders_outside_a_brew_are_acked() {
+        let brewery = Brewery::new(1);
+        let ack = brewery.take_order_acked(|| {});
+        assert_eq!(ack.wait(), Ack::Acked);
+        assert_eq!(AckHandle::new().wait_timeout(Duration::from_millis(1)), None);
+    }
+}
@@ -0,0 +1,426 @@
+use crate::ingredient::Ingredient;
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, ThreadId};
+
+///
+/// Key Tea is grouped by in an Aggregate.
+pub type GroupKey<T> = fn(&T) -> String;
+
+///
+/// Value read from each Tea by a numeric Reducer.
+pub type Field<T> = fn(&T) -> f64;
+
+///
+/// Builds the Tea sent on for a group from its key and the result of each Reducer, in the order
+/// the Reducers were added.
+pub type Finish<T> = fn(&str, Vec<Reduced<T>>) -> T;
+
+///
+/// Built-in reductions run over each group of Tea.
+pub enum Reducer<T> {
+    /// Number of Tea in the group.
+    Count,
+    /// Sum of the field over the group.
+    Sum(Field<T>),
+    /// Smallest value of the field in the group.
+    Min(Field<T>),
+    /// Largest value of the field in the group.
+    Max(Field<T>),
+    /// Mean of the field over the group.
+    Mean(Field<T>),
+    /// Every Tea in the group. Only one Collect can be used per Aggregate.
+    Collect,
+}
+
+///
+/// Result of a Reducer over one group, passed to the Aggregate's finish function.
+#[derive(Debug, PartialEq)]
+pub enum Reduced<T> {
+    Count(usize),
+    Sum(f64),
+    Min(f64),
+    Max(f64),
+    Mean(f64),
+    Collect(Vec<T>),
+}
+
+///
+/// Running state of one Reducer over one group.
+pub(crate) enum Partial<T> {
+    Count(usize),
+    Sum(f64),
+    Min(f64),
+    Max(f64),
+    Mean(f64, usize),
+    Collect(Vec<T>),
+}
+
+impl<T> Partial<T> {
+    pub(crate) fn new(reducer: &Reducer<T>) -> Partial<T> {
+        match reducer {
+            Reducer::Count => Partial::Count(0),
+            Reducer::Sum(_) => Partial::Sum(0.0),
+            Reducer::Min(_) => Partial::Min(f64::INFINITY),
+            Reducer::Max(_) => Partial::Max(f64::NEG_INFINITY),
+            Reducer::Mean(_) => Partial::Mean(0.0, 0),
+            Reducer::Collect => Partial::Collect(Vec::new()),
+        }
+    }
+
+    pub(crate) fn add(&mut self, reducer: &Reducer<T>, tea: &T) {
+        match (self, reducer) {
+            (Partial::Count(count), _) => *count += 1,
+            (Partial::Sum(sum), Reducer::Sum(field)) => *sum += field(tea),
+            (Partial::Min(min), Reducer::Min(field)) => *min = min.min(field(tea)),
+            (Partial::Max(max), Reducer::Max(field)) => *max = max.max(field(tea)),
+            (Partial::Mean(sum, count), Reducer::Mean(field)) => {
+                *sum += field(tea);
+                *count += 1;
+            },
+            _ => (),
+        }
+    }
+
+    pub(crate) fn merge(&mut self, other: Partial<T>) {
+        match (self, other) {
+            (Partial::Count(count), Partial::Count(other)) => *count += other,
+            (Partial::Sum(sum), Partial::Sum(other)) => *sum += other,
+            (Partial::Min(min), Partial::Min(other)) => *min = min.min(other),
+            (Partial::Max(max), Partial::Max(other)) => *max = max.max(other),
+            (Partial::Mean(sum, count), Partial::Mean(other_sum, other_count)) => {
+                *sum += other_sum;
+                *count += other_count;
+            },
+            (Partial::Collect(tea), Partial::Collect(other)) => tea.extend(other),
+            _ => (),
+        }
+    }
+
+    pub(crate) fn reduce(self) -> Reduced<T> {
+        match self {
+            Partial::Count(count) => Reduced::Count(count),
+            Partial::Sum(sum) => Reduced::Sum(sum),
+            Partial::Min(min) => Reduced::Min(min),
+            Partial::Max(max) => Reduced::Max(max),
+            Partial::Mean(sum, count) => Reduced::Mean(if count == 0 { 0.0 } else { sum / count as f64 }),
+            Partial::Collect(tea) => Reduced::Collect(tea),
+        }
+    }
+}
+
+///
+/// Adds a Tea to the running state of each Reducer, moving it into the group if collected.
+pub(crate) fn accumulate<T>(partials: &mut [Partial<T>], reducers: &[Reducer<T>], tea: T) {
+    for (partial, reducer) in partials.iter_mut().zip(reducers) {
+        partial.add(reducer, &tea);
+    }
+    if let Some(Partial::Collect(collected)) = partials.iter_mut().find(|partial| matches!(partial, Partial::Collect(_))) {
+        collected.push(tea);
+    }
+}
+
+///
+/// Merges the running state of a group seen elsewhere into this one.
+pub(crate) fn merge<T>(partials: &mut [Partial<T>], other: Vec<Partial<T>>) {
+    for (partial, other) in partials.iter_mut().zip(other) {
+        partial.merge(other);
+    }
+}
+
+///
+/// Splits Tea into batches of at most `batch_size`.
+pub(crate) fn rebatch<T>(tea: Vec<T>, batch_size: usize) -> Vec<Vec<T>> {
+    let mut tea_batches = Vec::new();
+    let mut tea_batch = Vec::with_capacity(batch_size.min(tea.len()));
+    for tea in tea {
+        tea_batch.push(tea);
+        if tea_batch.len() == batch_size {
+            tea_batches.push(tea_batch);
+            tea_batch = Vec::new();
+        }
+    }
+    if !tea_batch.is_empty() {
+        tea_batches.push(tea_batch);
+    }
+    tea_batches
+}
+
+///
+/// Groups seen by one Brewer, keyed by group key.
+type Groups<T> = HashMap<String, Vec<Partial<T>>>;
+
+///
+/// Ingredient used to group Tea by key and reduce each group across every batch of a brew.
+///
+/// Each Brewer keeps its own partial groups, found under a shared read lock that is only written
+/// the first time a Brewer aggregates a batch, so Brewers do not wait on each other while they
+/// aggregate. Nothing is passed on while brewing; once every batch is done the partial groups
+/// are merged and one Tea per group, built by the finish function, is sent on to the next steps.
+pub struct Aggregate<T: Send> {
+    pub name: String,
+    pub key: Box<GroupKey<T>>,
+    pub reducers: Vec<Reducer<T>>,
+    pub finish: Box<Finish<T>>,
+    /// Most Tea sent on in one batch once the groups are flushed.
+    pub batch_size: usize,
+    partials: RwLock<HashMap<ThreadId, Arc<Mutex<Groups<T>>>>>,
+}
+
+impl<T: Send> Aggregate<T> {
+    ///
+    /// Creates an Aggregate with no Reducers, flushing batches of up to 1000 groups.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the step
+    /// * `key` - key Tea is grouped by
+    /// * `finish` - builds the Tea sent on for each group
+    pub fn new(name: &str, key: GroupKey<T>, finish: Finish<T>) -> Aggregate<T> {
+        Aggregate {
+            name: String::from(name),
+            key: Box::new(key),
+            reducers: Vec::new(),
+            finish: Box::new(finish),
+            batch_size: 1000,
+            partials: RwLock::new(HashMap::new()),
+        }
+    }
+
+    ///
+    /// Adds a Reducer run over each group.
+    ///
+    /// # Arguments
+    ///
+    /// * `reducer` - the reduction to add
+    pub fn reduce(mut self, reducer: Reducer<T>) -> Aggregate<T> {
+        self.reducers.push(reducer);
+        self
+    }
+
+    ///
+    /// Sets the most Tea sent on in one batch once the groups are flushed.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch_size` - size of the flushed batches
+    pub fn batch_size(mut self, batch_size: usize) -> Aggregate<T> {
+        self.batch_size = batch_size;
+        self
+    }
+
+    ///
+    /// Returns the partial groups of the current Brewer.
+    fn groups(&self) -> Arc<Mutex<Groups<T>>> {
+        let brewer = thread::current().id();
+        if let Some(groups) = self.partials.read().unwrap().get(&brewer) {
+            return Arc::clone(groups);
+        }
+        Arc::clone(self.partials.write().unwrap().entry(brewer).or_default())
+    }
+}
+
+impl<T: Send + 'static> Ingredient<T> for Aggregate<T> {
+    fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
+        let groups = self.groups();
+        let mut groups = groups.lock().unwrap();
+        for tea in tea_batch {
+            let partials = groups
+                .entry((self.key)(&tea))
+                .or_insert_with(|| self.reducers.iter().map(Partial::new).collect());
+            accumulate(partials, &self.reducers, tea);
+        }
+        Vec::new()
+    }
+    fn exec_batches(&self, tea_batch: Vec<T>) -> Vec<Vec<T>> {
+        // Nothing is sent on until the groups are flushed.
+        self.exec(tea_batch);
+        Vec::new()
+    }
+    fn flush(&self) -> Vec<Vec<T>> {
+        let mut merged: Groups<T> = HashMap::new();
+        for (_, groups) in self.partials.write().unwrap().drain() {
+            for (key, partials) in groups.lock().unwrap().drain() {
+                match merged.get_mut(&key) {
+                    Some(merged) => merge(merged, partials),
+                    None => { merged.insert(key, partials); },
+                }
+            }
+        }
+
+        let mut groups: Vec<(String, Vec<Partial<T>>)> = merged.into_iter().collect();
+        groups.sort_by(|a, b| a.0.cmp(&b.0));
+        let tea = groups
+            .into_iter()
+            .map(|(key, partials)| (self.finish)(&key, partials.into_iter().map(Partial::reduce).collect()))
+            .collect();
+        rebatch(tea, self.batch_size)
+    }
+    fn get_name(&self) -> &str {
+        &self.name[..]
+    }
+    fn kind(&self) -> &str {
+        "Aggregate"
+    }
+    fn print(&self) {
+        println!("Current Step: {}", self.get_name());
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn validate(&self) -> Result<(), String> {
+        if self.batch_size == 0 {
+            return Err(String::from("batch_size must be greater than 0"));
+        }
+        if self.reducers.iter().filter(|reducer| matches!(reducer, Reducer::Collect)).count() > 1 {
+            return Err(String::from("only one Collect reducer can be used"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Aggregate, Reducer, Reduced};
+    use super::super::ingredient::{Fill, Pour, Argument, Ingredient};
+    use super::super::brewery::{Brewery, make_tea};
+    use super::super::pot::Pot;
+    use std::any::Any;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[derive(Debug, PartialEq, Default, Clone)]
+    struct TestTea {
+        customer: String,
+        amount: f64,
+    }
+
+    fn tea(customer: &str, amount: f64) -> TestTea {
+        TestTea { customer: String::from(customer), amount }
+    }
+
+    fn totals() -> Aggregate<TestTea> {
+        Aggregate::new("totals", |tea: &TestTea| tea.customer.clone(), |key, reduced| {
+            let amount = match reduced[1] {
+                Reduced::Sum(sum) => sum,
+                _ => 0.0,
+            };
+            TestTea { customer: format!("{} x{:?}", key, reduced[0]), amount }
+        })
+            .reduce(Reducer::Count)
+            .reduce(Reducer::Sum(|tea: &TestTea| tea.amount))
+    }
+
+    #[test]
+    fn aggregate_holds_tea_until_flushed() {
+        let aggregate = totals();
+        assert_eq!(aggregate.exec_batches(vec![tea("a", 1.0)]).len(), 0);
+        assert_eq!(aggregate.flush(), vec![vec![TestTea { customer: String::from("a xCount(1)"), amount: 1.0 }]]);
+        assert_eq!(aggregate.flush().len(), 0);
+    }
+
+    #[test]
+    fn aggregate_merges_partials_from_each_brewer() {
+        let aggregate = Arc::new(Aggregate::new("stats", |tea: &TestTea| tea.customer.clone(), |key, reduced| {
+            assert_eq!(key, "a");
+            assert_eq!(reduced, vec![
+                Reduced::Count(4),
+                Reduced::Sum(10.0),
+                Reduced::Min(1.0),
+                Reduced::Max(4.0),
+                Reduced::Mean(2.5),
+            ]);
+            TestTea::default()
+        })
+            .reduce(Reducer::Count)
+            .reduce(Reducer::Sum(|tea: &TestTea| tea.amount))
+            .reduce(Reducer::Min(|tea: &TestTea| tea.amount))
+            .reduce(Reducer::Max(|tea: &TestTea| tea.amount))
+            .reduce(Reducer::Mean(|tea: &TestTea| tea.amount)));
+
+        let brewers: Vec<_> = vec![vec![tea("a", 1.0), tea("a", 4.0)], vec![tea("a", 2.0), tea("a", 3.0)]]
+            .into_iter()
+            .map(|tea_batch| {
+                let aggregate = Arc::clone(&aggregate);
+                thread::spawn(move || aggregate.exec(tea_batch))
+            })
+            .collect();
+        for brewer in brewers {
+            brewer.join().unwrap();
+        }
+        assert_eq!(aggregate.partials.read().unwrap().len(), 2);
+        assert_eq!(aggregate.flush(), vec![vec![TestTea::default()]]);
+    }
+
+    #[test]
+    fn aggregate_collects_tea() {
+        let aggregate = Aggregate::new("collect", |tea: &TestTea| tea.customer.clone(), |_key, mut reduced| {
+            match reduced.pop() {
+                Some(Reduced::Collect(tea)) => TestTea { customer: tea[0].customer.clone(), amount: tea.len() as f64 },
+                _ => TestTea::default(),
+            }
+        })
+            .reduce(Reducer::Collect)
+            .batch_size(1);
+        aggregate.exec(vec![tea("a", 1.0), tea("b", 1.0), tea("a", 2.0)]);
+        assert_eq!(aggregate.flush(), vec![vec![tea("a", 2.0)], vec![tea("b", 1.0)]]);
+    }
+
+    #[test]
+    fn aggregate_allows_one_collect() {
+        let aggregate = totals().reduce(Reducer::Collect).reduce(Reducer::Collect);
+        assert!(aggregate.validate().is_err());
+    }
+
+    struct PouredArgs {
+        pub poured: Arc<Mutex<Vec<TestTea>>>,
+    }
+
+    impl Argument for PouredArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn aggregated_tea_flows_to_later_steps() {
+        let poured = Arc::new(Mutex::new(Vec::new()));
+        let new_pot = Pot::new()
+            .add_source(Box::new(Fill {
+                name: String::from("orders"),
+                source: String::from("hardcoded"),
+                computation: Box::new(|_args, brewery, recipe| {
+                    for x in 0 .. 20 {
+                        let recipe = Arc::clone(&recipe);
+                        let customer = if x % 2 == 0 { "even" } else { "odd" };
+                        let tea_batch = vec![tea(customer, x as f64)];
+                        brewery.take_order(|| {
+                            make_tea(tea_batch, recipe);
+                        });
+                    }
+                }),
+                params: None,
+            }))
+            .add_ingredient(Box::new(totals()))
+            .add_ingredient(Box::new(Pour {
+                name: String::from("pour_totals"),
+                computation: Box::new(|tea_batch: Vec<TestTea>, args| {
+                    let args = args.as_ref().unwrap().as_any().downcast_ref::<PouredArgs>().unwrap();
+                    args.poured.lock().unwrap().extend(tea_batch.iter().cloned());
+                    tea_batch
+                }),
+                params: Some(Box::new(PouredArgs { poured: Arc::clone(&poured) })),
+            }));
+        let brewery = Brewery::new(4);
+        let report = new_pot.brew(&brewery).unwrap();
+        assert_eq!(*poured.lock().unwrap(), vec![
+            TestTea { customer: String::from("even xCount(10)"), amount: 90.0 },
+            TestTea { customer: String::from("odd xCount(10)"), amount: 100.0 },
+        ]);
+        assert_eq!(report.steps["totals"].records_in, 20);
+        assert_eq!(report.steps["totals"].records_out, 2);
+        assert_eq!(report.steps["pour_totals"].batches, 1);
+    }
+}
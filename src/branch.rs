@@ -0,0 +1,469 @@
+use crate::brewery::{brew_from, flush_steps};
+use crate::context;
+use crate::ingredient::{Ingredient, Steps};
+
+use std::any::Any;
+use std::collections::HashSet;
+use std::panic::{self, AssertUnwindSafe};
+
+///
+/// Ingredient used to send a copy of each batch down several sub-recipes, such as an archive
+/// copy and a cleaned copy of the same Tea.
+///
+/// Each branch brews its copy through its own steps before the batch is passed on, unchanged, to
+/// the rest of the recipe. Steps in a branch are recorded in the `BrewReport` as `branch/step`.
+/// A branch that panics is recorded as a `BrewError::BranchFailed` without stopping the others.
+pub struct Branch<T: Send> {
+    pub name: String,
+    pub branches: Vec<(String, Steps<T>)>,
+}
+
+impl<T: Send> Branch<T> {
+    ///
+    /// Creates a Branch without any branches.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the step
+    pub fn new(name: &str) -> Branch<T> {
+        Branch {
+            name: String::from(name),
+            branches: Vec::new(),
+        }
+    }
+
+    ///
+    /// Adds a named sub-recipe brewing a copy of each batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the branch
+    /// * `steps` - steps run on the copy, in order
+    pub fn branch(mut self, name: &str, steps: Steps<T>) -> Branch<T> {
+        self.branches.push((String::from(name), steps));
+        self
+    }
+}
+
+///
+/// Brews a batch through a branch's steps, catching a panic so the other branches carry on.
+pub(crate) fn brew_branch<T: Send + 'static>(branch: &str, steps: &Steps<T>, tea_batch: Vec<T>) {
+    let records = tea_batch.len();
+    let context = context::current();
+    let brewed = panic::catch_unwind(AssertUnwindSafe(|| {
+        context::scoped(branch, || brew_from(0, vec![tea_batch], steps, &context));
+    }));
+    if let Err(panic) = brewed {
+        let reason = context::panic_reason(&*panic);
+        context::panicked_step();
+        match &context {
+            Some(context) => context.branch_failed(branch, records, reason),
+            None => println!("Error: branch {} failed on batch of {} tea: {}", branch, records, reason),
+        }
+    }
+}
+
+///
+/// Sends on the Tea held back by a branch's steps, catching a panic like `brew_branch`.
+pub(crate) fn flush_branch<T: Send + 'static>(branch: &str, steps: &Steps<T>) {
+    let context = context::current();
+    let flushed = panic::catch_unwind(AssertUnwindSafe(|| {
+        context::scoped(branch, || flush_steps(steps, &context));
+    }));
+    if let Err(panic) = flushed {
+        let reason = context::panic_reason(&*panic);
+        context::panicked_step();
+        match &context {
+            Some(context) => context.branch_failed(branch, 0, reason),
+            None => println!("Error: branch {} failed while flushing: {}", branch, reason),
+        }
+    }
+}
+
+///
+/// Checks every branch or route has a unique name and valid steps.
+pub(crate) fn validate_branches<'a, T: Send + 'a>(
+    kind: &str,
+    branches: impl Iterator<Item = (&'a str, &'a Steps<T>)>
+) -> Result<(), String> {
+    let mut names = HashSet::new();
+    for (branch, steps) in branches {
+        if branch.is_empty() {
+            return Err(format!("{} has no name", kind));
+        }
+        if !names.insert(branch) {
+            return Err(format!("{} {} is added twice", kind, branch));
+        }
+        for step in steps {
+            step.validate().map_err(|error| format!("{}/{}: {}", branch, step.get_name(), error))?;
+        }
+    }
+    Ok(())
+}
+
+impl<T: Send + Clone + 'static> Ingredient<T> for Branch<T> {
+    fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
+        for (branch, steps) in &self.branches {
+            brew_branch(branch, steps, tea_batch.clone());
+        }
+        tea_batch
+    }
+    fn flush_into(&self, _send: &mut dyn FnMut(Vec<T>)) {
+        for (branch, steps) in &self.branches {
+            flush_branch(branch, steps);
+        }
+    }
+    fn get_name(&self) -> &str {
+        &self.name[..]
+    }
+    fn kind(&self) -> &str {
+        "Branch"
+    }
+    fn sub_recipes(&self) -> Vec<(&str, &Steps<T>)> {
+        self.branches.iter().map(|(branch, steps)| (&branch[..], steps)).collect()
+    }
+    fn print(&self) {
+        println!("Current Step: {}", self.get_name());
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn validate(&self) -> Result<(), String> {
+        if self.branches.is_empty() {
+            return Err(String::from("branch step has no branches"));
+        }
+        validate_branches("branch", self.branches.iter().map(|(branch, steps)| (&branch[..], steps)))
+    }
+}
+
+///
+/// Names the route a Tea is sent down.
+pub type Classifier<T> = fn(&T) -> String;
+
+///
+/// Ingredient used to send each Tea down one of several named routes, such as valid Tea to a
+/// warehouse and invalid Tea to quarantine.
+///
+/// Each batch is split by the classifier, and each part is brewed through the steps of its route
+/// within the same Order. Tea classified into a route that does not exist goes down the default
+/// route, or is rejected if there is none. Nothing is passed on to the rest of the recipe. Like
+/// a Branch, steps in a route are recorded as `route/step` and a route that panics is reported as
+/// a `BrewError::BranchFailed`.
+pub struct Router<T: Send> {
+    pub name: String,
+    pub classifier: Box<Classifier<T>>,
+    pub routes: Vec<(String, Steps<T>)>,
+    pub default: Option<Steps<T>>,
+}
+
+impl<T: Send> Router<T> {
+    ///
+    /// Creates a Router without any routes.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the step
+    /// * `classifier` - names the route of each Tea
+    pub fn new(name: &str, classifier: Classifier<T>) -> Router<T> {
+        Router {
+            name: String::from(name),
+            classifier: Box::new(classifier),
+            routes: Vec::new(),
+            default: None,
+        }
+    }
+
+    ///
+    /// Adds a named route.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name the classifier gives Tea for this route
+    /// * `steps` - steps run on the route's Tea, in order
+    pub fn route(mut self, name: &str, steps: Steps<T>) -> Router<T> {
+        self.routes.push((String::from(name), steps));
+        self
+    }
+
+    ///
+    /// Sets the route for Tea the classifier does not send down any other route.
+    ///
+    /// # Arguments
+    ///
+    /// * `steps` - steps run on unmatched Tea, in order
+    pub fn default_route(mut self, steps: Steps<T>) -> Router<T> {
+        self.default = Some(steps);
+        self
+    }
+}
+
+impl<T: Send + 'static> Ingredient<T> for Router<T> {
+    fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
+        let mut routed: Vec<Vec<T>> = self.routes.iter().map(|_| Vec::new()).collect();
+        let mut unmatched = Vec::new();
+        for tea in tea_batch {
+            let route = (self.classifier)(&tea);
+            match self.routes.iter().position(|(name, _)| *name == route) {
+                Some(index) => routed[index].push(tea),
+                None => unmatched.push(tea),
+            }
+        }
+
+        for ((route, steps), tea_batch) in self.routes.iter().zip(routed) {
+            if !tea_batch.is_empty() {
+                brew_branch(route, steps, tea_batch);
+            }
+        }
+        if !unmatched.is_empty() {
+            match &self.default {
+                Some(steps) => brew_branch("default", steps, unmatched),
+                None => {
+                    if let Some(context) = context::current() {
+                        context.records_rejected(self.get_name(), unmatched.len());
+                    }
+                },
+            }
+        }
+        Vec::new()
+    }
+    fn flush_into(&self, _send: &mut dyn FnMut(Vec<T>)) {
+        for (route, steps) in &self.routes {
+            flush_branch(route, steps);
+        }
+        if let Some(steps) = &self.default {
+            flush_branch("default", steps);
+        }
+    }
+    fn get_name(&self) -> &str {
+        &self.name[..]
+    }
+    fn kind(&self) -> &str {
+        "Router"
+    }
+    fn sub_recipes(&self) -> Vec<(&str, &Steps<T>)> {
+        let default = self.default.iter().map(|steps| ("default", steps));
+        self.routes.iter().map(|(route, steps)| (&route[..], steps)).chain(default).collect()
+    }
+    fn print(&self) {
+        println!("Current Step: {}", self.get_name());
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn validate(&self) -> Result<(), String> {
+        if self.routes.is_empty() {
+            return Err(String::from("router has no routes"));
+        }
+        let default = self.default.iter().map(|steps| ("default", steps));
+        validate_branches("route", self.routes.iter().map(|(route, steps)| (&route[..], steps)).chain(default))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Branch, Router};
+    use super::super::aggregate::{Aggregate, Reducer, Reduced};
+    use super::super::brewery::{Brewery, make_tea};
+    use super::super::context::{BrewError, BrewReport};
+    use super::super::ingredient::{Fill, Steep, Pour, Filter, Argument, Ingredient};
+    use super::super::pot::Pot;
+    use std::any::Any;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq, Default, Clone)]
+    struct TestTea {
+        id: u32,
+    }
+
+    struct PouredArgs {
+        pub poured: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl Argument for PouredArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn pour(name: &str, poured: &Arc<Mutex<Vec<u32>>>) -> Box<Pour<TestTea>> {
+        Box::new(Pour {
+            name: String::from(name),
+            computation: Box::new(|tea_batch, args| {
+                let args = args.as_ref().unwrap().as_any().downcast_ref::<PouredArgs>().unwrap();
+                args.poured.lock().unwrap().extend(tea_batch.iter().map(|tea| tea.id));
+                tea_batch
+            }),
+            params: Some(Box::new(PouredArgs { poured: Arc::clone(poured) })),
+        })
+    }
+
+    fn evens() -> Box<Filter<TestTea>> {
+        Box::new(Filter {
+            name: String::from("evens"),
+            predicate: Box::new(|tea, _args| tea.id % 2 == 0),
+            params: None,
+            rejected: Vec::new(),
+        })
+    }
+
+    fn brew(step: Box<dyn Ingredient<TestTea> + Send + Sync>, main: &Arc<Mutex<Vec<u32>>>) -> BrewReport {
+        let new_pot = Pot::new()
+            .add_source(Box::new(Fill {
+                name: String::from("tea"),
+                source: String::from("hardcoded"),
+                computation: Box::new(|_args, brewery, recipe| {
+                    for x in 0 .. 5 {
+                        let recipe = Arc::clone(&recipe);
+                        let tea_batch = (x * 2 .. x * 2 + 2).map(|id| TestTea { id }).collect();
+                        brewery.take_order(|| {
+                            make_tea(tea_batch, recipe);
+                        });
+                    }
+                }),
+                params: None,
+            }))
+            .add_ingredient(step)
+            .add_ingredient(pour("pour_main", main));
+        new_pot.brew(&Brewery::new(2)).unwrap()
+    }
+
+    fn sorted(poured: &Arc<Mutex<Vec<u32>>>) -> Vec<u32> {
+        let mut poured = poured.lock().unwrap().clone();
+        poured.sort_unstable();
+        poured
+    }
+
+    #[test]
+    fn branches_brew_copies_of_each_batch() {
+        let main = Arc::new(Mutex::new(Vec::new()));
+        let archive = Arc::new(Mutex::new(Vec::new()));
+        let cleaned = Arc::new(Mutex::new(Vec::new()));
+        let branch = Branch::new("fan_out")
+            .branch("archive", vec![pour("pour", &archive)])
+            .branch("cleaned", vec![evens(), pour("pour", &cleaned)]);
+        let report = brew(Box::new(branch), &main);
+
+        assert_eq!(sorted(&main), (0 .. 10).collect::<Vec<u32>>());
+        assert_eq!(sorted(&archive), (0 .. 10).collect::<Vec<u32>>());
+        assert_eq!(sorted(&cleaned), vec![0, 2, 4, 6, 8]);
+        assert_eq!(report.steps["archive/pour"].records_in, 10);
+        assert_eq!(report.steps["cleaned/evens"].records_rejected, 5);
+        assert_eq!(report.steps["cleaned/pour"].records_in, 5);
+        assert_eq!(report.steps["fan_out"].records_out, 10);
+        assert!(!report.steps.contains_key("pour"));
+    }
+
+    #[test]
+    fn failed_branch_leaves_others_brewing() {
+        let main = Arc::new(Mutex::new(Vec::new()));
+        let archive = Arc::new(Mutex::new(Vec::new()));
+        let branch = Branch::new("fan_out")
+            .branch("broken", vec![Box::new(Steep {
+                name: String::from("explode"),
+                computation: Box::new(|tea_batch: Vec<TestTea>, _args| {
+                    if tea_batch[0].id == 4 {
+                        panic!("bad tea");
+                    }
+                    tea_batch
+                }),
+                params: None,
+            })])
+            .branch("archive", vec![pour("pour", &archive)]);
+        let report = brew(Box::new(branch), &main);
+
+        assert_eq!(sorted(&main), (0 .. 10).collect::<Vec<u32>>());
+        assert_eq!(sorted(&archive), (0 .. 10).collect::<Vec<u32>>());
+        assert_eq!(report.errors, vec![BrewError::BranchFailed {
+            branch: String::from("broken"),
+            records: 2,
+            reason: String::from("bad tea"),
+        }]);
+    }
+
+    #[test]
+    fn branches_flush_their_steps() {
+        let main = Arc::new(Mutex::new(Vec::new()));
+        let totals = Arc::new(Mutex::new(Vec::new()));
+        let count = Aggregate::new("count", |_tea: &TestTea| String::from("all"), |_key, reduced| {
+            match reduced[0] {
+                Reduced::Count(count) => TestTea { id: count as u32 },
+                _ => TestTea::default(),
+            }
+        }).reduce(Reducer::Count);
+        let branch = Branch::new("fan_out").branch("totals", vec![Box::new(count), pour("pour", &totals)]);
+        let report = brew(Box::new(branch), &main);
+
+        assert_eq!(*totals.lock().unwrap(), vec![10]);
+        assert_eq!(report.steps["totals/count"].records_out, 1);
+        assert_eq!(report.steps["totals/pour"].records_in, 1);
+    }
+
+    #[test]
+    fn branch_validates_branches() {
+        let poured = Arc::new(Mutex::new(Vec::new()));
+        let branch: Branch<TestTea> = Branch::new("fan_out");
+        assert_eq!(branch.validate(), Err(String::from("branch step has no branches")));
+        let branch = Branch::new("fan_out")
+            .branch("copy", vec![pour("pour", &poured)])
+            .branch("copy", vec![pour("pour", &poured)]);
+        assert_eq!(branch.validate(), Err(String::from("branch copy is added twice")));
+        let branch = Branch::new("fan_out").branch("copy", vec![pour("pour", &poured)]);
+        assert_eq!(branch.validate(), Ok(()));
+    }
+
+    fn by_size(tea: &TestTea) -> String {
+        match tea.id {
+            0 ..= 2 => String::from("small"),
+            3 ..= 6 => String::from("medium"),
+            _ => String::from("large"),
+        }
+    }
+
+    #[test]
+    fn router_sends_tea_down_its_route() {
+        let main = Arc::new(Mutex::new(Vec::new()));
+        let small = Arc::new(Mutex::new(Vec::new()));
+        let medium = Arc::new(Mutex::new(Vec::new()));
+        let other = Arc::new(Mutex::new(Vec::new()));
+        let router = Router::new("by_size", by_size)
+            .route("small", vec![pour("pour", &small)])
+            .route("medium", vec![evens(), pour("pour", &medium)])
+            .default_route(vec![pour("pour", &other)]);
+        let report = brew(Box::new(router), &main);
+
+        assert!(main.lock().unwrap().is_empty());
+        assert_eq!(sorted(&small), vec![0, 1, 2]);
+        assert_eq!(sorted(&medium), vec![4, 6]);
+        assert_eq!(sorted(&other), vec![7, 8, 9]);
+        assert_eq!(report.steps["by_size"].records_in, 10);
+        assert_eq!(report.steps["by_size"].records_out, 0);
+        assert_eq!(report.steps["medium/evens"].records_rejected, 2);
+        assert_eq!(report.steps["default/pour"].records_in, 3);
+    }
+
+    #[test]
+    fn router_rejects_unmatched_tea_without_default() {
+        let main = Arc::new(Mutex::new(Vec::new()));
+        let small = Arc::new(Mutex::new(Vec::new()));
+        let router = Router::new("by_size", by_size).route("small", vec![pour("pour", &small)]);
+        let report = brew(Box::new(router), &main);
+
+        assert_eq!(sorted(&small), vec![0, 1, 2]);
+        assert_eq!(report.steps["by_size"].records_rejected, 7);
+    }
+
+    #[test]
+    fn router_validates_routes() {
+        let poured = Arc::new(Mutex::new(Vec::new()));
+        let router: Router<TestTea> = Router::new("by_size", by_size);
+        assert_eq!(router.validate(), Err(String::from("router has no routes")));
+        let router = Router::new("by_size", by_size)
+            .route("default", vec![pour("pour", &poured)])
+            .default_route(vec![pour("pour", &poured)]);
+        assert_eq!(router.validate(), Err(String::from("route default is added twice")));
+        let router = Router::new("by_size", by_size)
+            .route("small", vec![pour("pour", &poured)])
+            .default_route(vec![]);
+        assert_eq!(router.validate(), Ok(()));
+    }
+}
@@ -1,12 +1,31 @@
-use crate::ingredient::{Ingredient, Steep, Skim, Pour};
+use crate::ack::{Ack, AckHandle, Unbrewed};
+use crate::context::{self, BrewContext, BrewError, OrderGuard};
+use crate::envelope::Stamp;
+use crate::ingredient::{Ingredient, SharedRecipe, Steps};
+use crate::pipeline::Feed;
+use crate::scheduler::{Scheduler, Ticket, View};
 
-use std::sync::{mpsc, Arc, Mutex, RwLock};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-/// Types of instructions that can be sent to Brewers.
+/// How often an idle Brewer checks whether it has been retired.
+const RETIRE_CHECK: Duration = Duration::from_millis(50);
+
+thread_local! {
+    /// Shift of the Brewer running on the current thread, so `make_tea` can record its steps.
+    static SHIFT: RefCell<Option<Arc<Mutex<Shift>>>> = const { RefCell::new(None) };
+}
+
+/// Types of instructions that can be sent to Brewers. New Orders wait in the Scheduler until a
+/// Brewer picks one up.
 enum OrderTea {
-    NewOrder(Order),
+    NewOrder,
     Terminate
 }
 
@@ -27,9 +46,16 @@ impl<F: FnOnce()> FnBox for F {
 type Order = Box<dyn FnBox + Send + 'static>;
 
 /// Struct holding the Array of Brewers and sender to push Tea Orders out to them.
+///
+/// Orders are scheduled by priority and shared fairly between the Pots (tenants) sending them.
+/// Idle Brewers wait on a shared multi-consumer channel instead of a Mutex guarded receiver, then
+/// take the next Order from the queues of the Scheduler.
 pub struct Brewery {
-    brewers: Vec<Brewer>,
-    sender: mpsc::Sender<OrderTea>,
+    staff: Arc<Staff>,
+    sender: Sender<OrderTea>,
+    autoscale: Option<Autoscale>,
+    watchdog: Option<Watchdog>,
+    supervisor: Option<Supervisor>,
     start_time: Instant,
 }
 
@@ -40,27 +66,119 @@ impl Brewery {
     /// # Arguments
     ///
     /// * `size` - number of brewers to instantiate
-    /// * `start_time` - program start time to expose runtime metrics
     pub fn new(size: usize) -> Brewery {
         assert!(size > 0);
 
-        let (sender, plain_rx) = mpsc::channel();
-        let rx = Arc::new(Mutex::new(plain_rx));
-
-        let mut brewers = Vec::with_capacity(size);
-        for id in 0 .. size {
-            brewers.push(Brewer::new(id, Arc::clone(&rx)));
-        }
+        let (sender, receiver) = unbounded();
+        let staff = Arc::new(Staff::new(receiver));
+        staff.resize(size);
 
         Brewery {
-            brewers,
+            staff,
             sender,
+            autoscale: None,
+            watchdog: None,
+            supervisor: None,
             start_time: Instant::now(),
         }
     }
 
     ///
-    /// Send function (job) with batch of Tea with Recipe to Brewers.
+    /// Enables autoscaling of the Brewers, growing the pool when Orders back up and shrinking it
+    /// when Brewers sit idle. The current size is clamped to the policy's bounds.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - bounds and thresholds used to scale the Brewers
+    pub fn autoscale(mut self, policy: Autoscale) -> Brewery {
+        assert!(policy.min_brewers > 0);
+        assert!(policy.min_brewers <= policy.max_brewers);
+
+        let size = self.size().max(policy.min_brewers).min(policy.max_brewers);
+        self.staff.resize(size);
+        self.autoscale = Some(policy);
+        self.supervise();
+        self
+    }
+
+    ///
+    /// Enables deadlines on Orders and steps. Brewers running past a deadline are flagged in the
+    /// brew's report and the Brewery metrics, and can optionally be replaced and have the stuck
+    /// step halted for the rest of the brew.
+    ///
+    /// # Arguments
+    ///
+    /// * `watchdog` - deadlines and actions to take on overruns
+    pub fn watchdog(mut self, watchdog: Watchdog) -> Brewery {
+        self.watchdog = Some(watchdog);
+        self.supervise();
+        self
+    }
+
+    ///
+    /// Restarts the Supervisor with the current autoscale and watchdog settings.
+    fn supervise(&mut self) {
+        if let Some(mut supervisor) = self.supervisor.take() {
+            supervisor.stop();
+        }
+        self.supervisor = Some(Supervisor::new(
+            Arc::clone(&self.staff),
+            self.autoscale.clone(),
+            self.watchdog.clone(),
+        ));
+    }
+
+    ///
+    /// Adds or retires Brewers so the Brewery runs `size` of them. Retired Brewers finish their
+    /// current Order before leaving; queued Orders stay queued for the remaining Brewers.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - number of brewers to keep working
+    pub fn resize(&self, size: usize) {
+        assert!(size > 0);
+        self.staff.resize(size);
+    }
+
+    ///
+    /// Returns the number of Brewers currently working.
+    pub fn size(&self) -> usize {
+        self.staff.size()
+    }
+
+    ///
+    /// Returns the number of Orders waiting for a Brewer.
+    pub fn queued(&self) -> usize {
+        self.staff.queued.load(Ordering::SeqCst)
+    }
+
+    ///
+    /// Gives the current thread, such as a pipeline stage worker, a Shift watched by the
+    /// Brewery's Watchdog for as long as the returned guard lives.
+    pub(crate) fn stage_shift(&self) -> StageShift {
+        let id = self.staff.next_id.fetch_add(1, Ordering::SeqCst);
+        let shift = Arc::new(Mutex::new(Shift::default()));
+        SHIFT.with(|on_shift| *on_shift.borrow_mut() = Some(Arc::clone(&shift)));
+        self.staff.stages.lock().unwrap().push((id, shift));
+        StageShift { staff: Arc::clone(&self.staff), id }
+    }
+
+    ///
+    /// Sets the share of Orders a tenant receives relative to other tenants of the same
+    /// priority. Tenants default to a weight of 1.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - pot or tenant id
+    /// * `weight` - orders brewed for the tenant on each of its turns
+    pub fn set_tenant_weight(&self, tenant: &str, weight: usize) {
+        assert!(weight > 0);
+        self.staff.scheduler.set_weight(tenant, weight);
+    }
+
+    ///
+    /// Send function (job) with batch of Tea with Recipe to Brewers. Orders sent while a Pot is
+    /// brewing are scheduled with the Pot's tenant and priority.
     ///
     /// # Arguments
     ///
@@ -68,32 +186,161 @@ impl Brewery {
     pub fn take_order<F>(&self, f: F)
         where F: FnOnce() + Send + 'static
     {
-        let order = Box::new(f);
+        let ticket = context::current()
+            .map(|context| context.ticket().clone())
+            .unwrap_or_default();
+        self.take_order_with(ticket, f);
+    }
 
+    ///
+    /// Send function (job) with a batch of Tea taken at a position in the current source, such as
+    /// the file offset or line number after the batch, or a cursor token. When the Pot is
+    /// checkpointed, the position is saved once the batch and every batch before it have been
+    /// brewed.
+    ///
+    /// # Arguments
+    ///
+    /// * `position` - where the source would resume after the batch
+    /// * `f` - function to send off to Brewers
+    pub fn take_order_at<F>(&self, position: &str, f: F)
+        where F: FnOnce() + Send + 'static
+    {
+        match context::current() {
+            Some(context) => context.take_at(position, || self.take_order(f)),
+            None => self.take_order(f),
+        }
+    }
+
+    ///
+    /// Send function (job) with a batch of Tea, returning a handle completed once the batch
+    /// leaves the Brewery. The handle is acked if every step brewed the batch, and nacked if the
+    /// batch was dropped, stopped part way or failed in a branch, so sources such as queues can
+    /// commit or redeliver it.
+    ///
+    /// # Arguments
+    ///
+    /// * `f` - function to send off to Brewers
+    pub fn take_order_acked<F>(&self, f: F) -> AckHandle
+        where F: FnOnce() + Send + 'static
+    {
+        let ack = AckHandle::new();
+        match context::current() {
+            Some(context) => context.take_acked(ack.clone(), || self.take_order(f)),
+            None => {
+                let unbrewed = Unbrewed(ack.clone());
+                self.take_order(move || {
+                    f();
+                    unbrewed.0.complete(Ack::Acked);
+                });
+            },
+        }
+        ack
+    }
+
+    ///
+    /// Returns the position the source being collected was last brewed up to, when its Pot is
+    /// checkpointed and was stopped part way through an earlier brew. Sources should skip the
+    /// Tea before it.
+    pub fn resume_position(&self) -> Option<String> {
+        context::current().and_then(|context| context.resume_position())
+    }
+
+    ///
+    /// Send function (job) to Brewers, scheduled with the given Ticket.
+    ///
+    /// # Arguments
+    ///
+    /// * `ticket` - priority and tenant used to schedule the order
+    /// * `f` - function to send off to Brewers
+    pub fn take_order_with<F>(&self, ticket: Ticket, f: F)
+        where F: FnOnce() + Send + 'static
+    {
+        // Orders sent while a Pot is brewing carry its context to the Brewer.
+        let order: Order = match context::current() {
+            None => Box::new(f),
+            Some(context) => {
+                if context.sources_stopped() {
+                    context.order_refused();
+                    return;
+                }
+                // A pipelined brew brews its batches in stages, so the Order only needs to hand
+                // its batch to the first stage, which takes the Order for it.
+                if context.is_pipelined() {
+                    f();
+                    return;
+                }
+                let guard = context.order_taken();
+                Box::new(move || {
+                    // Orders still queued when the brew is cancelled are dropped.
+                    if !context.token().is_cancelled() {
+                        brew_order(&context, &guard, f);
+                    }
+                })
+            }
+        };
+
+        self.staff.queued.fetch_add(1, Ordering::SeqCst);
+        self.staff.scheduler.push(&ticket, order);
         self.sender
-            .send(OrderTea::NewOrder(order))
+            .send(OrderTea::NewOrder)
             .unwrap();
     }
 
+    ///
+    /// Returns true if the brew currently filling the Brewery has been cancelled, or needs no more
+    /// Tea because a `Limit` was reached. Sources should check this between batches and stop
+    /// collecting Tea once it returns true.
+    pub fn is_cancelled(&self) -> bool {
+        context::current().is_some_and(|context| context.sources_stopped())
+    }
+
+    ///
+    /// Returns a snapshot of the Brewers, their queue, and how long each has been on its
+    /// current Order.
+    pub fn metrics(&self) -> BreweryMetrics {
+        let brewers = self.staff.brewers.lock().unwrap();
+        let on_order = brewers
+            .iter()
+            .filter_map(|brewer| {
+                let shift = brewer.shift.lock().unwrap();
+                shift.order_started.map(|started| (brewer.id, started.elapsed()))
+            })
+            .collect();
+
+        BreweryMetrics {
+            brewers: brewers.len(),
+            busy: self.staff.busy.load(Ordering::SeqCst),
+            queued: self.staff.queued.load(Ordering::SeqCst),
+            overruns: self.staff.overruns.load(Ordering::SeqCst),
+            on_order,
+        }
+    }
+
     ///
     /// Get info method to display number of Brewers assigned to Brewery.
     pub fn get_brewer_info(&self) {
-        println!("Number of brewers: {}", &self.brewers.len());
+        println!("Number of brewers: {}", self.size());
     }
 
 }
 
 impl Drop for Brewery {
     fn drop(&mut self) {
+        // Stop scaling so the set of Brewers is fixed while they are let go.
+        if let Some(mut supervisor) = self.supervisor.take() {
+            supervisor.stop();
+        }
+
         // After all jobs are sent terminate message is sent to close out worker pool.
         println!("Sending terminate message to all brewers.");
 
-        for _ in &mut self.brewers {
+        let mut brewers = self.staff.release();
+        for _ in &brewers {
             self.sender.send(OrderTea::Terminate).unwrap();
         }
 
         // Run any jobs that have not yet been completed before killing worker.
-        for brewer in &mut self.brewers {
+        for brewer in &mut brewers {
             println!("\tLetting go brewer {}", brewer.id);
 
             if let Some(thread) = brewer.thread.take() {
@@ -106,10 +353,405 @@ impl Drop for Brewery {
     }
 }
 
+///
+/// Snapshot of a Brewery's workload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BreweryMetrics {
+    /// Brewers currently working.
+    pub brewers: usize,
+    /// Brewers currently running an Order.
+    pub busy: usize,
+    /// Orders waiting for a Brewer.
+    pub queued: usize,
+    /// Orders and steps that have run past their deadline.
+    pub overruns: usize,
+    /// Id of each Brewer running an Order and how long it has been running it.
+    pub on_order: Vec<(usize, Duration)>,
+}
+
+///
+/// Policy used to autoscale the number of Brewers in a Brewery.
+#[derive(Clone)]
+pub struct Autoscale {
+    /// Fewest Brewers to keep, even when idle.
+    pub min_brewers: usize,
+    /// Most Brewers to hire, even when Orders back up.
+    pub max_brewers: usize,
+    /// Queued Orders per Brewer tolerated before more Brewers are hired.
+    pub backlog_per_brewer: usize,
+    /// How long Brewers must sit idle before one is retired.
+    pub idle_period: Duration,
+    /// How often the queue is checked.
+    pub check_interval: Duration,
+}
+
+impl Autoscale {
+    ///
+    /// Creates a policy scaling between `min_brewers` and `max_brewers` with default thresholds.
+    ///
+    /// # Arguments
+    ///
+    /// * `min_brewers` - fewest brewers to keep
+    /// * `max_brewers` - most brewers to hire
+    pub fn new(min_brewers: usize, max_brewers: usize) -> Autoscale {
+        Autoscale {
+            min_brewers,
+            max_brewers,
+            backlog_per_brewer: 2,
+            idle_period: Duration::from_secs(1),
+            check_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+///
+/// Deadlines on Orders and steps, checked by the Brewery's Supervisor. Steps brewed by the workers
+/// of a pipelined brew are watched too, though stuck workers are only flagged, never replaced.
+#[derive(Clone)]
+pub struct Watchdog {
+    /// Longest a Brewer may spend on one Order.
+    pub order_timeout: Option<Duration>,
+    /// Longest a Brewer may spend on a step, keyed by step name.
+    pub step_timeouts: HashMap<String, Duration>,
+    /// Drop batches reaching a step while it is stuck past its deadline.
+    pub halt_stuck_steps: bool,
+    /// Retire Brewers stuck past a deadline and hire replacements in their place.
+    pub replace_stuck_brewers: bool,
+    /// How often the Brewers are checked.
+    pub check_interval: Duration,
+}
+
+impl Watchdog {
+    ///
+    /// Creates a Watchdog with no deadlines.
+    pub fn new() -> Watchdog {
+        Watchdog {
+            order_timeout: None,
+            step_timeouts: HashMap::new(),
+            halt_stuck_steps: false,
+            replace_stuck_brewers: false,
+            check_interval: Duration::from_millis(100),
+        }
+    }
+
+    ///
+    /// Sets the longest a Brewer may spend on one Order.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - deadline for each order
+    pub fn order_timeout(mut self, timeout: Duration) -> Watchdog {
+        self.order_timeout = Some(timeout);
+        self
+    }
+
+    ///
+    /// Sets the longest a Brewer may spend on the named step.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the step
+    /// * `timeout` - deadline for the step on each batch
+    pub fn step_timeout(mut self, name: &str, timeout: Duration) -> Watchdog {
+        self.step_timeouts.insert(String::from(name), timeout);
+        self
+    }
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Watchdog::new()
+    }
+}
+
+///
+/// What a Brewer is working on, watched by the Supervisor.
+#[derive(Default)]
+struct Shift {
+    order_started: Option<Instant>,
+    step: Option<(String, Instant)>,
+    context: Option<Arc<BrewContext>>,
+    order_flagged: bool,
+    step_flagged: bool,
+}
+
+///
+/// Records the step a Brewer is on for as long as the guard lives.
+struct OnStep<'a> {
+    name: &'a str,
+    shift: Option<Arc<Mutex<Shift>>>,
+    /// Step the Brewer was on before, such as a Branch running this step.
+    outer: Option<(String, Instant)>,
+}
+
+impl<'a> OnStep<'a> {
+    fn start(name: &'a str, context: &Option<Arc<BrewContext>>) -> OnStep<'a> {
+        let shift = SHIFT.with(|shift| shift.borrow().clone());
+        let mut outer = None;
+        if let Some(shift) = &shift {
+            let mut shift = shift.lock().unwrap();
+            outer = shift.step.replace((context::step_name(name), Instant::now()));
+            shift.context = context.clone();
+            shift.step_flagged = false;
+        }
+        OnStep { name, shift, outer }
+    }
+}
+
+impl Drop for OnStep<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            context::step_panicked(self.name);
+        }
+        if let Some(shift) = &self.shift {
+            let mut shift = shift.lock().unwrap();
+            // A stuck step that finally finishes no longer holds up the rest of the brew.
+            if let (Some((name, _)), Some(context)) = (shift.step.take(), &shift.context) {
+                if shift.step_flagged {
+                    context.resume_step(&name);
+                }
+            }
+            shift.step = self.outer.take();
+            shift.step_flagged = false;
+        }
+    }
+}
+
+///
+/// Stops watching a stage worker's Shift when dropped.
+pub(crate) struct StageShift {
+    staff: Arc<Staff>,
+    id: usize,
+}
+
+impl Drop for StageShift {
+    fn drop(&mut self) {
+        SHIFT.with(|shift| shift.borrow_mut().take());
+        self.staff.stages.lock().unwrap().retain(|(id, _)| *id != self.id);
+    }
+}
+
+///
+/// Brewers shared between the Brewery and its Supervisor.
+struct Staff {
+    brewers: Mutex<Vec<Brewer>>,
+    retired: Mutex<Vec<Brewer>>,
+    receiver: Receiver<OrderTea>,
+    scheduler: Arc<Scheduler<Order>>,
+    next_id: AtomicUsize,
+    busy: Arc<AtomicUsize>,
+    queued: Arc<AtomicUsize>,
+    overruns: AtomicUsize,
+    /// Shifts of pipeline stage workers, watched alongside the Brewers' and keyed by worker id.
+    stages: Mutex<Vec<(usize, Arc<Mutex<Shift>>)>>,
+}
+
+impl Staff {
+    fn new(receiver: Receiver<OrderTea>) -> Staff {
+        Staff {
+            brewers: Mutex::new(Vec::new()),
+            retired: Mutex::new(Vec::new()),
+            receiver,
+            scheduler: Arc::new(Scheduler::new()),
+            next_id: AtomicUsize::new(0),
+            busy: Arc::new(AtomicUsize::new(0)),
+            queued: Arc::new(AtomicUsize::new(0)),
+            overruns: AtomicUsize::new(0),
+            stages: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.brewers.lock().unwrap().len()
+    }
+
+    ///
+    /// Hires or retires Brewers until `size` are working.
+    fn resize(&self, size: usize) {
+        let mut brewers = self.brewers.lock().unwrap();
+        let mut retired = self.retired.lock().unwrap();
+
+        // Forget retired Brewers that have already left.
+        retired.retain(|brewer| brewer.thread.as_ref().is_some_and(|thread| !thread.is_finished()));
+
+        while brewers.len() < size {
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            brewers.push(self.hire(id));
+        }
+        while brewers.len() > size {
+            let brewer = brewers.pop().unwrap();
+            brewer.retire();
+            retired.push(brewer);
+        }
+    }
+
+    fn hire(&self, id: usize) -> Brewer {
+        Brewer::new(id, self.receiver.clone(), Arc::clone(&self.scheduler), Arc::clone(&self.busy), Arc::clone(&self.queued))
+    }
+
+    ///
+    /// Retires the Brewer with the given id and hires a replacement for it.
+    fn replace(&self, id: usize) {
+        let mut brewers = self.brewers.lock().unwrap();
+        if let Some(index) = brewers.iter().position(|brewer| brewer.id == id) {
+            let brewer = brewers.remove(index);
+            brewer.retire();
+            println!("Replacing stuck brewer {}", id);
+            self.retired.lock().unwrap().push(brewer);
+
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            brewers.push(self.hire(id));
+        }
+    }
+
+    ///
+    /// Flags Orders and steps that have run past their deadline, returning the ids of the
+    /// Brewers newly found stuck.
+    fn check_deadlines(&self, watchdog: &Watchdog) -> Vec<usize> {
+        let stuck = self.brewers.lock().unwrap()
+            .iter()
+            .filter(|brewer| self.check_shift(brewer.id, &brewer.shift, watchdog))
+            .map(|brewer| brewer.id)
+            .collect();
+        // Stage workers cannot be replaced, but their overruns are flagged and their steps halted.
+        for (id, shift) in self.stages.lock().unwrap().iter() {
+            self.check_shift(*id, shift, watchdog);
+        }
+        stuck
+    }
+
+    ///
+    /// Flags the Order and step of one worker if they have run past their deadline, returning
+    /// true if either is newly found stuck.
+    fn check_shift(&self, id: usize, shift: &Mutex<Shift>, watchdog: &Watchdog) -> bool {
+        let mut shift = shift.lock().unwrap();
+        let mut overruns = Vec::new();
+
+        if let (Some(started), Some(limit)) = (shift.order_started, watchdog.order_timeout) {
+            if !shift.order_flagged && started.elapsed() > limit {
+                shift.order_flagged = true;
+                overruns.push(BrewError::OrderOverrun { brewer: id, limit });
+            }
+        }
+
+        if let Some((name, started)) = &shift.step {
+            if let Some(&limit) = watchdog.step_timeouts.get(name) {
+                if !shift.step_flagged && started.elapsed() > limit {
+                    overruns.push(BrewError::StepOverrun { brewer: id, step: name.clone(), limit });
+                }
+            }
+        }
+
+        let stuck = !overruns.is_empty();
+        for error in overruns {
+            println!("Brewer {} overran: {}", id, error);
+            self.overruns.fetch_add(1, Ordering::SeqCst);
+            if let BrewError::StepOverrun { step, .. } = &error {
+                shift.step_flagged = true;
+                if let (true, Some(context)) = (watchdog.halt_stuck_steps, &shift.context) {
+                    context.halt_step(step);
+                }
+            }
+            if let Some(context) = &shift.context {
+                context.overrun(error);
+            }
+        }
+        stuck
+    }
+
+    ///
+    /// Retires every Brewer, returning the ones still working so they can be let go.
+    fn release(&self) -> Vec<Brewer> {
+        let mut brewers: Vec<Brewer> = self.brewers.lock().unwrap().drain(..).collect();
+        for mut brewer in self.retired.lock().unwrap().drain(..) {
+            if let Some(thread) = brewer.thread.take() {
+                thread.join().unwrap();
+            }
+        }
+        brewers.sort_by_key(|brewer| brewer.id);
+        brewers
+    }
+}
+
+///
+/// Background thread that applies the Autoscale policy and Watchdog deadlines to the Staff.
+struct Supervisor {
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Supervisor {
+    fn new(staff: Arc<Staff>, autoscale: Option<Autoscale>, watchdog: Option<Watchdog>) -> Supervisor {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&stopped);
+        let check_interval = autoscale.iter().map(|policy| policy.check_interval)
+            .chain(watchdog.iter().map(|watchdog| watchdog.check_interval))
+            .min()
+            .unwrap_or(RETIRE_CHECK);
+
+        let thread = thread::spawn(move || {
+            let mut idle_since: Option<Instant> = None;
+            while !stop.load(Ordering::SeqCst) {
+                thread::sleep(check_interval);
+
+                if let Some(watchdog) = &watchdog {
+                    let stuck = staff.check_deadlines(watchdog);
+                    if watchdog.replace_stuck_brewers {
+                        for id in stuck {
+                            staff.replace(id);
+                        }
+                    }
+                }
+
+                if let Some(policy) = &autoscale {
+                    Supervisor::scale(&staff, policy, &mut idle_since);
+                }
+            }
+        });
+
+        Supervisor {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+
+    ///
+    /// Hires Brewers when Orders back up and retires one after the Brewers sit idle.
+    fn scale(staff: &Staff, policy: &Autoscale, idle_since: &mut Option<Instant>) {
+        let size = staff.size();
+        let queued = staff.queued.load(Ordering::SeqCst);
+        let busy = staff.busy.load(Ordering::SeqCst);
+
+        if queued > size * policy.backlog_per_brewer && size < policy.max_brewers {
+            // Hire enough Brewers to bring the backlog back under the threshold.
+            let wanted = queued / policy.backlog_per_brewer.max(1);
+            staff.resize(wanted.max(size + 1).min(policy.max_brewers));
+            *idle_since = None;
+        } else if queued == 0 && busy < size && size > policy.min_brewers {
+            let since = *idle_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= policy.idle_period {
+                staff.resize(size - 1);
+                *idle_since = None;
+            }
+        } else {
+            *idle_since = None;
+        }
+    }
+
+    fn stop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
 ///
 /// Worker that runs the Recipe and brews the batch of Tea.
 struct Brewer {
     id: usize,
+    retired: Arc<AtomicBool>,
+    shift: Arc<Mutex<Shift>>,
     thread: Option<thread::JoinHandle<()>>,
 }
 
@@ -120,20 +762,56 @@ impl Brewer {
     /// # Arguments
     ///
     /// * `id` - brewer number assigned.
-    /// * `reciever` - receiver clone to receive jobs on.
-    pub fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<OrderTea>>>) -> Brewer {
+    /// * `receiver` - receiver clone to receive jobs on.
+    /// * `scheduler` - scheduler holding the queued orders.
+    /// * `busy` - count of brewers currently working on an order.
+    /// * `queued` - count of orders waiting for a brewer.
+    pub fn new(
+        id: usize,
+        receiver: Receiver<OrderTea>,
+        scheduler: Arc<Scheduler<Order>>,
+        busy: Arc<AtomicUsize>,
+        queued: Arc<AtomicUsize>,
+    ) -> Brewer {
+        let retired = Arc::new(AtomicBool::new(false));
+        let let_go = Arc::clone(&retired);
+        let shift = Arc::new(Mutex::new(Shift::default()));
+        let on_shift = Arc::clone(&shift);
         let thread = thread::spawn(move || {
-            loop {
-                let make_tea = receiver.lock()
-                    .unwrap()
-                    .recv()
-                    .unwrap();
+            SHIFT.with(|shift| *shift.borrow_mut() = Some(Arc::clone(&on_shift)));
+            let mut view = View::new();
+
+            // Retired Brewers stop taking Orders, leaving the queue to the rest of the Staff.
+            while !let_go.load(Ordering::SeqCst) {
+                let make_tea = match receiver.recv_timeout(RETIRE_CHECK) {
+                    Ok(make_tea) => make_tea,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
 
                 match make_tea {
-                    OrderTea::NewOrder(order) => {
+                    OrderTea::NewOrder => {
+                        // Every signal is sent after its Order is queued, so one is waiting. It
+                        // may sit in a queue added after the View was refreshed, so the Brewer
+                        // yields and tries again with a fresh View.
+                        let order = loop {
+                            if let Some(order) = scheduler.pop(&mut view) {
+                                break order;
+                            }
+                            thread::yield_now();
+                        };
                         // TODO: Change this to DEBUG logs/
                         //println!("Brewer {} received order! Executing...", id);
-                        order.call_box();
+                        queued.fetch_sub(1, Ordering::SeqCst);
+                        busy.fetch_add(1, Ordering::SeqCst);
+                        on_shift.lock().unwrap().order_started = Some(Instant::now());
+                        // A panicking Order is caught, so the Brewer stays on the Staff and is no
+                        // longer counted as busy.
+                        if panic::catch_unwind(AssertUnwindSafe(move || order.call_box())).is_err() {
+                            println!("Brewer {} dropped an Order that panicked", id);
+                        }
+                        *on_shift.lock().unwrap() = Shift::default();
+                        busy.fetch_sub(1, Ordering::SeqCst);
                     },
                     OrderTea::Terminate => {
                         println!("Brewer {} was let go...", id);
@@ -145,48 +823,240 @@ impl Brewer {
 
         Brewer { 
             id, 
+            retired,
+            shift,
             thread: Some(thread),
         }
     }
+
+    ///
+    /// Tells the Brewer to leave once its current Order is finished.
+    fn retire(&self) {
+        self.retired.store(true, Ordering::SeqCst);
+    }
 }
 
 ///
-/// This function is passed to the brewer via a thread for it to process the batch of Tea.
+/// This function is passed to the brewer via a thread for it to process the batch of Tea. When
+/// the Pot is brewed as a pipeline, the batch is sent into the first stage instead, and batches
+/// for a Graph are brewed by the source.
 ///
 /// # Arguments
 ///
 /// * `tea_batch` - Array of Tea structs to be processed
 /// * `recipe` - read only clone of recipe containing all steps
-pub fn make_tea<T: Send + 'static>(mut tea_batch: Vec<T>, recipe: Arc<RwLock<Vec<Box<dyn Ingredient<T> + Send + Sync>>>>) {
+pub fn make_tea<T: Send + 'static>(tea_batch: Vec<T>, recipe: SharedRecipe<T>) {
+    let context = context::current();
+    if let Some(context) = &context {
+        if let Some(feed) = context.feed() {
+            if let Some(feed) = feed.downcast_ref::<Feed<T>>() {
+                if feed.feeds(&recipe) {
+                    feed.send(tea_batch, Arc::new(context.order_taken()));
+                    return;
+                }
+                // Recipes outside the pipeline, such as a Graph's, are brewed by the source as an
+                // Order of their own.
+                if context::order_id().is_none() {
+                    let guard = context.order_taken();
+                    let current = Some(Arc::clone(context));
+                    brew_order(context, &guard, || brew_from(0, vec![tea_batch], &recipe.read().unwrap(), &current));
+                    return;
+                }
+            }
+        }
+    }
+
     let recipe = recipe.read().unwrap();
     // TODO: In the future, Fill will become a valid step in the recipe. For simplicity, this is
     // excluded at this stage in the project.
     // TODO: In the future, Tranfuse will become a valid step in the recipe. The Ingredient does not currently
     // exist, and additional logic may need to be introduced to handle how things are combined.
-    for step in recipe.iter() {
-        if let Some(steep) = step.as_any().downcast_ref::<Steep<T>>() {
-            tea_batch = steep.exec(tea_batch);
-        } else if let Some(skim) = step.as_any().downcast_ref::<Skim<T>>() {
-            tea_batch = skim.exec(tea_batch);
-        } else if let Some(pour) = step.as_any().downcast_ref::<Pour<T>>() {
-            tea_batch = pour.exec(tea_batch);
+    brew_from(0, vec![tea_batch], &recipe, &context);
+}
+
+///
+/// Runs an Order's function for its brew, recording whether it brewed the batch. A batch
+/// panicking in a step is dropped, leaving the thread brewing.
+///
+/// # Arguments
+///
+/// * `context` - brew the Order belongs to
+/// * `guard` - guard of the Order
+/// * `f` - function brewing the Order's batch
+fn brew_order<F>(context: &Arc<BrewContext>, guard: &OrderGuard, f: F)
+    where F: FnOnce()
+{
+    let (brewed, failure) = context::failures(|| context::enter(context, || {
+        guard.brewing(|| panic::catch_unwind(AssertUnwindSafe(f)))
+            .map_err(|panic| context.order_panicked(&*panic))
+            .is_ok()
+    }));
+    if let Some(reason) = failure {
+        guard.failed(reason);
+    }
+    if brewed {
+        guard.brewed();
+    }
+}
+
+///
+/// Brews batches through the recipe starting at the given step. Batches split by a step are
+/// brewed through the rest of the recipe one at a time, in order.
+///
+/// # Arguments
+///
+/// * `index` - position in the recipe of the first step to run
+/// * `tea_batches` - batches of Tea to be processed
+/// * `recipe` - steps to run
+/// * `context` - brew the batches belong to, if any
+pub(crate) fn brew_from<T: Send + 'static>(
+    index: usize,
+    tea_batches: Vec<Vec<T>>,
+    recipe: &Steps<T>,
+    context: &Option<Arc<BrewContext>>,
+) {
+    brew_into(index, tea_batches, recipe, context, &mut |_tea_batch| {});
+}
+
+///
+/// Brews batches through the recipe like `brew_from`, sending each batch that makes it past the
+/// last step to `send`, such as the steps of a sub-recipe.
+///
+/// # Arguments
+///
+/// * `index` - position in the recipe of the first step to run
+/// * `tea_batches` - batches of Tea to be processed
+/// * `recipe` - steps to run
+/// * `context` - brew the batches belong to, if any
+/// * `send` - receives the brewed batches
+pub(crate) fn brew_into<T: Send + 'static>(
+    index: usize,
+    tea_batches: Vec<Vec<T>>,
+    recipe: &Steps<T>,
+    context: &Option<Arc<BrewContext>>,
+    send: &mut dyn FnMut(Vec<T>),
+) {
+    let mut batches: Vec<(usize, Vec<T>)> = tea_batches.into_iter().rev().map(|tea_batch| (index, tea_batch)).collect();
+    while let Some((index, tea_batch)) = batches.pop() {
+        if index == recipe.len() {
+            send(tea_batch);
+            continue;
+        }
+        if let Some(split) = brew_step(&*recipe[index], tea_batch, context) {
+            batches.extend(split.into_iter().rev().map(|tea_batch| (index + 1, tea_batch)));
+        }
+    }
+}
+
+///
+/// Sends on the Tea each step held back until the end of the brew, such as aggregates, brewing it
+/// through the rest of the recipe.
+///
+/// # Arguments
+///
+/// * `recipe` - steps to flush
+/// * `context` - brew the steps belong to, if any
+pub(crate) fn flush_steps<T: Send + 'static>(recipe: &Steps<T>, context: &Option<Arc<BrewContext>>) {
+    flush_steps_into(recipe, context, &mut |_tea_batch| {});
+}
+
+///
+/// Flushes the steps like `flush_steps`, sending each batch that makes it past the last step to
+/// `send`.
+///
+/// # Arguments
+///
+/// * `recipe` - steps to flush
+/// * `context` - brew the steps belong to, if any
+/// * `send` - receives the brewed batches
+pub(crate) fn flush_steps_into<T: Send + 'static>(
+    recipe: &Steps<T>,
+    context: &Option<Arc<BrewContext>>,
+    send: &mut dyn FnMut(Vec<T>),
+) {
+    for (index, step) in recipe.iter().enumerate() {
+        step.flush_into(&mut |mut tea_batch| {
+            if let Some(context) = context {
+                context.step_flushed(step.get_name(), tea_batch.len());
+                if let Some(Stamp(stamp)) = context.stamp::<T>() {
+                    stamp(&mut tea_batch, step.get_name());
+                }
+            }
+            brew_into(index + 1, vec![tea_batch], recipe, context, send);
+        });
+    }
+}
+
+///
+/// Runs one step on a batch of Tea, returning the batches it passes on, or None if the batch
+/// was stopped before the step.
+///
+/// # Arguments
+///
+/// * `step` - the step to run
+/// * `tea_batch` - Array of Tea structs to be processed
+/// * `context` - brew the batch belongs to, if any
+pub(crate) fn brew_step<T: Send + 'static>(
+    step: &dyn Ingredient<T>,
+    tea_batch: Vec<T>,
+    context: &Option<Arc<BrewContext>>,
+) -> Option<Vec<Vec<T>>> {
+    if let Some(context) = context {
+        // An immediate cancel stops the batch between steps.
+        if context.stop_now() {
+            context.batch_interrupted(tea_batch.len());
+            return None;
+        }
+        // Batches are not sent into a step that is stuck past its deadline.
+        if context.is_halted(step.get_name()) {
+            context.step_halted(step.get_name(), tea_batch.len());
+            return None;
+        }
+    }
+    let _on_step = OnStep::start(step.get_name(), context);
+    let records_in = tea_batch.len();
+    let mut batches = step.exec_batches(tea_batch);
+    if let Some(context) = context {
+        let records_out = batches.iter().map(|tea_batch| tea_batch.len()).sum();
+        context.step_brewed(step.get_name(), records_in, records_out);
+        if let Some(Stamp(stamp)) = context.stamp::<T>() {
+            for tea_batch in &mut batches {
+                stamp(tea_batch, step.get_name());
+            }
         }
     }
+    Some(batches)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Brewery;
+    use super::{Brewery, Autoscale, Watchdog, make_tea};
+    use super::super::ingredient::{Steep, Pour, FlatMap, Argument, Ingredient};
+    use std::any::Any;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::thread;
+    use std::time::{Duration, Instant};
 
     #[derive(Debug, PartialEq, Default)]
     struct TestTea {
         x: i32,
     }
 
+    struct CounterArgs {
+        pub counter: Arc<Mutex<i32>>,
+    }
+
+    impl Argument for CounterArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
     #[test]
     fn create_brewery_with_brewers() {
         let brewery = Brewery::new(4);
-        assert_eq!(brewery.brewers.len(), 4);
+        assert_eq!(brewery.size(), 4);
     }
 
     #[test]
@@ -195,13 +1065,265 @@ mod tests {
         let _brewery = Brewery::new(0);
     }
 
-    //TODO figure out how to properly test threads
-    //#[test]
-    //fn brewery_sends_job_done_channel() {
-    //    let brewery = Brewery::new(4, Instant::now());
-    //    let tea = TestTea::new(Box::new(TestTea::default()));
-    //    brewery.take_order(|| {
-    //        make_tea(tea, recipe);
-    //    });
-    //}
+    #[test]
+    fn brewery_processes_all_orders() {
+        let counter = Arc::new(Mutex::new(0));
+        let recipe = Arc::new(RwLock::new(Vec::new()));
+        recipe.write().unwrap().push(Box::new(Steep {
+            name: String::from("steep1"),
+            computation: Box::new(|tea_batch: Vec<TestTea>, _args| {
+                tea_batch
+                    .into_iter()
+                    .map(|tea| TestTea { x: tea.x + 1 })
+                    .collect()
+            }),
+            params: None,
+        }) as Box<_>);
+        recipe.write().unwrap().push(Box::new(Pour {
+            name: String::from("pour1"),
+            computation: Box::new(|tea_batch: Vec<TestTea>, args| {
+                if let Some(box_args) = args {
+                    let box_args = box_args.as_any().downcast_ref::<CounterArgs>().unwrap();
+                    let mut total = box_args.counter.lock().unwrap();
+                    *total += tea_batch.iter().map(|tea| tea.x).sum::<i32>();
+                }
+                tea_batch
+            }),
+            params: Some(Box::new(CounterArgs { counter: Arc::clone(&counter) })),
+        }) as Box<_>);
+
+        {
+            let brewery = Brewery::new(4);
+            for _ in 0 .. 50 {
+                let tea_batch = vec![TestTea::default(), TestTea::default()];
+                let recipe = Arc::clone(&recipe);
+                brewery.take_order(|| {
+                    make_tea(tea_batch, recipe);
+                });
+            }
+        }
+
+        assert_eq!(*counter.lock().unwrap(), 100);
+    }
+
+    /// Ingredient defined outside the crate's own Steep, Skim and Pour.
+    struct Tally {
+        counter: Arc<Mutex<i32>>,
+    }
+
+    impl Ingredient<TestTea> for Tally {
+        fn exec(&self, tea_batch: Vec<TestTea>) -> Vec<TestTea> {
+            *self.counter.lock().unwrap() += tea_batch.len() as i32;
+            tea_batch
+        }
+        fn print(&self) {
+            println!("Current Step: {}", self.get_name());
+        }
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+        fn get_name(&self) -> &str {
+            "tally"
+        }
+    }
+
+    #[test]
+    fn make_tea_runs_custom_ingredients() {
+        let counter = Arc::new(Mutex::new(0));
+        let recipe = Arc::new(RwLock::new(Vec::new()));
+        recipe.write().unwrap().push(Box::new(Tally { counter: Arc::clone(&counter) }) as Box<_>);
+        make_tea(vec![TestTea::default(), TestTea::default()], recipe);
+        assert_eq!(*counter.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn make_tea_brews_split_batches_in_order() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let recipe = Arc::new(RwLock::new(Vec::new()));
+        recipe.write().unwrap().push(Box::new(FlatMap {
+            name: String::from("explode"),
+            computation: Box::new(|tea: TestTea, _args| {
+                (0 .. 5).map(|x| TestTea { x: tea.x * 10 + x }).collect()
+            }),
+            params: None,
+            batch_size: 4,
+        }) as Box<_>);
+        recipe.write().unwrap().push(Box::new(Pour {
+            name: String::from("record_batches"),
+            computation: Box::new(|tea_batch: Vec<TestTea>, args| {
+                let args = args.as_ref().unwrap().as_any().downcast_ref::<BatchArgs>().unwrap();
+                args.batches.lock().unwrap().push(tea_batch.iter().map(|tea| tea.x).collect());
+                tea_batch
+            }),
+            params: Some(Box::new(BatchArgs { batches: Arc::clone(&batches) })),
+        }) as Box<_>);
+
+        make_tea(vec![TestTea { x: 1 }, TestTea { x: 2 }], recipe);
+        assert_eq!(*batches.lock().unwrap(), vec![
+            vec![10, 11, 12, 13],
+            vec![14, 20, 21, 22],
+            vec![23, 24],
+        ]);
+    }
+
+    struct BatchArgs {
+        pub batches: Arc<Mutex<Vec<Vec<i32>>>>,
+    }
+
+    impl Argument for BatchArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn panicking_order_keeps_brewer() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let brewery = Brewery::new(1);
+        brewery.take_order(|| panic!("kettle boiled over"));
+        let brewed = Arc::clone(&counter);
+        brewery.take_order(move || {
+            brewed.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let started = Instant::now();
+        while counter.load(Ordering::SeqCst) == 0 && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        thread::sleep(Duration::from_millis(20));
+        let metrics = brewery.metrics();
+        assert_eq!((metrics.brewers, metrics.busy, metrics.queued), (1, 0, 0));
+        assert!(metrics.on_order.is_empty());
+    }
+
+    #[test]
+    fn resize_brewery() {
+        let brewery = Brewery::new(2);
+        brewery.resize(5);
+        assert_eq!(brewery.size(), 5);
+        brewery.resize(1);
+        assert_eq!(brewery.size(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn resize_brewery_to_no_brewers() {
+        let brewery = Brewery::new(2);
+        brewery.resize(0);
+    }
+
+    #[test]
+    fn shrinking_brewery_keeps_queued_orders() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        {
+            let brewery = Brewery::new(4);
+            for _ in 0 .. 20 {
+                let counter = Arc::clone(&counter);
+                brewery.take_order(move || {
+                    thread::sleep(Duration::from_millis(5));
+                    counter.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            brewery.resize(1);
+        }
+        assert_eq!(counter.load(Ordering::SeqCst), 20);
+    }
+
+    #[test]
+    fn autoscale_grows_and_shrinks_brewery() {
+        let mut policy = Autoscale::new(1, 4);
+        policy.idle_period = Duration::from_millis(50);
+        policy.check_interval = Duration::from_millis(10);
+        let brewery = Brewery::new(1).autoscale(policy);
+
+        for _ in 0 .. 40 {
+            brewery.take_order(|| thread::sleep(Duration::from_millis(20)));
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while brewery.size() < 4 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(brewery.size(), 4);
+
+        while brewery.size() > 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(brewery.size(), 1);
+        assert_eq!(brewery.queued(), 0);
+    }
+
+    fn sleeping_recipe(millis: u64) -> super::SharedRecipe<TestTea> {
+        let recipe: super::SharedRecipe<TestTea> = Arc::new(RwLock::new(Vec::new()));
+        recipe.write().unwrap().push(Box::new(Steep {
+            name: String::from("slow_steep"),
+            computation: Box::new(|tea_batch: Vec<TestTea>, args| {
+                let millis = args.as_ref().unwrap().as_any().downcast_ref::<SleepArgs>().unwrap().millis;
+                thread::sleep(Duration::from_millis(millis));
+                tea_batch
+            }),
+            params: Some(Box::new(SleepArgs { millis })),
+        }));
+        recipe
+    }
+
+    struct SleepArgs {
+        pub millis: u64,
+    }
+
+    impl Argument for SleepArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn watchdog_flags_sleeping_step() {
+        let mut watchdog = Watchdog::new().step_timeout("slow_steep", Duration::from_millis(20));
+        watchdog.check_interval = Duration::from_millis(5);
+        let brewery = Brewery::new(1).watchdog(watchdog);
+
+        let recipe = sleeping_recipe(200);
+        brewery.take_order(|| {
+            make_tea(vec![TestTea::default()], recipe);
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while brewery.metrics().overruns == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let metrics = brewery.metrics();
+        assert_eq!(metrics.overruns, 1);
+        assert_eq!(metrics.busy, 1);
+        assert_eq!(metrics.on_order.len(), 1);
+        assert!(metrics.on_order[0].1 >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn watchdog_replaces_stuck_brewer() {
+        let mut watchdog = Watchdog::new().order_timeout(Duration::from_millis(20));
+        watchdog.replace_stuck_brewers = true;
+        watchdog.check_interval = Duration::from_millis(5);
+        let brewery = Brewery::new(1).watchdog(watchdog);
+
+        let recipe = sleeping_recipe(500);
+        brewery.take_order(|| {
+            make_tea(vec![TestTea::default()], recipe);
+        });
+        let counter = Arc::new(AtomicUsize::new(0));
+        let done = Arc::clone(&counter);
+        brewery.take_order(move || {
+            done.fetch_add(1, Ordering::SeqCst);
+        });
+
+        // The replacement Brewer picks up the second Order while the first is still stuck.
+        let deadline = Instant::now() + Duration::from_millis(400);
+        while counter.load(Ordering::SeqCst) == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        assert_eq!(brewery.size(), 1);
+        assert_eq!(brewery.metrics().overruns, 1);
+    }
 }
@@ -0,0 +1,112 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+const RUNNING: u8 = 0;
+const GRACEFUL: u8 = 1;
+const IMMEDIATE: u8 = 2;
+
+///
+/// How a cancelled brew winds down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelMode {
+    /// Finish batches already being brewed, but drop queued Orders and stop the sources.
+    Graceful,
+    /// Stop batches at their next step, drop queued Orders and stop the sources.
+    Immediate,
+}
+
+///
+/// Token used to cancel a brew started with `Pot::brew_with_token`.
+///
+/// Clones share the same state, so one clone can be handed to the brew while another is kept to
+/// cancel it from a different thread.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    state: Arc<AtomicU8>,
+}
+
+impl CancelToken {
+    ///
+    /// Creates a token that has not been cancelled.
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    ///
+    /// Cancels the brew. A Graceful cancel can be escalated to Immediate, but not the reverse.
+    ///
+    /// # Arguments
+    ///
+    /// * `mode` - how the brew should wind down
+    pub fn cancel(&self, mode: CancelMode) {
+        let state = match mode {
+            CancelMode::Graceful => GRACEFUL,
+            CancelMode::Immediate => IMMEDIATE,
+        };
+        self.state.fetch_max(state, Ordering::SeqCst);
+    }
+
+    ///
+    /// Returns true once the token has been cancelled in any mode.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.load(Ordering::SeqCst) != RUNNING
+    }
+
+    ///
+    /// Returns the mode the token was cancelled with, if any.
+    pub fn mode(&self) -> Option<CancelMode> {
+        match self.state.load(Ordering::SeqCst) {
+            GRACEFUL => Some(CancelMode::Graceful),
+            IMMEDIATE => Some(CancelMode::Immediate),
+            _ => None,
+        }
+    }
+
+    ///
+    /// Cancels the token when the process receives SIGINT or SIGTERM. The first signal cancels
+    /// gracefully and a second one escalates to an immediate cancel.
+    ///
+    /// Only one signal handler can be installed per process.
+    #[cfg(feature = "signals")]
+    pub fn cancel_on_signal(&self) -> Result<(), ctrlc::Error> {
+        let token = self.clone();
+        ctrlc::set_handler(move || {
+            if token.is_cancelled() {
+                println!("Received second signal, stopping brew immediately...");
+                token.cancel(CancelMode::Immediate);
+            } else {
+                println!("Received signal, finishing brewing tea...");
+                token.cancel(CancelMode::Graceful);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CancelToken, CancelMode};
+
+    #[test]
+    fn new_token_is_not_cancelled() {
+        let token = CancelToken::new();
+        assert!(!token.is_cancelled());
+        assert_eq!(token.mode(), None);
+    }
+
+    #[test]
+    fn cancel_is_shared_between_clones() {
+        let token = CancelToken::new();
+        let clone = token.clone();
+        clone.cancel(CancelMode::Graceful);
+        assert!(token.is_cancelled());
+        assert_eq!(token.mode(), Some(CancelMode::Graceful));
+    }
+
+    #[test]
+    fn cancel_only_escalates() {
+        let token = CancelToken::new();
+        token.cancel(CancelMode::Immediate);
+        token.cancel(CancelMode::Graceful);
+        assert_eq!(token.mode(), Some(CancelMode::Immediate));
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+///
+/// Positions each source of a checkpointed Pot has been brewed up to, saved to a state file.
+///
+/// Batches are taken at a position in their source, such as the offset after their last line.
+/// A source's checkpoint moves up to a batch's position once it and every batch taken before it
+/// have been brewed, so a batch still brewing, dropped or failed holds the checkpoint back and is
+/// brewed again when the Pot is restarted.
+///
+/// Checkpoints that are held are only moved in memory, never saved. Pots writing to a Sink hold
+/// them, since the Sink only commits its output once the whole brew succeeds: a stopped brew
+/// discards the output of every batch, so it must start again from the last saved position.
+pub(crate) struct Checkpoints {
+    path: PathBuf,
+    held: bool,
+    state: Mutex<State>,
+}
+
+struct State {
+    committed: BTreeMap<String, String>,
+    sources: BTreeMap<String, Taken>,
+}
+
+///
+/// Batches taken from one source whose checkpoint has not yet moved past them.
+#[derive(Default)]
+struct Taken {
+    first: usize,
+    batches: VecDeque<(String, bool)>,
+}
+
+///
+/// Batch taken at a position in a source.
+pub(crate) struct Mark {
+    source: String,
+    batch: usize,
+}
+
+impl Checkpoints {
+    ///
+    /// Loads the checkpoints saved for a pipeline id, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `pipeline_id` - names the state file
+    /// * `state_dir` - directory holding the state file
+    pub(crate) fn load(pipeline_id: &str, state_dir: &Path) -> Result<Checkpoints, String> {
+        let path = state_dir.join(format!("{}.checkpoint.json", pipeline_id));
+        let committed = match fs::read_to_string(&path) {
+            Ok(saved) => serde_json::from_str(&saved)
+                .map_err(|error| format!("checkpoint {} cannot be read: {}", path.display(), error))?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => return Err(format!("checkpoint {} cannot be read: {}", path.display(), error)),
+        };
+        Ok(Checkpoints {
+            path,
+            held: false,
+            state: Mutex::new(State { committed, sources: BTreeMap::new() }),
+        })
+    }
+
+    ///
+    /// Holds the checkpoints in memory instead of saving them as batches are brewed.
+    ///
+    /// # Arguments
+    ///
+    /// * `held` - true to hold the checkpoints
+    pub(crate) fn held(mut self, held: bool) -> Checkpoints {
+        self.held = held;
+        self
+    }
+
+    ///
+    /// Returns the position a source was last brewed up to.
+    pub(crate) fn position(&self, source: &str) -> Option<String> {
+        self.state.lock().unwrap().committed.get(source).cloned()
+    }
+
+    ///
+    /// Records a batch taken from a source, returning its mark.
+    pub(crate) fn taken(&self, source: &str, position: String) -> Mark {
+        let mut state = self.state.lock().unwrap();
+        let taken = state.sources.entry(String::from(source)).or_default();
+        taken.batches.push_back((position, false));
+        Mark { source: String::from(source), batch: taken.first + taken.batches.len() - 1 }
+    }
+
+    ///
+    /// Records a batch leaving the Brewery, saving the source's new checkpoint if it moved.
+    pub(crate) fn finished(&self, mark: &Mark, brewed: bool) -> Result<(), String> {
+        if !brewed {
+            return Ok(());
+        }
+        let mut state = self.state.lock().unwrap();
+        let taken = state.sources.get_mut(&mark.source).unwrap();
+        taken.batches[mark.batch - taken.first].1 = true;
+        let mut position = None;
+        while taken.batches.front().is_some_and(|(_, brewed)| *brewed) {
+            position = taken.batches.pop_front().map(|(position, _)| position);
+            taken.first += 1;
+        }
+        if let Some(position) = position {
+            state.committed.insert(mark.source.clone(), position);
+            if self.held {
+                return Ok(());
+            }
+            save(&self.path, &state.committed)
+                .map_err(|error| format!("checkpoint {} cannot be saved: {}", self.path.display(), error))?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Removes the state file once every batch taken has been brewed, so the next brew starts
+    /// from the beginning of its sources.
+    pub(crate) fn clear(&self) -> Result<(), String> {
+        let state = self.state.lock().unwrap();
+        if state.sources.values().any(|taken| !taken.batches.is_empty()) {
+            return Ok(());
+        }
+        match fs::remove_file(&self.path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                Err(format!("checkpoint {} cannot be removed: {}", self.path.display(), error))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+///
+/// Writes the checkpoints to a temporary file and moves it over the state file, so a crash while
+/// saving leaves the last checkpoint in place. Both the file and the move are synced to disk
+/// before returning, so a saved checkpoint survives a power loss.
+fn save(path: &Path, committed: &BTreeMap<String, String>) -> io::Result<()> {
+    let saving = path.with_extension("json.tmp");
+    let mut file = File::create(&saving)?;
+    file.write_all(serde_json::to_string(committed)?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&saving, path)?;
+    match path.parent() {
+        Some(dir) if dir != Path::new("") => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Checkpoints;
+    use super::super::brewery::{Brewery, make_tea};
+    use super::super::cancel::{CancelToken, CancelMode};
+    use super::super::context::BrewError;
+    use super::super::ingredient::{Fill, Steep, Pour, Argument};
+    use super::super::pot::Pot;
+    use super::super::sink::{SinkPour, FileSink};
+    use serde::Serialize;
+    use std::any::Any;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq, Default, Clone, Serialize)]
+    struct TestTea {
+        id: u32,
+    }
+
+    struct PouredArgs {
+        pub poured: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl Argument for PouredArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct CancelArgs {
+        pub token: CancelToken,
+        pub at: u32,
+    }
+
+    impl Argument for CancelArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn state_dir(test: &str) -> PathBuf {
+        let state_dir = env::temp_dir().join(format!("rettle_checkpoint_{}_{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&state_dir);
+        fs::create_dir_all(&state_dir).unwrap();
+        state_dir
+    }
+
+    /// Pot reading ids 0 to 9 in batches of 2, resuming after its checkpoint, that cancels the
+    /// brew immediately when it reaches the given id.
+    fn counting_pot(state_dir: &PathBuf, token: &CancelToken, cancel_at: u32, poured: &Arc<Mutex<Vec<u32>>>) -> Pot<TestTea> {
+        Pot::new()
+            .checkpoint("counting", state_dir)
+            .add_source(Box::new(Fill {
+                name: String::from("ids"),
+                source: String::from("hardcoded"),
+                computation: Box::new(|_args, brewery, recipe| {
+                    let start = brewery.resume_position().map_or(0, |position| position.parse().unwrap());
+                    for id in (start .. 10).step_by(2) {
+                        if brewery.is_cancelled() {
+                            break;
+                        }
+                        let recipe = Arc::clone(&recipe);
+                        brewery.take_order_at(&(id + 2).to_string(), move || {
+                            make_tea(vec![TestTea { id }, TestTea { id: id + 1 }], recipe);
+                        });
+                    }
+                }),
+                params: None,
+            }))
+            .add_ingredient(Box::new(Steep {
+                name: String::from("crash"),
+                computation: Box::new(|tea_batch: Vec<TestTea>, args| {
+                    let args = args.as_ref().unwrap().as_any().downcast_ref::<CancelArgs>().unwrap();
+                    if tea_batch[0].id == args.at {
+                        args.token.cancel(CancelMode::Immediate);
+                    }
+                    tea_batch
+                }),
+                params: Some(Box::new(CancelArgs { token: token.clone(), at: cancel_at })),
+            }))
+            .add_ingredient(Box::new(Pour {
+                name: String::from("pour"),
+                computation: Box::new(|tea_batch: Vec<TestTea>, args| {
+                    let args = args.as_ref().unwrap().as_any().downcast_ref::<PouredArgs>().unwrap();
+                    args.poured.lock().unwrap().extend(tea_batch.iter().map(|tea| tea.id));
+                    tea_batch
+                }),
+                params: Some(Box::new(PouredArgs { poured: Arc::clone(poured) })),
+            }))
+    }
+
+    #[test]
+    fn checkpoint_waits_for_earlier_batches() {
+        let state_dir = state_dir("order");
+        let checkpoints = Checkpoints::load("order", &state_dir).unwrap();
+        let first = checkpoints.taken("ids", String::from("2"));
+        let second = checkpoints.taken("ids", String::from("4"));
+        let third = checkpoints.taken("ids", String::from("6"));
+        checkpoints.finished(&second, true).unwrap();
+        assert_eq!(checkpoints.position("ids"), None);
+        checkpoints.finished(&first, true).unwrap();
+        assert_eq!(checkpoints.position("ids"), Some(String::from("4")));
+        checkpoints.finished(&third, false).unwrap();
+        assert_eq!(checkpoints.position("ids"), Some(String::from("4")));
+
+        let reloaded = Checkpoints::load("order", &state_dir).unwrap();
+        assert_eq!(reloaded.position("ids"), Some(String::from("4")));
+        checkpoints.clear().unwrap();
+        assert!(state_dir.join("order.checkpoint.json").exists());
+        fs::remove_dir_all(&state_dir).unwrap();
+    }
+
+    #[test]
+    fn brew_resumes_from_checkpoint() {
+        let state_dir = state_dir("resume");
+        let brewery = Brewery::new(1);
+        let poured = Arc::new(Mutex::new(Vec::new()));
+        let token = CancelToken::new();
+        let report = counting_pot(&state_dir, &token, 6, &poured).brew_with_token(&brewery, &token).unwrap();
+        assert!(!report.is_complete());
+        assert_eq!(*poured.lock().unwrap(), vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(fs::read_to_string(state_dir.join("counting.checkpoint.json")).unwrap(), "{\"ids\":\"6\"}");
+
+        let resumed = Arc::new(Mutex::new(Vec::new()));
+        let token = CancelToken::new();
+        let report = counting_pot(&state_dir, &token, 100, &resumed).brew_with_token(&brewery, &token).unwrap();
+        assert!(report.is_complete());
+        assert_eq!(*resumed.lock().unwrap(), vec![6, 7, 8, 9]);
+        assert!(!state_dir.join("counting.checkpoint.json").exists());
+        fs::remove_dir_all(&state_dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_checkpoint_stops_brew() {
+        let state_dir = state_dir("unreadable");
+        fs::write(state_dir.join("counting.checkpoint.json"), "not json").unwrap();
+        let token = CancelToken::new();
+        let poured = Arc::new(Mutex::new(Vec::new()));
+        let problems = counting_pot(&state_dir, &token, 100, &poured).brew(&Brewery::new(1)).unwrap_err();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("checkpoint "));
+        assert!(problems[0].contains("cannot be read"));
+        fs::remove_dir_all(&state_dir).unwrap();
+    }
+
+    #[test]
+    fn failed_saves_are_reported() {
+        let state_dir = state_dir("missing");
+        fs::remove_dir_all(&state_dir).unwrap();
+        let token = CancelToken::new();
+        let poured = Arc::new(Mutex::new(Vec::new()));
+        let report = counting_pot(&state_dir, &token, 100, &poured).brew(&Brewery::new(1)).unwrap();
+        assert_eq!(*poured.lock().unwrap(), (0 .. 10).collect::<Vec<u32>>());
+        assert_eq!(report.errors.len(), 5);
+        assert!(report.errors.iter().all(|error| matches!(error, BrewError::CheckpointFailed { .. })));
+        assert!(!report.is_successful());
+    }
+
+    #[test]
+    fn resumed_sink_holds_every_record() {
+        let state_dir = state_dir("sink");
+        let path = state_dir.join("ids.jsonl");
+        let sink_pot = |token: &CancelToken, cancel_at: u32| {
+            counting_pot(&state_dir, token, cancel_at, &Arc::new(Mutex::new(Vec::new())))
+                .add_ingredient(Box::new(SinkPour::new("sink", Box::new(FileSink::json_lines(&path)))))
+        };
+        let brewery = Brewery::new(1);
+        let token = CancelToken::new();
+        let report = sink_pot(&token, 6).brew_with_token(&brewery, &token).unwrap();
+        assert!(!report.is_complete());
+        assert!(!path.exists());
+        assert!(!state_dir.join("counting.checkpoint.json").exists());
+
+        let token = CancelToken::new();
+        let report = sink_pot(&token, 100).brew_with_token(&brewery, &token).unwrap();
+        assert!(report.is_successful());
+        let mut ids: Vec<String> = fs::read_to_string(&path).unwrap().lines().map(String::from).collect();
+        ids.sort();
+        let mut expected: Vec<String> = (0 .. 10).map(|id| format!("{{\"id\":{}}}", id)).collect();
+        expected.sort();
+        assert_eq!(ids, expected);
+        fs::remove_dir_all(&state_dir).unwrap();
+    }
+}
@@ -0,0 +1,753 @@
+use crate::ack::{Ack, AckHandle};
+use crate::cancel::{CancelToken, CancelMode};
+use crate::checkpoint::{Checkpoints, Mark};
+use crate::envelope::Stamp;
+use crate::scheduler::Ticket;
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::Duration;
+
+thread_local! {
+    /// Brew the current thread is working for, set while sources fill and Brewers brew.
+    static CURRENT: RefCell<Option<Arc<BrewContext>>> = const { RefCell::new(None) };
+    /// Branches the current thread is brewing in, each followed by a `/`.
+    static SCOPE: RefCell<String> = const { RefCell::new(String::new()) };
+    /// Position in its source of the batch the current thread is taking an Order for.
+    static POSITION: RefCell<Option<String>> = const { RefCell::new(None) };
+    /// Handle acknowledging the batch the current thread is taking an Order for.
+    static ACK: RefCell<Option<AckHandle>> = const { RefCell::new(None) };
+    /// Why a batch brewed by the current thread was not fully brewed, if it was not.
+    static FAILURE: RefCell<Option<String>> = const { RefCell::new(None) };
+    /// Innermost step a panic on the current thread unwound through, until the panic is caught.
+    static PANICKED: RefCell<Option<String>> = const { RefCell::new(None) };
+    /// Steps run once the Order the current thread is brewing is done, if it is brewing one.
+    static ROLLBACK: RefCell<Option<Arc<Rollback>>> = const { RefCell::new(None) };
+}
+
+///
+/// Steps undone if an Order's batch is not fully brewed, such as keys recorded by a Dedupe.
+type Rollback = Mutex<Vec<Box<dyn FnOnce(bool) + Send>>>;
+
+///
+/// State shared by every Order sent while a Pot is brewing.
+///
+/// The context is entered on the thread running the sources, captured by `Brewery::take_order`,
+/// and entered again by the Brewer running the Order, so `make_tea` can see which brew it is
+/// working for.
+pub(crate) struct BrewContext {
+    token: CancelToken,
+    ticket: Ticket,
+    sources_stopped: AtomicBool,
+    pending: Mutex<usize>,
+    finished: Condvar,
+    orders_completed: AtomicUsize,
+    orders_dropped: AtomicUsize,
+    batches_interrupted: AtomicUsize,
+    records_interrupted: AtomicUsize,
+    overruns: AtomicUsize,
+    halted: Mutex<HashSet<String>>,
+    errors: Mutex<Vec<BrewError>>,
+    steps: Mutex<BTreeMap<String, StepStats>>,
+    feed: RwLock<Option<Arc<dyn Any + Send + Sync>>>,
+    checkpoints: Option<Checkpoints>,
+    /// Name and location of the source being collected.
+    source: Mutex<(String, String)>,
+    batches: AtomicU64,
+    lineage: Option<Arc<dyn Any + Send + Sync>>,
+    /// True if the brew's output is only committed once the whole brew succeeds.
+    output_held: bool,
+}
+
+impl BrewContext {
+    pub(crate) fn new(token: CancelToken, ticket: Ticket) -> BrewContext {
+        BrewContext {
+            token,
+            ticket,
+            sources_stopped: AtomicBool::new(false),
+            pending: Mutex::new(0),
+            finished: Condvar::new(),
+            orders_completed: AtomicUsize::new(0),
+            orders_dropped: AtomicUsize::new(0),
+            batches_interrupted: AtomicUsize::new(0),
+            records_interrupted: AtomicUsize::new(0),
+            overruns: AtomicUsize::new(0),
+            halted: Mutex::new(HashSet::new()),
+            errors: Mutex::new(Vec::new()),
+            steps: Mutex::new(BTreeMap::new()),
+            feed: RwLock::new(None),
+            checkpoints: None,
+            source: Mutex::new((String::new(), String::new())),
+            batches: AtomicU64::new(0),
+            lineage: None,
+            output_held: false,
+        }
+    }
+
+    ///
+    /// Records the steps each Tea is brewed through with a `Stamp` for the Pot's Tea.
+    pub(crate) fn lineage(mut self, lineage: Option<Arc<dyn Any + Send + Sync>>) -> BrewContext {
+        self.lineage = lineage;
+        self
+    }
+
+    ///
+    /// Returns the `Stamp` recording steps in the lineage of the brew's Tea, if it traces lineage.
+    pub(crate) fn stamp<T: 'static>(&self) -> Option<&Stamp<T>> {
+        self.lineage.as_ref()?.downcast_ref::<Stamp<T>>()
+    }
+
+    ///
+    /// Saves the positions the brew's sources are brewed up to.
+    pub(crate) fn checkpoints(mut self, checkpoints: Option<Checkpoints>) -> BrewContext {
+        self.checkpoints = checkpoints;
+        self
+    }
+
+    ///
+    /// Marks the brew's output as only committed once the whole brew succeeds, such as by a
+    /// Pot writing to a Sink.
+    pub(crate) fn output_held(mut self, held: bool) -> BrewContext {
+        self.output_held = held;
+        self
+    }
+
+    ///
+    /// Returns true if the brew's output is only committed once the whole brew succeeds.
+    pub(crate) fn is_output_held(&self) -> bool {
+        self.output_held
+    }
+
+    pub(crate) fn token(&self) -> &CancelToken {
+        &self.token
+    }
+
+    pub(crate) fn ticket(&self) -> &Ticket {
+        &self.ticket
+    }
+
+    ///
+    /// Stops the sources without cancelling the brew, once a step needs no more Tea.
+    pub(crate) fn stop_sources(&self) {
+        self.sources_stopped.store(true, Ordering::SeqCst);
+    }
+
+    ///
+    /// Returns true when the sources should stop collecting Tea.
+    pub(crate) fn sources_stopped(&self) -> bool {
+        self.token.is_cancelled() || self.sources_stopped.load(Ordering::SeqCst)
+    }
+
+    ///
+    /// Returns true when batches should stop before their next step.
+    pub(crate) fn stop_now(&self) -> bool {
+        self.token.mode() == Some(CancelMode::Immediate)
+    }
+
+    ///
+    /// Records an Order accepted by the Brewery, returning a guard that records it leaving.
+    pub(crate) fn order_taken(self: &Arc<Self>) -> OrderGuard {
+        *self.pending.lock().unwrap() += 1;
+        let mark = POSITION.with(|position| position.borrow_mut().take()).map(|position| self.batch_taken(position));
+        let ack = ACK.with(|ack| ack.borrow_mut().take());
+        OrderGuard {
+            context: Arc::clone(self),
+            brewed: AtomicBool::new(false),
+            failure: Mutex::new(None),
+            rollback: Arc::new(Mutex::new(Vec::new())),
+            mark,
+            ack,
+        }
+    }
+
+    ///
+    /// Records the source being collected, for its checkpoint and the metadata of its Tea.
+    pub(crate) fn source_started(&self, source: &str, location: &str) {
+        *self.source.lock().unwrap() = (String::from(source), String::from(location));
+    }
+
+    ///
+    /// Returns the name and location of the source being collected.
+    pub(crate) fn source(&self) -> (String, String) {
+        self.source.lock().unwrap().clone()
+    }
+
+    ///
+    /// Returns the id of a new batch collected by a source.
+    pub(crate) fn next_batch(&self) -> u64 {
+        self.batches.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    ///
+    /// Returns the position the source being collected was last brewed up to, if checkpointed.
+    pub(crate) fn resume_position(&self) -> Option<String> {
+        let checkpoints = self.checkpoints.as_ref()?;
+        checkpoints.position(&self.source.lock().unwrap().0)
+    }
+
+    ///
+    /// Takes an Order for a batch at a position in the source being collected.
+    pub(crate) fn take_at<F>(&self, position: &str, take: F)
+        where F: FnOnce()
+    {
+        if self.checkpoints.is_none() {
+            take();
+            return;
+        }
+        POSITION.with(|taking| *taking.borrow_mut() = Some(String::from(position)));
+        let ((), failure) = failures(take);
+        // Batches brewed by the source itself, such as a Graph's in a pipelined brew, are brewed
+        // once taken.
+        if let Some(position) = POSITION.with(|taking| taking.borrow_mut().take()) {
+            self.batch_finished(&self.batch_taken(position), failure.is_none());
+        }
+    }
+
+    ///
+    /// Takes an Order for a batch acknowledged through `ack`.
+    pub(crate) fn take_acked<F>(&self, ack: AckHandle, take: F)
+        where F: FnOnce()
+    {
+        ACK.with(|taking| *taking.borrow_mut() = Some(ack));
+        let ((), failure) = failures(take);
+        if let Some(ack) = ACK.with(|taking| taking.borrow_mut().take()) {
+            ack.complete(failure.map_or(Ack::Acked, Ack::Nacked));
+        }
+    }
+
+    fn batch_taken(&self, position: String) -> Mark {
+        let checkpoints = self.checkpoints.as_ref().unwrap();
+        checkpoints.taken(&self.source.lock().unwrap().0, position)
+    }
+
+    ///
+    /// Records a batch leaving the Brewery, for its source's checkpoint.
+    fn batch_finished(&self, mark: &Mark, brewed: bool) {
+        if let Some(checkpoints) = &self.checkpoints {
+            if let Err(reason) = checkpoints.finished(mark, brewed) {
+                self.errors.lock().unwrap().push(BrewError::CheckpointFailed { reason });
+            }
+        }
+    }
+
+    ///
+    /// Removes the brew's checkpoint if it brewed every batch it took without being cancelled.
+    ///
+    /// # Arguments
+    ///
+    /// * `report` - report of the brew, given any failure to remove the checkpoint
+    pub(crate) fn clear_checkpoints(&self, report: &mut BrewReport) {
+        if let Some(checkpoints) = &self.checkpoints {
+            if !self.token.is_cancelled() {
+                if let Err(reason) = checkpoints.clear() {
+                    report.errors.push(BrewError::CheckpointFailed { reason });
+                }
+            }
+        }
+    }
+
+    ///
+    /// Records an accepted Order leaving the Brewery, whether it was brewed or dropped.
+    fn order_finished(&self, brewed: bool) {
+        if brewed {
+            self.orders_completed.fetch_add(1, Ordering::SeqCst);
+        } else {
+            self.orders_dropped.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        *pending -= 1;
+        if *pending == 0 {
+            self.finished.notify_all();
+        }
+    }
+
+    ///
+    /// Records an Order refused because the brew was already cancelled, or a `Limit` was reached.
+    /// Orders refused after a Limit was reached are not needed, so they are not counted as dropped.
+    pub(crate) fn order_refused(&self) {
+        POSITION.with(|position| position.borrow_mut().take());
+        if let Some(ack) = ACK.with(|ack| ack.borrow_mut().take()) {
+            ack.complete(Ack::Nacked(String::from("sources were stopped")));
+        }
+        if self.token.is_cancelled() {
+            self.orders_dropped.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    ///
+    /// Records a batch stopped part way through the recipe.
+    pub(crate) fn batch_interrupted(&self, records: usize) {
+        batch_failed(String::from("batch was interrupted by a cancel"));
+        self.batches_interrupted.fetch_add(1, Ordering::SeqCst);
+        self.records_interrupted.fetch_add(records, Ordering::SeqCst);
+    }
+
+    ///
+    /// Records an Order or step that ran past its deadline.
+    pub(crate) fn overrun(&self, error: BrewError) {
+        self.overruns.fetch_add(1, Ordering::SeqCst);
+        self.errors.lock().unwrap().push(error);
+    }
+
+    ///
+    /// Stops batches from entering the named step until it is resumed.
+    pub(crate) fn halt_step(&self, step: &str) {
+        self.halted.lock().unwrap().insert(String::from(step));
+    }
+
+    ///
+    /// Lets batches enter the named step again.
+    pub(crate) fn resume_step(&self, step: &str) {
+        self.halted.lock().unwrap().remove(step);
+    }
+
+    pub(crate) fn is_halted(&self, step: &str) -> bool {
+        self.halted.lock().unwrap().contains(&step_name(step))
+    }
+
+    ///
+    /// Records a batch dropped because the step it needed was halted.
+    pub(crate) fn step_halted(&self, step: &str, records: usize) {
+        batch_failed(format!("step {} was halted past its deadline", step_name(step)));
+        self.batch_interrupted(records);
+        self.errors.lock().unwrap().push(BrewError::StepHalted { step: step_name(step), records });
+    }
+
+    ///
+    /// Records a step that could not finish its work.
+    pub(crate) fn step_failed(&self, step: &str, reason: String) {
+        self.errors.lock().unwrap().push(BrewError::StepFailed { step: step_name(step), reason });
+    }
+
+    ///
+    /// Records an Order whose batch panicked in a step, dropping the rest of the batch.
+    pub(crate) fn order_panicked(&self, panic: &(dyn Any + Send)) {
+        let reason = panic_reason(panic);
+        let step = panicked_step().unwrap_or_else(|| String::from("order"));
+        batch_failed(format!("step {} panicked: {}", step, reason));
+        self.errors.lock().unwrap().push(BrewError::StepFailed { step, reason });
+    }
+
+    ///
+    /// Records a branch that panicked on a batch, leaving the other branches brewing.
+    pub(crate) fn branch_failed(&self, branch: &str, records: usize, reason: String) {
+        batch_failed(format!("branch {} failed: {}", step_name(branch), reason));
+        self.errors.lock().unwrap().push(BrewError::BranchFailed { branch: step_name(branch), records, reason });
+    }
+
+    ///
+    /// Records a batch passing through a step.
+    pub(crate) fn step_brewed(&self, step: &str, records_in: usize, records_out: usize) {
+        let mut steps = self.steps.lock().unwrap();
+        let stats = steps.entry(step_name(step)).or_default();
+        stats.batches += 1;
+        stats.records_in += records_in;
+        stats.records_out += records_out;
+    }
+
+    ///
+    /// Records Tea a step held back until the end of the brew, such as aggregates.
+    pub(crate) fn step_flushed(&self, step: &str, records: usize) {
+        self.steps.lock().unwrap().entry(step_name(step)).or_default().records_out += records;
+    }
+
+    ///
+    /// Records Tea a step rejected, such as Tea failing a Filter.
+    pub(crate) fn records_rejected(&self, step: &str, records: usize) {
+        self.steps.lock().unwrap().entry(step_name(step)).or_default().records_rejected += records;
+    }
+
+    ///
+    /// Sends the brew's batches into a pipeline instead of through the Brewery.
+    pub(crate) fn attach_feed(&self, feed: Arc<dyn Any + Send + Sync>) {
+        *self.feed.write().unwrap() = Some(feed);
+    }
+
+    pub(crate) fn detach_feed(&self) {
+        self.feed.write().unwrap().take();
+    }
+
+    ///
+    /// Returns the entrance of the pipeline the brew is running, if it is pipelined.
+    pub(crate) fn feed(&self) -> Option<Arc<dyn Any + Send + Sync>> {
+        self.feed.read().unwrap().clone()
+    }
+
+    pub(crate) fn is_pipelined(&self) -> bool {
+        self.feed.read().unwrap().is_some()
+    }
+
+    ///
+    /// Blocks until every accepted Order has left the Brewery.
+    pub(crate) fn wait(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        while *pending > 0 {
+            pending = self.finished.wait(pending).unwrap();
+        }
+    }
+
+    pub(crate) fn report(&self, sources_skipped: usize) -> BrewReport {
+        BrewReport {
+            cancelled: self.token.mode(),
+            orders_completed: self.orders_completed.load(Ordering::SeqCst),
+            orders_dropped: self.orders_dropped.load(Ordering::SeqCst),
+            batches_interrupted: self.batches_interrupted.load(Ordering::SeqCst),
+            records_interrupted: self.records_interrupted.load(Ordering::SeqCst),
+            sources_skipped,
+            limit_reached: self.sources_stopped.load(Ordering::SeqCst),
+            overruns: self.overruns.load(Ordering::SeqCst),
+            errors: self.errors.lock().unwrap().clone(),
+            steps: self.steps.lock().unwrap().clone(),
+        }
+    }
+}
+
+///
+/// Guard held by an accepted Order so the brew is not left waiting on it, even if it panics.
+/// Batches split from the Order's batch share its guard, and the Order leaves the Brewery once
+/// the last of them has.
+pub(crate) struct OrderGuard {
+    context: Arc<BrewContext>,
+    brewed: AtomicBool,
+    failure: Mutex<Option<String>>,
+    rollback: Arc<Rollback>,
+    mark: Option<Mark>,
+    ack: Option<AckHandle>,
+}
+
+impl OrderGuard {
+    ///
+    /// Marks the Order as brewed rather than dropped.
+    pub(crate) fn brewed(&self) {
+        self.brewed.store(true, Ordering::SeqCst);
+    }
+
+    ///
+    /// Records why a batch of the Order was not fully brewed. Only the first reason is kept.
+    pub(crate) fn failed(&self, reason: String) {
+        self.failure.lock().unwrap().get_or_insert(reason);
+    }
+
+    ///
+    /// Runs `f` brewing the Order's batch, so its steps can register steps to run once it is done
+    /// with `on_finished`.
+    pub(crate) fn brewing<F, R>(&self, f: F) -> R
+        where F: FnOnce() -> R
+    {
+        let outer = ROLLBACK.with(|rollback| rollback.borrow_mut().replace(Arc::clone(&self.rollback)));
+        let _restore = RestoreRollback(outer);
+        f()
+    }
+}
+
+impl Drop for OrderGuard {
+    fn drop(&mut self) {
+        let brewed = self.brewed.load(Ordering::SeqCst);
+        let failure = match self.failure.lock().unwrap().take() {
+            None if !brewed => Some(String::from("batch was not brewed")),
+            failure => failure,
+        };
+        // Undone before the batch is nacked, so a redelivered batch is brewed like the first.
+        for done in self.rollback.lock().unwrap().drain(..) {
+            done(failure.is_none());
+        }
+        if let Some(mark) = &self.mark {
+            self.context.batch_finished(mark, failure.is_none());
+        }
+        if let Some(ack) = &self.ack {
+            ack.complete(failure.map_or(Ack::Acked, Ack::Nacked));
+        }
+        self.context.order_finished(brewed);
+    }
+}
+
+///
+/// Restores the steps of the outer Order when leaving `brewing`, even if the batch panics.
+struct RestoreRollback(Option<Arc<Rollback>>);
+
+impl Drop for RestoreRollback {
+    fn drop(&mut self) {
+        let outer = self.0.take();
+        ROLLBACK.with(|rollback| *rollback.borrow_mut() = outer);
+    }
+}
+
+///
+/// Restores the previous brew when leaving `enter`, even if the brew panics.
+struct Restore(Option<Arc<BrewContext>>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+///
+/// Returns the brew the current thread is working for, if any.
+pub(crate) fn current() -> Option<Arc<BrewContext>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+///
+/// Runs `f` with `context` as the current brew, restoring the previous one afterwards.
+pub(crate) fn enter<F, R>(context: &Arc<BrewContext>, f: F) -> R
+    where F: FnOnce() -> R
+{
+    let _restore = Restore(CURRENT.with(|current| current.replace(Some(Arc::clone(context)))));
+    f()
+}
+
+///
+/// Runs `f` inside a branch, so the steps it brews are recorded as `branch/step`.
+pub(crate) fn scoped<F, R>(branch: &str, f: F) -> R
+    where F: FnOnce() -> R
+{
+    let outer = SCOPE.with(|scope| {
+        let mut scope = scope.borrow_mut();
+        let outer = scope.len();
+        scope.push_str(branch);
+        scope.push('/');
+        outer
+    });
+    let _restore = Unscope(outer);
+    f()
+}
+
+///
+/// Runs `f` outside the branch the current thread is brewing in, such as to pass on Tea flushed
+/// out of a sub-recipe, then returns to the branch.
+pub(crate) fn unscoped<F, R>(branch: &str, f: F) -> R
+    where F: FnOnce() -> R
+{
+    let inner = SCOPE.with(|scope| {
+        let mut scope = scope.borrow_mut();
+        let outer = scope.len().saturating_sub(branch.len() + 1);
+        scope.split_off(outer)
+    });
+    let _restore = Rescope(inner);
+    f()
+}
+
+///
+/// Runs `f`, returning the first reason a batch brewed by it on the current thread was not fully
+/// brewed, such as a step halted past its deadline or a branch that panicked.
+pub(crate) fn failures<F, R>(f: F) -> (R, Option<String>)
+    where F: FnOnce() -> R
+{
+    let outer = FAILURE.with(|failure| failure.borrow_mut().take());
+    let result = f();
+    let failure = FAILURE.with(|failure| std::mem::replace(&mut *failure.borrow_mut(), outer));
+    (result, failure)
+}
+
+///
+/// Records why a batch brewed by the current thread was not fully brewed.
+fn batch_failed(reason: String) {
+    FAILURE.with(|failure| {
+        failure.borrow_mut().get_or_insert(reason);
+    });
+}
+
+///
+/// Registers a step to run once the batch the current thread is brewing is done, told whether it
+/// was fully brewed, such as a Dedupe releasing the keys it claimed for the batch and forgetting
+/// them if it failed. Does nothing outside an Order.
+pub(crate) fn on_finished<F>(done: F)
+    where F: FnOnce(bool) + Send + 'static
+{
+    ROLLBACK.with(|rollback| {
+        if let Some(rollback) = &*rollback.borrow() {
+            rollback.lock().unwrap().push(Box::new(done));
+        }
+    });
+}
+
+///
+/// Returns an id for the Order the current thread is brewing, unique among the Orders still
+/// brewing, or None outside an Order.
+pub(crate) fn order_id() -> Option<usize> {
+    ROLLBACK.with(|rollback| rollback.borrow().as_ref().map(|rollback| Arc::as_ptr(rollback) as *const () as usize))
+}
+
+///
+/// Records a step a panic is unwinding through, keeping the innermost one.
+pub(crate) fn step_panicked(step: &str) {
+    PANICKED.with(|panicked| {
+        panicked.borrow_mut().get_or_insert_with(|| step_name(step));
+    });
+}
+
+///
+/// Takes the innermost step the panic just caught on the current thread unwound through.
+pub(crate) fn panicked_step() -> Option<String> {
+    PANICKED.with(|panicked| panicked.borrow_mut().take())
+}
+
+///
+/// Returns the message a panic was raised with.
+pub(crate) fn panic_reason(panic: &(dyn Any + Send)) -> String {
+    if let Some(reason) = panic.downcast_ref::<&str>() {
+        String::from(*reason)
+    } else if let Some(reason) = panic.downcast_ref::<String>() {
+        reason.clone()
+    } else {
+        String::from("unknown panic")
+    }
+}
+
+///
+/// Returns the name a step is recorded under in the current branch.
+pub(crate) fn step_name(step: &str) -> String {
+    SCOPE.with(|scope| format!("{}{}", scope.borrow(), step))
+}
+
+///
+/// Leaves a branch when `scoped` returns, even if the branch panics.
+struct Unscope(usize);
+
+impl Drop for Unscope {
+    fn drop(&mut self) {
+        SCOPE.with(|scope| scope.borrow_mut().truncate(self.0));
+    }
+}
+
+///
+/// Returns to a branch when `unscoped` returns, even if the Tea passed on panics.
+struct Rescope(String);
+
+impl Drop for Rescope {
+    fn drop(&mut self) {
+        SCOPE.with(|scope| scope.borrow_mut().push_str(&self.0));
+    }
+}
+
+///
+/// Problems found while brewing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BrewError {
+    /// A Brewer spent longer than its deadline on one Order.
+    OrderOverrun { brewer: usize, limit: Duration },
+    /// A Brewer spent longer than its deadline on one step.
+    StepOverrun { brewer: usize, step: String, limit: Duration },
+    /// A batch was dropped because the step it needed was stuck.
+    StepHalted { step: String, records: usize },
+    /// A step could not finish its work, such as writing to disk.
+    StepFailed { step: String, reason: String },
+    /// A branch panicked while brewing its copy of a batch.
+    BranchFailed { branch: String, records: usize, reason: String },
+    /// A checkpoint could not be saved or removed, so a restart may brew batches again.
+    CheckpointFailed { reason: String },
+}
+
+impl fmt::Display for BrewError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BrewError::OrderOverrun { brewer, limit } => {
+                write!(f, "brewer {} exceeded order deadline of {} ms", brewer, limit.as_millis())
+            },
+            BrewError::StepOverrun { brewer, step, limit } => {
+                write!(f, "brewer {} exceeded deadline of {} ms on step {}", brewer, limit.as_millis(), step)
+            },
+            BrewError::StepHalted { step, records } => {
+                write!(f, "dropped batch of {} tea waiting on stuck step {}", records, step)
+            },
+            BrewError::StepFailed { step, reason } => {
+                write!(f, "step {} failed: {}", step, reason)
+            },
+            BrewError::BranchFailed { branch, records, reason } => {
+                write!(f, "branch {} failed on batch of {} tea: {}", branch, records, reason)
+            },
+            BrewError::CheckpointFailed { reason } => {
+                write!(f, "checkpoint failed: {}", reason)
+            },
+        }
+    }
+}
+
+///
+/// Tea counted in and out of one step over a brew.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StepStats {
+    /// Batches the step brewed.
+    pub batches: usize,
+    /// Tea sent into the step.
+    pub records_in: usize,
+    /// Tea the step passed on.
+    pub records_out: usize,
+    /// Tea the step rejected, such as Tea failing a Filter.
+    pub records_rejected: usize,
+}
+
+///
+/// Summary of a brew, including what was left unprocessed when it was cancelled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrewReport {
+    /// Mode the brew was cancelled with, if it was cancelled.
+    pub cancelled: Option<CancelMode>,
+    /// Orders run by a Brewer, including any with batches interrupted part way.
+    pub orders_completed: usize,
+    /// Orders refused or dropped from the queue after the brew was cancelled.
+    pub orders_dropped: usize,
+    /// Batches stopped part way through the recipe by an immediate cancel.
+    pub batches_interrupted: usize,
+    /// Tea in the interrupted batches.
+    pub records_interrupted: usize,
+    /// Sources never collected because the brew was cancelled first.
+    pub sources_skipped: usize,
+    /// True if a `Limit` was reached, stopping the sources early without cancelling the brew.
+    pub limit_reached: bool,
+    /// Orders and steps that ran past their deadline.
+    pub overruns: usize,
+    /// Problems found while brewing.
+    pub errors: Vec<BrewError>,
+    /// Tea counted in and out of each step, keyed by step name.
+    pub steps: BTreeMap<String, StepStats>,
+}
+
+impl BrewReport {
+    ///
+    /// Returns true if the brew ran to completion without being cancelled.
+    pub fn is_complete(&self) -> bool {
+        self.cancelled.is_none()
+            && self.orders_dropped == 0 && self.batches_interrupted == 0 && self.sources_skipped == 0
+    }
+
+    ///
+    /// Returns true if the brew ran to completion without losing any Tea to a halted or failed
+    /// step or branch, so sinks can commit their output.
+    pub fn is_successful(&self) -> bool {
+        self.is_complete() && self.errors.iter().all(|error| {
+            matches!(error, BrewError::OrderOverrun { .. } | BrewError::StepOverrun { .. })
+        })
+    }
+
+    ///
+    /// Print out the brew summary.
+    pub fn print(&self) {
+        if let Some(mode) = self.cancelled {
+            println!("Brew cancelled ({:?})", mode);
+        }
+        if self.limit_reached {
+            println!("Limit reached, sources stopped early");
+        }
+        println!("Orders completed: {}", self.orders_completed);
+        if !self.is_complete() {
+            println!("Orders dropped: {}", self.orders_dropped);
+            println!("Batches interrupted: {} ({} tea)", self.batches_interrupted, self.records_interrupted);
+            println!("Sources skipped: {}", self.sources_skipped);
+        }
+        for (name, stats) in &self.steps {
+            println!("Step {}: {} in, {} out, {} rejected",
+                name, stats.records_in, stats.records_out, stats.records_rejected);
+        }
+        for error in &self.errors {
+            println!("Error: {}", error);
+        }
+    }
+}
@@ -0,0 +1,515 @@
+use crate::brewery::brew_step;
+use crate::context::{self, BrewContext};
+use crate::ingredient::{Ingredient, Steps};
+use crate::source::Source;
+
+use std::any::Any;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+///
+/// Recipe built as a directed acyclic graph of named steps.
+///
+/// Each source feeds one step, and every batch a step passes on is sent to each step it has an
+/// edge to, copied when there are several. A step with several edges into it brews the batches of
+/// each, merging the paths. Steps run in topological order, so a step only sees a batch once the
+/// steps before it are done with it, and held back Tea is flushed in the same order at the end of
+/// the brew. Steps are recorded in the `BrewReport` under their own names, which must be unique.
+pub struct Graph<T: Send> {
+    pub nodes: Steps<T>,
+    pub edges: Vec<(String, String)>,
+    pub sources: Vec<(Box<dyn Source<T>>, String)>,
+}
+
+impl<T: Send> Graph<T> {
+    ///
+    /// Creates an empty Graph.
+    pub fn new() -> Graph<T> {
+        Graph {
+            nodes: Vec::new(),
+            edges: Vec::new(),
+            sources: Vec::new(),
+        }
+    }
+
+    ///
+    /// Adds a step, named by the Ingredient's name.
+    ///
+    /// # Arguments
+    ///
+    /// * `ingredient` - the step to add
+    pub fn node(mut self, ingredient: Box<dyn Ingredient<T> + Send + Sync>) -> Graph<T> {
+        self.nodes.push(ingredient);
+        self
+    }
+
+    ///
+    /// Sends the batches passed on by one step to another.
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - name of the step passing the batches on
+    /// * `to` - name of the step brewing them next
+    pub fn edge(mut self, from: &str, to: &str) -> Graph<T> {
+        self.edges.push((String::from(from), String::from(to)));
+        self
+    }
+
+    ///
+    /// Adds a source feeding its batches into a step.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the source collecting Tea
+    /// * `into` - name of the first step brewing its batches
+    pub fn source(mut self, source: Box<dyn Source<T>>, into: &str) -> Graph<T> {
+        self.sources.push((source, String::from(into)));
+        self
+    }
+
+    ///
+    /// Checks the Graph can be brewed: steps are valid and uniquely named, edges and sources only
+    /// name steps in the Graph, every step has an input, and there are no cycles.
+    pub fn validate(&self) -> Result<(), String> {
+        self.plan().map(|_| ())
+    }
+
+    ///
+    /// Validates the Graph, returning the steps each step sends to and a topological order.
+    fn plan(&self) -> Result<(Vec<Vec<usize>>, Vec<usize>), String> {
+        if self.sources.is_empty() {
+            return Err(String::from("graph has no sources"));
+        }
+        let mut index = HashMap::new();
+        for (node, step) in self.nodes.iter().enumerate() {
+            step.validate().map_err(|error| format!("{}: {}", step.get_name(), error))?;
+            if index.insert(step.get_name(), node).is_some() {
+                return Err(format!("step {} is added twice", step.get_name()));
+            }
+        }
+        let find = |name: &str| index.get(name).copied().ok_or_else(|| format!("unknown step {}", name));
+
+        let mut children = vec![Vec::new(); self.nodes.len()];
+        let mut inputs = vec![0; self.nodes.len()];
+        let mut fed = HashSet::new();
+        for (from, to) in &self.edges {
+            let (from_node, to_node) = (find(from)?, find(to)?);
+            if children[from_node].contains(&to_node) {
+                return Err(format!("edge {} -> {} is added twice", from, to));
+            }
+            children[from_node].push(to_node);
+            inputs[to_node] += 1;
+        }
+        for (source, into) in &self.sources {
+            let node = find(into).map_err(|error| format!("source {}: {}", source.get_name(), error))?;
+            fed.insert(node);
+        }
+        if let Some(node) = (0 .. self.nodes.len()).find(|node| inputs[*node] == 0 && !fed.contains(node)) {
+            return Err(format!("step {} has no input", self.nodes[node].get_name()));
+        }
+
+        // Kahn's algorithm: steps left over once every step without inputs is removed are on a
+        // cycle, or fed by one.
+        let mut waiting = inputs.clone();
+        let mut ready: VecDeque<usize> = (0 .. self.nodes.len()).filter(|node| inputs[*node] == 0).collect();
+        let mut order = Vec::with_capacity(self.nodes.len());
+        while let Some(node) = ready.pop_front() {
+            order.push(node);
+            for child in &children[node] {
+                waiting[*child] -= 1;
+                if waiting[*child] == 0 {
+                    ready.push_back(*child);
+                }
+            }
+        }
+        if order.len() < self.nodes.len() {
+            let cycle: Vec<&str> = (0 .. self.nodes.len())
+                .filter(|node| waiting[*node] > 0)
+                .map(|node| self.nodes[node].get_name())
+                .collect();
+            return Err(format!("graph has a cycle through {}", cycle.join(", ")));
+        }
+        Ok((children, order))
+    }
+
+    ///
+    /// Builds the Graph into its steps and the sources feeding them, with the step each feeds.
+    pub(crate) fn build(self) -> Result<Built<T>, String> {
+        let (children, order) = self.plan()?;
+        let mut position = vec![0; order.len()];
+        for (at, node) in order.iter().enumerate() {
+            position[*node] = at;
+        }
+        let Graph { nodes, sources, .. } = self;
+        let sources = sources
+            .into_iter()
+            .map(|(source, into)| {
+                let node = nodes.iter().position(|step| step.get_name() == into).unwrap();
+                (source, node)
+            })
+            .collect();
+        Ok(Built { dag: Arc::new(Dag { steps: nodes, children, order, position }), sources })
+    }
+}
+
+impl<T: Send> Default for Graph<T> {
+    fn default() -> Self {
+        Graph::new()
+    }
+}
+
+///
+/// Graph ready to brew, with the sources feeding it and the step each feeds.
+pub(crate) struct Built<T: Send> {
+    pub(crate) dag: Arc<Dag<T>>,
+    pub(crate) sources: Vec<(Box<dyn Source<T>>, usize)>,
+}
+
+///
+/// Validated Graph, shared by the sources feeding it.
+pub(crate) struct Dag<T: Send> {
+    steps: Steps<T>,
+    children: Vec<Vec<usize>>,
+    order: Vec<usize>,
+    position: Vec<usize>,
+}
+
+impl<T: Send> Dag<T> {
+    pub(crate) fn steps(&self) -> &Steps<T> {
+        &self.steps
+    }
+
+    ///
+    /// Returns the steps each step sends its batches to.
+    pub(crate) fn children(&self) -> &[Vec<usize>] {
+        &self.children
+    }
+}
+
+impl<T: Send + Clone + 'static> Dag<T> {
+    ///
+    /// Brews a batch from the given step through the rest of the Graph.
+    pub(crate) fn brew(&self, node: usize, tea_batch: Vec<T>, context: &Option<Arc<BrewContext>>) {
+        let mut pending = self.pending();
+        pending[node].push(tea_batch);
+        self.brew_pending(self.position[node], pending, context);
+    }
+
+    ///
+    /// Sends on the Tea held back by each step in topological order, brewing it through the rest
+    /// of the Graph.
+    pub(crate) fn flush(&self, context: &Option<Arc<BrewContext>>) {
+        for (at, node) in self.order.iter().enumerate() {
+            let step = &self.steps[*node];
+            step.flush_into(&mut |tea_batch| {
+                if let Some(context) = context {
+                    context.step_flushed(step.get_name(), tea_batch.len());
+                }
+                let mut pending = self.pending();
+                self.send(*node, vec![tea_batch], &mut pending);
+                self.brew_pending(at + 1, pending, context);
+            });
+        }
+    }
+
+    fn pending(&self) -> Vec<Vec<Vec<T>>> {
+        (0 .. self.steps.len()).map(|_| Vec::new()).collect()
+    }
+
+    ///
+    /// Brews the batches waiting at each step, starting at a position in the topological order.
+    fn brew_pending(&self, from: usize, mut pending: Vec<Vec<Vec<T>>>, context: &Option<Arc<BrewContext>>) {
+        for node in &self.order[from ..] {
+            for tea_batch in std::mem::take(&mut pending[*node]) {
+                if let Some(tea_batches) = brew_step(&*self.steps[*node], tea_batch, context) {
+                    self.send(*node, tea_batches, &mut pending);
+                }
+            }
+        }
+    }
+
+    ///
+    /// Hands the batches passed on by a step to each step it has an edge to.
+    fn send(&self, node: usize, tea_batches: Vec<Vec<T>>, pending: &mut [Vec<Vec<T>>]) {
+        if let Some((last, children)) = self.children[node].split_last() {
+            for tea_batch in tea_batches {
+                for child in children {
+                    pending[*child].push(tea_batch.clone());
+                }
+                pending[*last].push(tea_batch);
+            }
+        }
+    }
+}
+
+///
+/// Step recipe given to a source feeding a Graph, sending its batches into the step it feeds.
+pub(crate) struct DagEntry<T: Send> {
+    pub(crate) name: String,
+    pub(crate) dag: Arc<Dag<T>>,
+    pub(crate) node: usize,
+    /// Whether this entry flushes the Graph, so it is flushed once however many sources feed it.
+    pub(crate) flushes: bool,
+}
+
+impl<T: Send + Clone + 'static> Ingredient<T> for DagEntry<T> {
+    fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
+        self.dag.brew(self.node, tea_batch, &context::current());
+        Vec::new()
+    }
+    fn flush_into(&self, _send: &mut dyn FnMut(Vec<T>)) {
+        if self.flushes {
+            self.dag.flush(&context::current());
+        }
+    }
+    fn get_name(&self) -> &str {
+        &self.name[..]
+    }
+    fn kind(&self) -> &str {
+        "Graph"
+    }
+    fn print(&self) {
+        println!("Current Step: {}", self.get_name());
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Graph;
+    use super::super::aggregate::{Aggregate, Reducer, Reduced};
+    use super::super::brewery::{Brewery, make_tea};
+    use super::super::ingredient::{Fill, Steep, Pour, Argument, Computation};
+    use super::super::pipeline::Pipeline;
+    use super::super::pot::Pot;
+    use std::any::Any;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq, Default, Clone)]
+    struct TestTea {
+        x: i32,
+    }
+
+    struct FillArgs {
+        pub start: i32,
+    }
+
+    impl Argument for FillArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct PouredArgs {
+        pub poured: Arc<Mutex<Vec<i32>>>,
+    }
+
+    impl Argument for PouredArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn fill(name: &str, start: i32) -> Box<Fill<TestTea>> {
+        Box::new(Fill {
+            name: String::from(name),
+            source: String::from("hardcoded"),
+            computation: Box::new(|args, brewery, recipe| {
+                let start = args.as_ref().unwrap().as_any().downcast_ref::<FillArgs>().unwrap().start;
+                for x in start .. start + 3 {
+                    let recipe = Arc::clone(&recipe);
+                    brewery.take_order(move || {
+                        make_tea(vec![TestTea { x }], recipe);
+                    });
+                }
+            }),
+            params: Some(Box::new(FillArgs { start })),
+        })
+    }
+
+    fn steep(name: &str, computation: Computation<TestTea>) -> Box<Steep<TestTea>> {
+        Box::new(Steep { name: String::from(name), computation: Box::new(computation), params: None })
+    }
+
+    fn pour(name: &str, poured: &Arc<Mutex<Vec<i32>>>) -> Box<Pour<TestTea>> {
+        Box::new(Pour {
+            name: String::from(name),
+            computation: Box::new(|tea_batch, args| {
+                let args = args.as_ref().unwrap().as_any().downcast_ref::<PouredArgs>().unwrap();
+                args.poured.lock().unwrap().extend(tea_batch.iter().map(|tea| tea.x));
+                tea_batch
+            }),
+            params: Some(Box::new(PouredArgs { poured: Arc::clone(poured) })),
+        })
+    }
+
+    fn sorted(poured: &Arc<Mutex<Vec<i32>>>) -> Vec<i32> {
+        let mut poured = poured.lock().unwrap().clone();
+        poured.sort_unstable();
+        poured
+    }
+
+    fn two_source_graph(archive: &Arc<Mutex<Vec<i32>>>, sink: &Arc<Mutex<Vec<i32>>>) -> Graph<TestTea> {
+        Graph::new()
+            .source(fill("orders", 1), "clean")
+            .source(fill("refunds", 10), "negate")
+            .node(steep("clean", |tea_batch, _args| tea_batch))
+            .node(pour("archive", archive))
+            .node(steep("double", |tea_batch, _args| tea_batch.into_iter().map(|tea| TestTea { x: tea.x * 2 }).collect()))
+            .node(steep("negate", |tea_batch, _args| tea_batch.into_iter().map(|tea| TestTea { x: -tea.x }).collect()))
+            .node(pour("sink", sink))
+            .edge("clean", "archive")
+            .edge("clean", "double")
+            .edge("double", "sink")
+            .edge("negate", "sink")
+    }
+
+    #[test]
+    fn graph_brews_each_source_through_its_subgraph() {
+        let archive = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::new(Mutex::new(Vec::new()));
+        let new_pot = Pot::new().add_graph(two_source_graph(&archive, &sink));
+        assert_eq!(new_pot.get_sources().len(), 2);
+        let report = new_pot.brew(&Brewery::new(2)).unwrap();
+
+        assert_eq!(sorted(&archive), vec![1, 2, 3]);
+        assert_eq!(sorted(&sink), vec![-12, -11, -10, 2, 4, 6]);
+        assert_eq!(report.steps["orders->clean"].records_in, 3);
+        assert_eq!(report.steps["refunds->negate"].records_in, 3);
+        assert_eq!(report.steps["sink"].records_in, 6);
+        assert_eq!(report.steps["sink"].batches, 6);
+        assert_eq!(report.steps["archive"].records_in, 3);
+    }
+
+    #[test]
+    fn graph_brews_beside_the_recipe_when_pipelined() {
+        let archive = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::new(Mutex::new(Vec::new()));
+        let recipe_poured = Arc::new(Mutex::new(Vec::new()));
+        let new_pot = Pot::new()
+            .add_source(fill("plain", 100))
+            .add_ingredient(pour("recipe_pour", &recipe_poured))
+            .add_graph(two_source_graph(&archive, &sink));
+        let report = new_pot.brew_pipelined(&Brewery::new(1), &Pipeline::new()).unwrap();
+
+        assert_eq!(sorted(&recipe_poured), vec![100, 101, 102]);
+        assert_eq!(sorted(&sink), vec![-12, -11, -10, 2, 4, 6]);
+        assert_eq!(report.steps["recipe_pour"].records_in, 3);
+    }
+
+    #[test]
+    fn graph_step_panicking_when_pipelined_drops_its_batch() {
+        let sink = Arc::new(Mutex::new(Vec::new()));
+        let graph = Graph::new()
+            .source(fill("orders", 1), "check")
+            .node(steep("check", |tea_batch, _args| {
+                if tea_batch[0].x == 2 {
+                    panic!("bad order");
+                }
+                tea_batch
+            }))
+            .node(pour("sink", &sink))
+            .edge("check", "sink");
+        let report = Pot::new().add_graph(graph).brew_pipelined(&Brewery::new(1), &Pipeline::new()).unwrap();
+
+        assert_eq!(sorted(&sink), vec![1, 3]);
+        assert_eq!(report.orders_completed, 2);
+        assert_eq!(report.orders_dropped, 1);
+        assert!(!report.is_successful());
+    }
+
+    #[test]
+    fn graph_flushes_in_topological_order() {
+        let sink = Arc::new(Mutex::new(Vec::new()));
+        let total = Aggregate::new("total", |_tea: &TestTea| String::from("all"), |_key, reduced| {
+            match reduced[0] {
+                Reduced::Sum(sum) => TestTea { x: sum as i32 },
+                _ => TestTea::default(),
+            }
+        }).reduce(Reducer::Sum(|tea: &TestTea| tea.x as f64));
+        let graph = Graph::new()
+            .source(fill("orders", 1), "total")
+            .source(fill("more_orders", 4), "total")
+            .node(Box::new(total))
+            .node(steep("double", |tea_batch, _args| tea_batch.into_iter().map(|tea| TestTea { x: tea.x * 2 }).collect()))
+            .node(pour("sink", &sink))
+            .edge("total", "double")
+            .edge("double", "sink");
+        let report = Pot::new().add_graph(graph).brew(&Brewery::new(2)).unwrap();
+
+        assert_eq!(*sink.lock().unwrap(), vec![42]);
+        assert_eq!(report.steps["total"].records_out, 1);
+        assert_eq!(report.steps["sink"].records_in, 1);
+    }
+
+    #[test]
+    fn graph_shows_in_diagrams() {
+        let archive = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::new(Mutex::new(Vec::new()));
+        let mermaid = Pot::new().add_graph(two_source_graph(&archive, &sink)).to_mermaid();
+        let lines: Vec<&str> = mermaid.lines().collect();
+        assert_eq!(lines, vec![
+            "flowchart LR",
+            "    n0[(\"orders<br/>(Fill)\")]",
+            "    n1[\"clean<br/>(Steep)\"]",
+            "    n2[\"archive<br/>(Pour)\"]",
+            "    n3[\"double<br/>(Steep)\"]",
+            "    n4[\"negate<br/>(Steep)\"]",
+            "    n5[\"sink<br/>(Pour)\"]",
+            "    n6[(\"refunds<br/>(Fill)\")]",
+            "    n1 --> n2",
+            "    n1 --> n3",
+            "    n3 --> n5",
+            "    n4 --> n5",
+            "    n0 --> n1",
+            "    n6 --> n4",
+        ]);
+    }
+
+    #[test]
+    fn graph_validates_shape() {
+        let poured = Arc::new(Mutex::new(Vec::new()));
+        let graph: Graph<TestTea> = Graph::new().node(pour("sink", &poured));
+        assert_eq!(graph.validate(), Err(String::from("graph has no sources")));
+
+        let graph = Graph::new().source(fill("orders", 0), "sink").node(pour("sink", &poured)).node(pour("sink", &poured));
+        assert_eq!(graph.validate(), Err(String::from("step sink is added twice")));
+
+        let graph = Graph::new().source(fill("orders", 0), "clean").node(pour("sink", &poured));
+        assert_eq!(graph.validate(), Err(String::from("source orders: unknown step clean")));
+
+        let graph = Graph::new().source(fill("orders", 0), "sink").node(pour("sink", &poured)).edge("sink", "archive");
+        assert_eq!(graph.validate(), Err(String::from("unknown step archive")));
+
+        let graph = Graph::new()
+            .source(fill("orders", 0), "sink")
+            .node(pour("sink", &poured))
+            .node(pour("archive", &poured));
+        assert_eq!(graph.validate(), Err(String::from("step archive has no input")));
+
+        let graph = Graph::new()
+            .source(fill("orders", 0), "clean")
+            .node(steep("clean", |tea_batch, _args| tea_batch))
+            .node(steep("a", |tea_batch, _args| tea_batch))
+            .node(steep("b", |tea_batch, _args| tea_batch))
+            .edge("clean", "a")
+            .edge("a", "b")
+            .edge("b", "a");
+        assert_eq!(graph.validate(), Err(String::from("graph has a cycle through a, b")));
+
+        let graph = Graph::new()
+            .source(fill("orders", 0), "clean")
+            .node(steep("clean", |tea_batch, _args| tea_batch))
+            .node(pour("sink", &poured))
+            .edge("clean", "sink");
+        assert_eq!(graph.validate(), Ok(()));
+    }
+
+    #[test]
+    fn pot_rejects_invalid_graph() {
+        let problems = Pot::<TestTea>::new().add_graph(Graph::new()).validate().unwrap_err();
+        assert!(problems.contains(&String::from("graph cannot be brewed: graph has no sources")));
+    }
+}
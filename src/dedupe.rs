@@ -0,0 +1,660 @@
+use crate::aggregate::GroupKey;
+use crate::context;
+use crate::ingredient::Ingredient;
+
+use std::any::Any;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+
+///
+/// Trait given to stores remembering the keys a Dedupe has seen.
+///
+/// Stores are shared by every Brewer running the Dedupe, so checking and recording a key must
+/// happen as one step: when two Brewers insert the same key at once, only one may see it as new.
+pub trait KeyStore: Send + Sync {
+    ///
+    /// Records the key, returning true if it had not been seen before.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - dedupe key of a Tea
+    fn insert(&self, key: &str) -> bool;
+
+    ///
+    /// Forgets a key recorded for a batch that was not fully brewed, so the batch is let through
+    /// when it is delivered again. Stores unable to forget keys, such as a Bloom filter, keep it.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - dedupe key of a Tea
+    fn forget(&self, _key: &str) {}
+
+    ///
+    /// Saves keys recorded since the last call, for stores that outlive the brew. Called once the
+    /// brew is done, after keys of batches that were not fully brewed have been forgotten.
+    fn persist(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+///
+/// Keeps every key seen in memory.
+#[derive(Default)]
+pub struct MemoryStore {
+    keys: Mutex<HashSet<String>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl KeyStore for MemoryStore {
+    fn insert(&self, key: &str) -> bool {
+        self.keys.lock().unwrap().insert(String::from(key))
+    }
+
+    fn forget(&self, key: &str) {
+        self.keys.lock().unwrap().remove(key);
+    }
+}
+
+///
+/// Keeps the most recently seen keys in memory, forgetting the least recently seen once full.
+/// Duplicates arriving further apart than `capacity` keys are let through.
+pub struct LruStore {
+    capacity: usize,
+    recent: Mutex<Recent>,
+}
+
+///
+/// Keys by when they were last seen.
+#[derive(Default)]
+struct Recent {
+    seen: HashMap<String, u64>,
+    order: VecDeque<(u64, String)>,
+    tick: u64,
+}
+
+impl LruStore {
+    ///
+    /// Creates a store remembering up to `capacity` keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - most keys held at once
+    pub fn new(capacity: usize) -> LruStore {
+        assert!(capacity > 0);
+        LruStore { capacity, recent: Mutex::new(Recent::default()) }
+    }
+}
+
+impl KeyStore for LruStore {
+    fn insert(&self, key: &str) -> bool {
+        let mut recent = self.recent.lock().unwrap();
+        let recent = &mut *recent;
+        recent.tick += 1;
+        let new = recent.seen.insert(String::from(key), recent.tick).is_none();
+        recent.order.push_back((recent.tick, String::from(key)));
+
+        while recent.seen.len() > self.capacity {
+            let (tick, key) = recent.order.pop_front().unwrap();
+            if recent.seen.get(&key) == Some(&tick) {
+                recent.seen.remove(&key);
+            }
+        }
+        // Keys seen again leave older entries behind, which are dropped once they pile up.
+        if recent.order.len() > self.capacity * 2 {
+            let seen = &recent.seen;
+            recent.order.retain(|(tick, key)| seen.get(key) == Some(tick));
+        }
+        new
+    }
+
+    fn forget(&self, key: &str) {
+        // Its entry in the order is left behind, and dropped once the entries pile up.
+        self.recent.lock().unwrap().seen.remove(key);
+    }
+}
+
+///
+/// Remembers keys in a Bloom filter of fixed size, for streams with too many keys to hold.
+/// Keys are never forgotten, but a small share of new keys are mistaken for duplicates.
+pub struct BloomStore {
+    hashes: u64,
+    bits: Mutex<Vec<u64>>,
+}
+
+impl BloomStore {
+    ///
+    /// Creates a filter sized for the expected number of keys and rate of false duplicates.
+    ///
+    /// # Arguments
+    ///
+    /// * `expected_keys` - number of distinct keys the filter is sized for
+    /// * `false_positive_rate` - share of new keys mistaken for duplicates once full
+    pub fn new(expected_keys: usize, false_positive_rate: f64) -> BloomStore {
+        assert!(expected_keys > 0);
+        assert!(false_positive_rate > 0.0 && false_positive_rate < 1.0);
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-(expected_keys as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil().max(64.0);
+        let hashes = (bits / expected_keys as f64 * ln2).round().max(1.0);
+        BloomStore { hashes: hashes as u64, bits: Mutex::new(vec![0; (bits as usize).div_ceil(64)]) }
+    }
+
+    ///
+    /// Returns the two hashes combined into each of the filter's bit positions.
+    fn hash(key: &str) -> (u64, u64) {
+        let mut first = DefaultHasher::new();
+        key.hash(&mut first);
+        let mut second = DefaultHasher::new();
+        (key, 0x9e37_79b9_7f4a_7c15_u64).hash(&mut second);
+        (first.finish(), second.finish() | 1)
+    }
+}
+
+impl KeyStore for BloomStore {
+    fn insert(&self, key: &str) -> bool {
+        let (first, second) = BloomStore::hash(key);
+        let mut bits = self.bits.lock().unwrap();
+        let size = bits.len() as u64 * 64;
+        let mut new = false;
+        for hash in 0 .. self.hashes {
+            let bit = first.wrapping_add(hash.wrapping_mul(second)) % size;
+            let (word, mask) = ((bit / 64) as usize, 1 << (bit % 64));
+            if bits[word] & mask == 0 {
+                bits[word] |= mask;
+                new = true;
+            }
+        }
+        new
+    }
+}
+
+///
+/// Keeps every key seen in memory and appends new keys to a file once their brew is done, so
+/// dedupe carries on where it left off when the file is opened again after a restart.
+pub struct FileStore {
+    keys: Mutex<Keys>,
+}
+
+///
+/// Keys seen by a FileStore, and those not yet saved to its file.
+struct Keys {
+    seen: HashSet<String>,
+    unsaved: HashSet<String>,
+    writer: BufWriter<File>,
+}
+
+impl FileStore {
+    ///
+    /// Opens the store, loading the keys already in the file and creating it if missing. A last
+    /// key cut short by a crash while it was saved is dropped from the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - file the keys are kept in
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileStore> {
+        let file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        let mut keys = HashSet::new();
+        let mut reader = BufReader::new(&file);
+        let mut saved = 0;
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 {
+            if !line.ends_with('\n') {
+                // Only the last line can be unfinished; cut it off so new keys start on their own line.
+                file.set_len(saved)?;
+                break;
+            }
+            let key: String = serde_json::from_str(&line)?;
+            keys.insert(key);
+            saved += line.len() as u64;
+            line.clear();
+        }
+        Ok(FileStore {
+            keys: Mutex::new(Keys { seen: keys, unsaved: HashSet::new(), writer: BufWriter::new(file) }),
+        })
+    }
+}
+
+impl KeyStore for FileStore {
+    fn insert(&self, key: &str) -> bool {
+        let mut keys = self.keys.lock().unwrap();
+        if !keys.seen.insert(String::from(key)) {
+            return false;
+        }
+        keys.unsaved.insert(String::from(key));
+        true
+    }
+
+    fn forget(&self, key: &str) {
+        let mut keys = self.keys.lock().unwrap();
+        if keys.unsaved.remove(key) {
+            keys.seen.remove(key);
+        }
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        let mut keys = self.keys.lock().unwrap();
+        let keys = &mut *keys;
+        for key in &keys.unsaved {
+            // Keys are written as JSON strings so they cannot break the one key per line layout.
+            writeln!(keys.writer, "{}", serde_json::to_string(key)?)?;
+        }
+        keys.writer.flush()?;
+        keys.writer.get_ref().sync_data()?;
+        keys.unsaved.clear();
+        Ok(())
+    }
+}
+
+///
+/// Ingredient used to drop Tea whose key has already been seen, such as data delivered again by
+/// a source. Duplicates are counted as rejected in the brew's step statistics. Keys recorded for
+/// a batch that is not fully brewed are forgotten, so the batch is let through when redelivered.
+/// A batch holding a key that another batch still brewing has recorded waits for that batch to
+/// finish, so the key is not dropped as a duplicate of Tea that is then forgotten.
+pub struct Dedupe<T: Send> {
+    pub name: String,
+    pub key: Box<GroupKey<T>>,
+    pub store: Arc<dyn KeyStore>,
+    claims: Arc<Claims>,
+}
+
+///
+/// Keys recorded for batches still brewing, with the Order each belongs to.
+#[derive(Default)]
+struct Claims {
+    pending: Mutex<HashMap<String, usize>>,
+    released: Condvar,
+}
+
+impl<T: Send> Dedupe<T> {
+    ///
+    /// Creates a Dedupe remembering keys in memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the step
+    /// * `key` - key duplicates share
+    pub fn new(name: &str, key: GroupKey<T>) -> Dedupe<T> {
+        Dedupe {
+            name: String::from(name),
+            key: Box::new(key),
+            store: Arc::new(MemoryStore::new()),
+            claims: Arc::new(Claims::default()),
+        }
+    }
+
+    ///
+    /// Sets the store the keys seen are kept in.
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - LRU, Bloom filter, file or custom key store
+    pub fn store(mut self, store: Box<dyn KeyStore>) -> Dedupe<T> {
+        self.store = Arc::from(store);
+        self
+    }
+}
+
+impl<T: Send + 'static> Ingredient<T> for Dedupe<T> {
+    fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
+        let records_in = tea_batch.len();
+        let order = context::order_id();
+        let keys: Vec<String> = tea_batch.iter().map(|tea| (self.key)(tea)).collect();
+        // Keys are recorded in sorted order, so two batches cannot each wait on a key the other holds.
+        let mut sorted: Vec<&String> = keys.iter().collect();
+        sorted.sort();
+        sorted.dedup();
+        let mut recorded = HashSet::new();
+        let mut pending = self.claims.pending.lock().unwrap();
+        for key in sorted {
+            while pending.get(key).is_some_and(|owner| Some(*owner) != order) {
+                pending = self.claims.released.wait(pending).unwrap();
+            }
+            if !pending.contains_key(key) && self.store.insert(key) {
+                if let Some(order) = order {
+                    pending.insert(key.clone(), order);
+                }
+                recorded.insert(key.clone());
+            }
+        }
+        drop(pending);
+
+        let mut unclaimed = recorded.clone();
+        let kept: Vec<T> = tea_batch
+            .into_iter()
+            .zip(keys)
+            .filter(|(_, key)| unclaimed.remove(key))
+            .map(|(tea, _)| tea)
+            .collect();
+        if order.is_some() && !recorded.is_empty() {
+            let store = Arc::clone(&self.store);
+            let claims = Arc::clone(&self.claims);
+            context::on_finished(move |brewed| {
+                let mut pending = claims.pending.lock().unwrap();
+                for key in &recorded {
+                    pending.remove(key);
+                    if !brewed {
+                        store.forget(key);
+                    }
+                }
+                claims.released.notify_all();
+            });
+        }
+        if kept.len() < records_in {
+            if let Some(context) = context::current() {
+                context.records_rejected(self.get_name(), records_in - kept.len());
+            }
+        }
+        kept
+    }
+    fn finish(&self, success: bool) -> Result<(), String> {
+        // Keys of failed batches are already forgotten. A brew holding its output until it
+        // succeeds discards the rest too, so their keys are only saved once it has.
+        let held = context::current().is_some_and(|context| context.is_output_held());
+        if success || !held {
+            self.store.persist().map_err(|error| format!("dedupe keys cannot be saved: {}", error))?;
+        }
+        Ok(())
+    }
+    fn get_name(&self) -> &str {
+        &self.name[..]
+    }
+    fn kind(&self) -> &str {
+        "Dedupe"
+    }
+    fn print(&self) {
+        println!("Current Step: {}", self.get_name());
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dedupe, KeyStore, MemoryStore, LruStore, BloomStore, FileStore};
+    use super::super::ack::Ack;
+    use super::super::brewery::{Brewery, make_tea};
+    use super::super::context::BrewError;
+    use super::super::ingredient::{Fill, Steep, Pour, Argument, Ingredient};
+    use super::super::pot::Pot;
+    use std::any::Any;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq, Default, Clone)]
+    struct TestTea {
+        id: u32,
+    }
+
+    fn by_id(tea: &TestTea) -> String {
+        tea.id.to_string()
+    }
+
+    fn ids(tea: Vec<TestTea>) -> Vec<u32> {
+        tea.into_iter().map(|tea| tea.id).collect()
+    }
+
+    fn batch(ids: &[u32]) -> Vec<TestTea> {
+        ids.iter().map(|id| TestTea { id: *id }).collect()
+    }
+
+    #[test]
+    fn dedupe_drops_repeated_keys() {
+        let dedupe = Dedupe::new("dedupe", by_id);
+        assert_eq!(ids(dedupe.exec(batch(&[1, 2, 1, 3]))), vec![1, 2, 3]);
+        assert_eq!(ids(dedupe.exec(batch(&[3, 4]))), vec![4]);
+    }
+
+    #[test]
+    fn lru_store_forgets_oldest_keys() {
+        let store = LruStore::new(2);
+        assert!(store.insert("a"));
+        assert!(store.insert("b"));
+        assert!(!store.insert("a"));
+        assert!(store.insert("c"));
+        // b was the least recently seen.
+        assert!(store.insert("b"));
+        assert!(!store.insert("b"));
+        for _ in 0 .. 10 {
+            assert!(!store.insert("b"));
+        }
+        assert!(store.recent.lock().unwrap().order.len() <= 4);
+    }
+
+    #[test]
+    fn bloom_store_never_forgets() {
+        let store = BloomStore::new(1000, 0.01);
+        let new = (0 .. 1000).filter(|key| store.insert(&key.to_string())).count();
+        assert!(new > 980);
+        assert!((0 .. 1000).all(|key| !store.insert(&key.to_string())));
+    }
+
+    #[test]
+    fn file_store_survives_restart() {
+        let path = env::temp_dir().join(format!("rettle_dedupe_{}.keys", std::process::id()));
+        let _ = fs::remove_file(&path);
+        {
+            let dedupe = Dedupe::new("dedupe", by_id).store(Box::new(FileStore::open(&path).unwrap()));
+            assert_eq!(ids(dedupe.exec(batch(&[1, 2]))), vec![1, 2]);
+            dedupe.finish(true).unwrap();
+        }
+        {
+            let dedupe = Dedupe::new("dedupe", by_id).store(Box::new(FileStore::open(&path).unwrap()));
+            assert_eq!(ids(dedupe.exec(batch(&[2, 3]))), vec![3]);
+            dedupe.finish(false).unwrap();
+        }
+        // Keys of batches brewed are saved even if the brew failed.
+        let dedupe = Dedupe::new("dedupe", by_id).store(Box::new(FileStore::open(&path).unwrap()));
+        assert_eq!(ids(dedupe.exec(batch(&[2, 3, 4]))), vec![4]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_store_drops_unfinished_key() {
+        let path = env::temp_dir().join(format!("rettle-dedupe-partial-{}", std::process::id()));
+        fs::write(&path, "\"1\"\n\"2").unwrap();
+        {
+            let dedupe = Dedupe::new("dedupe", by_id).store(Box::new(FileStore::open(&path).unwrap()));
+            assert_eq!(ids(dedupe.exec(batch(&[1, 2]))), vec![2]);
+            dedupe.finish(true).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "\"1\"\n\"2\"\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn redelivered_batches_are_not_duplicates() {
+        let poured = Arc::new(Mutex::new(Vec::new()));
+        let new_pot = Pot::new()
+            .add_source(Box::new(Fill {
+                name: String::from("redelivering"),
+                source: String::from("hardcoded"),
+                computation: Box::new(|_args, brewery, recipe| {
+                    for id in 1 .. 3 {
+                        loop {
+                            let recipe = Arc::clone(&recipe);
+                            let ack = brewery.take_order_acked(move || {
+                                make_tea(vec![TestTea { id }], recipe);
+                            });
+                            if ack.wait() == Ack::Acked {
+                                break;
+                            }
+                        }
+                    }
+                }),
+                params: None,
+            }))
+            .add_ingredient(Box::new(Dedupe::new("dedupe", by_id)))
+            .add_ingredient(Box::new(Steep {
+                name: String::from("flaky"),
+                computation: Box::new(|tea_batch: Vec<TestTea>, args| {
+                    let failures = &args.as_ref().unwrap().as_any().downcast_ref::<CountArgs>().unwrap().count;
+                    if tea_batch[0].id == 1 && failures.fetch_add(1, Ordering::SeqCst) == 0 {
+                        panic!("flaky");
+                    }
+                    tea_batch
+                }),
+                params: Some(Box::new(CountArgs { count: Arc::new(AtomicUsize::new(0)) })),
+            }))
+            .add_ingredient(Box::new(Pour {
+                name: String::from("pour"),
+                computation: Box::new(|tea_batch: Vec<TestTea>, args| {
+                    let poured = &args.as_ref().unwrap().as_any().downcast_ref::<PouredArgs>().unwrap().poured;
+                    poured.lock().unwrap().extend(tea_batch.iter().map(|tea| tea.id));
+                    tea_batch
+                }),
+                params: Some(Box::new(PouredArgs { poured: Arc::clone(&poured) })),
+            }));
+        new_pot.brew(&Brewery::new(1)).unwrap();
+        assert_eq!(*poured.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn duplicate_waits_for_failing_batch() {
+        let poured = Arc::new(Mutex::new(Vec::new()));
+        let new_pot = Pot::new()
+            .add_source(Box::new(Fill {
+                name: String::from("twice"),
+                source: String::from("hardcoded"),
+                computation: Box::new(|_args, brewery, recipe| {
+                    for _ in 0 .. 2 {
+                        let recipe = Arc::clone(&recipe);
+                        brewery.take_order(move || {
+                            make_tea(vec![TestTea { id: 1 }], recipe);
+                        });
+                    }
+                }),
+                params: None,
+            }))
+            .add_ingredient(Box::new(Dedupe::new("dedupe", by_id)))
+            .add_ingredient(Box::new(Steep {
+                name: String::from("flaky"),
+                computation: Box::new(|tea_batch: Vec<TestTea>, args| {
+                    let failures = &args.as_ref().unwrap().as_any().downcast_ref::<CountArgs>().unwrap().count;
+                    if failures.fetch_add(1, Ordering::SeqCst) == 0 {
+                        // Holds the key while the duplicate reaches the Dedupe on the other Brewer.
+                        thread::sleep(Duration::from_millis(200));
+                        panic!("flaky");
+                    }
+                    tea_batch
+                }),
+                params: Some(Box::new(CountArgs { count: Arc::new(AtomicUsize::new(0)) })),
+            }))
+            .add_ingredient(Box::new(Pour {
+                name: String::from("pour"),
+                computation: Box::new(|tea_batch: Vec<TestTea>, args| {
+                    let poured = &args.as_ref().unwrap().as_any().downcast_ref::<PouredArgs>().unwrap().poured;
+                    poured.lock().unwrap().extend(tea_batch.iter().map(|tea| tea.id));
+                    tea_batch
+                }),
+                params: Some(Box::new(PouredArgs { poured: Arc::clone(&poured) })),
+            }));
+        new_pot.brew(&Brewery::new(2)).unwrap();
+        // The batch that failed forgot the key, so its duplicate was brewed instead of dropped.
+        assert_eq!(*poured.lock().unwrap(), vec![1]);
+    }
+
+    struct CountArgs {
+        pub count: Arc<AtomicUsize>,
+    }
+
+    impl Argument for CountArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct PouredArgs {
+        pub poured: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl Argument for PouredArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct FullStore;
+
+    impl KeyStore for FullStore {
+        fn insert(&self, _key: &str) -> bool {
+            true
+        }
+
+        fn persist(&self) -> io::Result<()> {
+            Err(io::Error::other("disk full"))
+        }
+    }
+
+    #[test]
+    fn failed_key_saves_are_reported() {
+        let new_pot = Pot::new()
+            .add_source(Box::new(Fill {
+                name: String::from("fill"),
+                source: String::from("hardcoded"),
+                computation: Box::new(|_args, brewery, recipe| {
+                    brewery.take_order(move || {
+                        make_tea(vec![TestTea { id: 1 }], recipe);
+                    });
+                }),
+                params: None,
+            }))
+            .add_ingredient(Box::new(Dedupe::new("dedupe", by_id).store(Box::new(FullStore))))
+            .add_ingredient(Box::new(Pour {
+                name: String::from("pour"),
+                computation: Box::new(|tea_batch: Vec<TestTea>, _args| tea_batch),
+                params: None,
+            }));
+        let report = new_pot.brew(&Brewery::new(1)).unwrap();
+        assert_eq!(report.errors, vec![BrewError::StepFailed {
+            step: String::from("dedupe"),
+            reason: String::from("dedupe keys cannot be saved: disk full"),
+        }]);
+    }
+
+    #[test]
+    fn stores_are_safe_across_brewers() {
+        // A Bloom filter may mistake the odd new key for a duplicate, but never lets one through twice.
+        let stores: Vec<(Arc<dyn KeyStore>, usize)> = vec![
+            (Arc::new(MemoryStore::new()), 200),
+            (Arc::new(LruStore::new(1000)), 200),
+            (Arc::new(BloomStore::new(1000, 0.001)), 198),
+        ];
+        for (store, least_new) in stores {
+            let new = Arc::new(AtomicUsize::new(0));
+            let brewers: Vec<_> = (0 .. 4)
+                .map(|_| {
+                    let store = Arc::clone(&store);
+                    let new = Arc::clone(&new);
+                    thread::spawn(move || {
+                        for key in 0 .. 200 {
+                            if store.insert(&key.to_string()) {
+                                new.fetch_add(1, Ordering::SeqCst);
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for brewer in brewers {
+                brewer.join().unwrap();
+            }
+            assert!(new.load(Ordering::SeqCst) <= 200);
+            assert!(new.load(Ordering::SeqCst) >= least_new);
+        }
+    }
+}
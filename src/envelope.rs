@@ -0,0 +1,199 @@
+use crate::context;
+
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+///
+/// Tea wrapped with where it came from and the steps it has been through, so Pours can write
+/// provenance columns and bad output can be traced back to its input.
+///
+/// Brew a `Pot<Envelope<T>>` to carry envelopes through the recipe. Steeps and Pours see each
+/// envelope's `metadata` alongside its `tea`. Sources wrap the Tea they collect with
+/// `Envelope::batch`, and `Pot::trace_lineage` records each step in `metadata.steps`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub tea: T,
+    pub metadata: Metadata,
+}
+
+///
+/// Where a Tea came from and the steps it has been through.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Name of the source the Tea was collected by, such as `Fill::name`.
+    pub source: String,
+    /// Where the source collected the Tea from, such as `Fill::source`.
+    pub location: String,
+    /// Position of the Tea in its source, such as a file offset or line number, if known.
+    pub offset: Option<String>,
+    /// When the source collected the Tea.
+    pub ingested_at: SystemTime,
+    /// Batch the Tea was collected in, numbered from 1 in each brew.
+    pub batch: u64,
+    /// Steps the Tea has been through, in order, recorded as `branch/step` inside a branch.
+    pub steps: Vec<String>,
+}
+
+impl<T> Envelope<T> {
+    ///
+    /// Wraps a batch of Tea collected by the current source, stamping each Tea with the source,
+    /// the time and a new batch id. Tea wrapped outside a brew has no source and batch 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `tea_batch` - Tea collected by the source
+    /// * `offset` - position of the batch in its source, if known
+    pub fn batch(tea_batch: Vec<T>, offset: Option<&str>) -> Vec<Envelope<T>> {
+        let (source, location, batch) = match context::current() {
+            Some(context) => {
+                let (source, location) = context.source();
+                (source, location, context.next_batch())
+            },
+            None => (String::new(), String::new(), 0),
+        };
+        let metadata = Metadata {
+            source,
+            location,
+            offset: offset.map(String::from),
+            ingested_at: SystemTime::now(),
+            batch,
+            steps: Vec::new(),
+        };
+        tea_batch
+            .into_iter()
+            .map(|tea| Envelope { tea, metadata: metadata.clone() })
+            .collect()
+    }
+
+    ///
+    /// Replaces the Tea, keeping its metadata, such as for a Steep transforming the Tea.
+    ///
+    /// # Arguments
+    ///
+    /// * `f` - transforms the Tea
+    pub fn map<U, F>(self, f: F) -> Envelope<U>
+        where F: FnOnce(T) -> U
+    {
+        Envelope { tea: f(self.tea), metadata: self.metadata }
+    }
+}
+
+///
+/// Records a step in the lineage of each Tea in a batch brewed by a Pot tracing lineage.
+pub(crate) struct Stamp<T>(pub(crate) fn(&mut Vec<T>, &str));
+
+///
+/// Adds a step to the lineage of each envelope in a batch.
+pub(crate) fn stamp_envelopes<T>(tea_batch: &mut Vec<Envelope<T>>, step: &str) {
+    let step = context::step_name(step);
+    for envelope in tea_batch {
+        envelope.metadata.steps.push(step.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Envelope;
+    use super::super::branch::Branch;
+    use super::super::brewery::{Brewery, make_tea};
+    use super::super::ingredient::{Fill, Steep, Pour, Argument};
+    use super::super::pot::Pot;
+    use std::any::Any;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq, Default, Clone)]
+    struct TestTea {
+        id: u32,
+    }
+
+    struct PouredArgs {
+        pub poured: Arc<Mutex<Vec<Envelope<TestTea>>>>,
+    }
+
+    impl Argument for PouredArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn enveloped_pot(poured: &Arc<Mutex<Vec<Envelope<TestTea>>>>) -> Pot<Envelope<TestTea>> {
+        Pot::new()
+            .add_source(Box::new(Fill {
+                name: String::from("ids"),
+                source: String::from("ids.csv"),
+                computation: Box::new(|_args, brewery, recipe| {
+                    for line in 0 .. 2 {
+                        let recipe = Arc::clone(&recipe);
+                        let tea_batch = Envelope::batch(vec![TestTea { id: line }], Some(&line.to_string()));
+                        brewery.take_order(|| {
+                            make_tea(tea_batch, recipe);
+                        });
+                    }
+                }),
+                params: None,
+            }))
+            .add_ingredient(Box::new(Steep {
+                name: String::from("double"),
+                computation: Box::new(|tea_batch: Vec<Envelope<TestTea>>, _args| {
+                    tea_batch.into_iter().map(|envelope| envelope.map(|tea| TestTea { id: tea.id * 2 })).collect()
+                }),
+                params: None,
+            }))
+            .add_ingredient(Box::new(Branch::new("copy").branch("archive", vec![Box::new(Steep {
+                name: String::from("keep"),
+                computation: Box::new(|tea_batch, _args| tea_batch),
+                params: None,
+            })])))
+            .add_ingredient(Box::new(Pour {
+                name: String::from("pour"),
+                computation: Box::new(|tea_batch: Vec<Envelope<TestTea>>, args| {
+                    let args = args.as_ref().unwrap().as_any().downcast_ref::<PouredArgs>().unwrap();
+                    args.poured.lock().unwrap().extend(tea_batch.iter().cloned());
+                    tea_batch
+                }),
+                params: Some(Box::new(PouredArgs { poured: Arc::clone(poured) })),
+            }))
+    }
+
+    fn sorted(poured: &Arc<Mutex<Vec<Envelope<TestTea>>>>) -> Vec<Envelope<TestTea>> {
+        let mut poured = poured.lock().unwrap().clone();
+        poured.sort_by_key(|envelope| envelope.metadata.batch);
+        poured
+    }
+
+    #[test]
+    fn envelopes_carry_source_metadata() {
+        let poured = Arc::new(Mutex::new(Vec::new()));
+        enveloped_pot(&poured).brew(&Brewery::new(2)).unwrap();
+
+        let poured = sorted(&poured);
+        assert_eq!(poured.len(), 2);
+        for (line, envelope) in poured.iter().enumerate() {
+            assert_eq!(envelope.tea, TestTea { id: line as u32 * 2 });
+            assert_eq!(envelope.metadata.source, "ids");
+            assert_eq!(envelope.metadata.location, "ids.csv");
+            assert_eq!(envelope.metadata.offset, Some(line.to_string()));
+            assert_eq!(envelope.metadata.batch, line as u64 + 1);
+            assert!(envelope.metadata.steps.is_empty());
+        }
+    }
+
+    #[test]
+    fn traced_envelopes_record_steps() {
+        let poured = Arc::new(Mutex::new(Vec::new()));
+        enveloped_pot(&poured).trace_lineage().brew(&Brewery::new(2)).unwrap();
+
+        for envelope in sorted(&poured) {
+            assert_eq!(envelope.metadata.steps, vec![String::from("double"), String::from("copy")]);
+        }
+    }
+
+    #[test]
+    fn envelopes_outside_a_brew_have_no_source() {
+        let envelopes = Envelope::batch(vec![TestTea { id: 1 }, TestTea { id: 2 }], None);
+        assert_eq!(envelopes.len(), 2);
+        assert_eq!(envelopes[0].metadata.source, "");
+        assert_eq!(envelopes[0].metadata.batch, 0);
+        assert_eq!(envelopes[0].metadata, envelopes[1].metadata);
+    }
+}
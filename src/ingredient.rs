@@ -1,4 +1,5 @@
-use crate::brewery::Brewery;
+use crate::brewery::{Brewery, brew_from, flush_steps};
+use crate::context;
 
 use std::any::Any;
 use std::sync::{Arc, RwLock};
@@ -14,6 +15,50 @@ pub trait Ingredient<T: Send> {
     /// * `tea_batch` - current tea batch to be processed
     fn exec(&self, tea_batch: Vec<T>) -> Vec<T>;
 
+    ///
+    /// Run computation on batch of Tea, returning the batches to send on to the next step.
+    /// Ingredients whose output can grow well past the size of their input, such as FlatMap,
+    /// split it into several batches here.
+    ///
+    /// # Arguements
+    ///
+    /// * `tea_batch` - current tea batch to be processed
+    fn exec_batches(&self, tea_batch: Vec<T>) -> Vec<Vec<T>> {
+        vec![self.exec(tea_batch)]
+    }
+
+    ///
+    /// Returns Tea the Ingredient held back while brewing, such as aggregated totals, once every
+    /// batch has been brewed. The returned batches are brewed through the rest of the recipe.
+    fn flush(&self) -> Vec<Vec<T>> {
+        Vec::new()
+    }
+
+    ///
+    /// Sends the batches returned by `flush` to `send` one at a time. Ingredients holding back
+    /// more Tea than fits in memory, such as Sort, override this to stream their batches.
+    ///
+    /// # Arguements
+    ///
+    /// * `send` - brews each batch through the rest of the recipe
+    fn flush_into(&self, send: &mut dyn FnMut(Vec<T>)) {
+        for tea_batch in self.flush() {
+            send(tea_batch);
+        }
+    }
+
+    ///
+    /// Called once every batch has been brewed and flushed, with whether the brew succeeded, so
+    /// sinks can commit their output or abort it. Returns the reason if the output could not be
+    /// committed.
+    ///
+    /// # Arguements
+    ///
+    /// * `success` - true if the brew succeeded
+    fn finish(&self, _success: bool) -> Result<(), String> {
+        Ok(())
+    }
+
     ///
     /// Print out current step information.
     fn print(&self); 
@@ -25,20 +70,85 @@ pub trait Ingredient<T: Send> {
     ///
     /// Returns name given to Ingredient.
     fn get_name(&self) -> &str;
+
+    ///
+    /// Returns the kind of Ingredient, such as Steep or Pour, shown in diagrams of the Pot.
+    fn kind(&self) -> &str {
+        "Ingredient"
+    }
+
+    ///
+    /// Returns the named sub-recipes the Ingredient brews Tea through, such as the branches of a
+    /// Branch, so diagrams of the Pot can show them.
+    fn sub_recipes(&self) -> Vec<(&str, &Steps<T>)> {
+        Vec::new()
+    }
+
+    ///
+    /// Checks the Ingredient can be brewed, returning the reason if it cannot. Called by
+    /// `Pot::validate` before the Pot is brewed.
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+///
+/// Checks the params given to a step or source, if any.
+pub(crate) fn validate_params<A: Argument + ?Sized>(params: &Option<Box<A>>) -> Result<(), String> {
+    match params {
+        Some(params) => params.validate().map_err(|reason| format!("invalid params: {}", reason)),
+        None => Ok(()),
+    }
 }
 
+///
+/// Ordered steps that make up the recipe run on each batch of Tea.
+pub type Steps<T> = Vec<Box<dyn Ingredient<T> + Send + Sync>>;
+
+///
+/// Recipe shared between the Pot and the Brewers processing its Tea.
+pub type SharedRecipe<T> = Arc<RwLock<Steps<T>>>;
+
 ///
 /// Trait given to Box elements that add params to Ingredients.
 pub trait Argument {
     fn as_any(&self) -> &dyn Any;
+
+    ///
+    /// Checks the params can be used, returning the reason if they cannot. Called when the
+    /// Ingredient or source given the params is validated.
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
 }
 
+///
+/// Optional params initialized to an Ingredient.
+pub type Params = Option<Box<dyn Argument + Send>>;
+
+///
+/// Optional params initialized to an Ingredient whose params are shared by every Brewer running
+/// it, so they must be safe to share between threads.
+pub type SharedParams = Option<Box<dyn Argument + Send + Sync>>;
+
+///
+/// Computation run by Steep, Skim, and Pour on a batch of Tea.
+pub type Computation<T> = fn(Vec<T>, &SharedParams) -> Vec<T>;
+
+///
+/// Test run by Filter on each Tea, keeping the Tea when it returns true.
+pub type Predicate<T> = fn(&T, &SharedParams) -> bool;
+
+///
+/// Expansion run by FlatMap on each Tea, turning it into any number of Tea.
+pub type Expansion<T> = fn(T, &SharedParams) -> Vec<T>;
+
 ///
 /// Ingredient used to import or create Tea used in the Pot.
 pub struct Fill<T: Send> {
     pub source: String,
     pub name: String,
-    pub computation: Box<fn(&Option<Box<dyn Argument + Send>>, &Brewery, Arc<RwLock<Vec<Box<dyn Ingredient<T> + Send + Sync>>>>)>,
+    pub computation: Box<fn(&Params, &Brewery, SharedRecipe<T>)>,
     pub params: Option<Box<dyn Argument + Send>>,
 }
 
@@ -50,30 +160,52 @@ pub struct Transfuse;
 /// Ingredient used to transform Tea in the Pot.
 pub struct Steep<T: Send> {
     pub name: String,
-    pub computation: Box<fn(Vec<T>, &Option<Box<dyn Argument + Send>>) -> Vec<T>>, 
-    pub params: Option<Box<dyn Argument + Send>>,
+    pub computation: Box<Computation<T>>, 
+    pub params: Option<Box<dyn Argument + Send + Sync>>,
 }
 
 ///
 /// Ingredient used to remove fields on Tea in the Pot. *Not currently implemented*
 pub struct Skim<T: Send> {
     pub name: String,
-    pub computation: Box<fn(Vec<T>, &Option<Box<dyn Argument + Send>>) -> Vec<T>>, 
-    pub params: Option<Box<dyn Argument + Send>>,
+    pub computation: Box<Computation<T>>, 
+    pub params: Option<Box<dyn Argument + Send + Sync>>,
 }
 
 ///
 /// Ingredient used to send Tea to somewhere else.
 pub struct Pour<T: Send> {
     pub name: String,
-    pub computation: Box<fn(Vec<T>, &Option<Box<dyn Argument + Send>>) -> Vec<T>>, 
-    pub params: Option<Box<dyn Argument + Send>>,
+    pub computation: Box<Computation<T>>, 
+    pub params: Option<Box<dyn Argument + Send + Sync>>,
+}
+
+///
+/// Ingredient used to drop Tea that fails a predicate. Rejected Tea can be brewed through side
+/// steps, such as a side sink, and the kept and rejected counts are recorded in the brew's step
+/// statistics.
+pub struct Filter<T: Send> {
+    pub name: String,
+    pub predicate: Box<Predicate<T>>,
+    pub params: Option<Box<dyn Argument + Send + Sync>>,
+    /// Steps the rejected Tea is brewed through, recorded as `filter/step`, or none to drop it.
+    pub rejected: Steps<T>,
+}
+
+///
+/// Ingredient used to expand each Tea into many, such as an order into its line items. The
+/// expanded Tea is split into batches of at most `batch_size` before the next step.
+pub struct FlatMap<T: Send> {
+    pub name: String,
+    pub computation: Box<Expansion<T>>,
+    pub params: Option<Box<dyn Argument + Send + Sync>>,
+    pub batch_size: usize,
 }
 
 impl<T: Send> Fill<T> {
     ///
     /// Return params, if any, initialized to this step.
-    pub fn get_params(&self) -> &Option<Box<dyn Argument + Send>> {
+    pub fn get_params(&self) -> &Params {
         &self.params
     }
 }
@@ -81,7 +213,7 @@ impl<T: Send> Fill<T> {
 impl<T: Send> Steep<T> {
     ///
     /// Return params, if any, initialized to this step.
-    pub fn get_params(&self) -> &Option<Box<dyn Argument + Send>> {
+    pub fn get_params(&self) -> &SharedParams {
         &self.params
     }
 }
@@ -89,7 +221,7 @@ impl<T: Send> Steep<T> {
 impl<T: Send> Skim<T> {
     ///
     /// Return params, if any, initialized to this step.
-    pub fn get_params(&self) -> &Option<Box<dyn Argument + Send>> {
+    pub fn get_params(&self) -> &SharedParams {
         &self.params
     }
 }
@@ -97,17 +229,26 @@ impl<T: Send> Skim<T> {
 impl<T: Send> Pour<T> {
     ///
     /// Return params, if any, initialized to this step.
-    pub fn get_params(&self) -> &Option<Box<dyn Argument + Send>> {
+    pub fn get_params(&self) -> &SharedParams {
         &self.params
     }
 }
 
-unsafe impl<T: Send> Send for Steep<T> {}
-unsafe impl<T: Send>  Sync for Steep<T> {}
-unsafe impl<T: Send>  Send for Skim<T>{}
-unsafe impl<T: Send>  Sync for Skim<T> {}
-unsafe impl<T: Send>  Send for Pour<T> {}
-unsafe impl<T: Send>  Sync for Pour<T> {}
+impl<T: Send> Filter<T> {
+    ///
+    /// Return params, if any, initialized to this step.
+    pub fn get_params(&self) -> &SharedParams {
+        &self.params
+    }
+}
+
+impl<T: Send> FlatMap<T> {
+    ///
+    /// Return params, if any, initialized to this step.
+    pub fn get_params(&self) -> &SharedParams {
+        &self.params
+    }
+}
 
 impl<T: Send + 'static> Ingredient<T> for Steep<T> {
     fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
@@ -116,18 +257,27 @@ impl<T: Send + 'static> Ingredient<T> for Steep<T> {
     fn get_name(&self) -> &str {
         &self.name[..]
     }
+    fn kind(&self) -> &str {
+        "Steep"
+    }
     fn print(&self) {
         println!("Current Step: {}", self.get_name());
     }
     fn as_any(&self) -> &dyn Any {
         self
     }
+    fn validate(&self) -> Result<(), String> {
+        validate_params(self.get_params())
+    }
 }
 
 impl<T: Send + 'static>  Ingredient<T> for Pour<T> {
     fn get_name(&self) -> &str {
         &self.name[..]
     }
+    fn kind(&self) -> &str {
+        "Pour"
+    }
     fn print(&self) {
         println!("Current Step: {}", self.get_name());
     }
@@ -137,15 +287,21 @@ impl<T: Send + 'static>  Ingredient<T> for Pour<T> {
     fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
         (self.computation)(tea_batch, self.get_params())
     }
+    fn validate(&self) -> Result<(), String> {
+        validate_params(self.get_params())
+    }
 }
 
-// TODO: Implement Ingredient for Fill (add step plus logic to `brewery::make_tea` function)
+// TODO: Implement Ingredient for Fill
 // Need to consider if this still makes sense as an Ingredient in the recipe vs just a source...
 
 impl<T: Send + 'static>  Ingredient<T> for Skim<T>  {
     fn get_name(&self) -> &str {
         &self.name[..]
     }
+    fn kind(&self) -> &str {
+        "Skim"
+    }
     fn print(&self) {
         println!("Current Step: {}", self.get_name());
     }
@@ -155,16 +311,114 @@ impl<T: Send + 'static>  Ingredient<T> for Skim<T>  {
     fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
         (self.computation)(tea_batch, self.get_params())
     }
+    fn validate(&self) -> Result<(), String> {
+        validate_params(self.get_params())
+    }
+}
+
+impl<T: Send + 'static> Ingredient<T> for Filter<T> {
+    fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
+        let (kept, rejected): (Vec<T>, Vec<T>) = tea_batch
+            .into_iter()
+            .partition(|tea| (self.predicate)(tea, self.get_params()));
+        if !rejected.is_empty() {
+            if let Some(context) = context::current() {
+                context.records_rejected(self.get_name(), rejected.len());
+            }
+            if !self.rejected.is_empty() {
+                let context = context::current();
+                context::scoped(&self.name, || brew_from(0, vec![rejected], &self.rejected, &context));
+            }
+        }
+        kept
+    }
+    fn flush_into(&self, _send: &mut dyn FnMut(Vec<T>)) {
+        // Tea held back by the side steps is brewed through the rest of them, not the recipe.
+        let context = context::current();
+        context::scoped(&self.name, || flush_steps(&self.rejected, &context));
+    }
+    fn sub_recipes(&self) -> Vec<(&str, &Steps<T>)> {
+        if self.rejected.is_empty() {
+            Vec::new()
+        } else {
+            vec![(&self.name[..], &self.rejected)]
+        }
+    }
+    fn get_name(&self) -> &str {
+        &self.name[..]
+    }
+    fn kind(&self) -> &str {
+        "Filter"
+    }
+    fn print(&self) {
+        println!("Current Step: {}", self.get_name());
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn validate(&self) -> Result<(), String> {
+        validate_params(self.get_params())?;
+        for step in &self.rejected {
+            step.validate().map_err(|error| format!("{}/{}: {}", self.name, step.get_name(), error))?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Send + 'static> Ingredient<T> for FlatMap<T> {
+    fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
+        tea_batch
+            .into_iter()
+            .flat_map(|tea| (self.computation)(tea, self.get_params()))
+            .collect()
+    }
+    fn exec_batches(&self, tea_batch: Vec<T>) -> Vec<Vec<T>> {
+        let mut batches = Vec::new();
+        let mut batch = Vec::with_capacity(self.batch_size);
+        for tea in tea_batch {
+            for tea in (self.computation)(tea, self.get_params()) {
+                batch.push(tea);
+                if batch.len() == self.batch_size {
+                    batches.push(batch);
+                    batch = Vec::with_capacity(self.batch_size);
+                }
+            }
+        }
+        if !batch.is_empty() {
+            batches.push(batch);
+        }
+        batches
+    }
+    fn get_name(&self) -> &str {
+        &self.name[..]
+    }
+    fn kind(&self) -> &str {
+        "FlatMap"
+    }
+    fn print(&self) {
+        println!("Current Step: {}", self.get_name());
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn validate(&self) -> Result<(), String> {
+        validate_params(self.get_params())?;
+        if self.batch_size == 0 {
+            return Err(String::from("batch_size must be greater than 0"));
+        }
+        Ok(())
+    }
 }
 
-// TODO: Implement Ingredient for Transfuse (add step plus logic to `brewery::make_tea` function)
+// TODO: Implement Ingredient for Transfuse
 
 #[cfg(test)]
+#[allow(clippy::manual_map, clippy::map_identity, clippy::manual_inspect)]
 mod tests {
-    use super::super::ingredient::{Fill, Steep, Skim, Pour, Argument, Ingredient};
+    use super::super::ingredient::{Fill, Steep, Skim, Pour, Filter, FlatMap, Argument, Ingredient};
     use super::super::source::Source;
     use std::any::Any;
-    use std::sync::{Arc, RwLock};
+    use std::sync::{Arc, Mutex, RwLock};
 
     #[derive(Debug, PartialEq, Default, Clone)]
     struct TestTea {
@@ -364,4 +618,106 @@ mod tests {
         assert_eq!(skim.get_name(), "test_skim");
         assert_eq!(new_tea[0].x, None);
     }
+
+    struct SideSinkArgs {
+        pub rejected: Arc<Mutex<Vec<TestTea>>>,
+    }
+
+    impl Argument for SideSinkArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn create_filter_with_params() {
+        let filter = Filter {
+            name: String::from("test_filter"),
+            predicate: Box::new(|tea: &TestTea, args| {
+                let min = args.as_ref().unwrap().as_any().downcast_ref::<TestArgs>().unwrap().val;
+                tea.x.is_some_and(|x| x >= min)
+            }),
+            params: Some(Box::new(TestArgs { val: 5 })),
+            rejected: Vec::new(),
+        };
+        let orig_tea = vec![TestTea { x: Some(3) }, TestTea { x: Some(7) }, TestTea { x: None }];
+        let new_tea = filter.exec(orig_tea);
+        assert_eq!(filter.get_name(), "test_filter");
+        assert_eq!(new_tea, vec![TestTea { x: Some(7) }]);
+    }
+
+    #[test]
+    fn filter_sends_rejected_tea_to_side_sink() {
+        let rejected = Arc::new(Mutex::new(Vec::new()));
+        let filter = Filter {
+            name: String::from("test_filter"),
+            predicate: Box::new(|tea: &TestTea, _args| tea.x.is_some()),
+            params: None,
+            rejected: vec![Box::new(Pour {
+                name: String::from("rejected_pour"),
+                computation: Box::new(|tea: Vec<TestTea>, args| {
+                    let args = args.as_ref().unwrap().as_any().downcast_ref::<SideSinkArgs>().unwrap();
+                    args.rejected.lock().unwrap().extend(tea);
+                    Vec::new()
+                }),
+                params: Some(Box::new(SideSinkArgs { rejected: Arc::clone(&rejected) })),
+            })],
+        };
+        let new_tea = filter.exec(vec![TestTea { x: Some(1) }, TestTea { x: None }]);
+        assert_eq!(new_tea, vec![TestTea { x: Some(1) }]);
+        assert_eq!(*rejected.lock().unwrap(), vec![TestTea { x: None }]);
+    }
+
+    #[test]
+    fn filter_validates_side_sink() {
+        let filter = Filter {
+            name: String::from("test_filter"),
+            predicate: Box::new(|tea: &TestTea, _args| tea.x.is_some()),
+            params: None,
+            rejected: vec![Box::new(FlatMap {
+                name: String::from("unbatched"),
+                computation: Box::new(|tea: TestTea, _args| vec![tea]),
+                params: None,
+                batch_size: 0,
+            })],
+        };
+        assert_eq!(filter.validate(), Err(String::from("test_filter/unbatched: batch_size must be greater than 0")));
+    }
+
+    #[test]
+    fn flat_map_splits_output_into_batches() {
+        let flat_map = FlatMap {
+            name: String::from("test_flat_map"),
+            computation: Box::new(|tea: TestTea, args| {
+                let copies = args.as_ref().unwrap().as_any().downcast_ref::<TestArgs>().unwrap().val;
+                vec![tea; copies as usize]
+            }),
+            params: Some(Box::new(TestArgs { val: 5 })),
+            batch_size: 4,
+        };
+        let orig_tea = vec![TestTea { x: Some(1) }, TestTea { x: Some(2) }];
+        let batches = flat_map.exec_batches(orig_tea.clone());
+        assert_eq!(batches.iter().map(|batch| batch.len()).collect::<Vec<_>>(), vec![4, 4, 2]);
+        assert_eq!(batches[1], vec![TestTea { x: Some(1) }, TestTea { x: Some(2) }, TestTea { x: Some(2) }, TestTea { x: Some(2) }]);
+        assert_eq!(flat_map.exec(orig_tea).len(), 10);
+    }
+
+    #[test]
+    fn flat_map_needs_batch_size() {
+        let flat_map = FlatMap {
+            name: String::from("test_flat_map"),
+            computation: Box::new(|tea: TestTea, _args| vec![tea]),
+            params: None,
+            batch_size: 0,
+        };
+        assert!(flat_map.validate().is_err());
+    }
+
+    #[test]
+    fn filter_and_flat_map_are_shared_safely() {
+        // Checked by the compiler from their fields rather than asserted with an unsafe impl.
+        fn shared<S: Send + Sync>() {}
+        shared::<Filter<TestTea>>();
+        shared::<FlatMap<TestTea>>();
+    }
 }
@@ -19,6 +19,11 @@ rettle has the following Types to be used in any project to "Brew" data:
 - **Skim:** remove a field (or Tea object) *Not Implemented Yet*
 - **Pour:** data output destination
 
+## Upgrading from 2.x
+`Pot::brew` used to return `()` as soon as the sources were collected, leaving the Brewers to finish in the background. It now waits until every Order is brewed and returns `Result<BrewReport, Vec<String>>`: the `BrewReport` of the brew, or every problem `Pot::validate` found if the Pot could not be brewed. Code that called `brew` and then waited or slept before reading its output can drop the wait, and should handle the `Err` case, for example with `new_pot.brew(&brewery).unwrap();`. The return value must be used, so a bare `new_pot.brew(&brewery);` now warns.
+
+The params of `Steep`, `Skim` and `Pour` are now `SharedParams`, like those of `Filter` and `FlatMap`, because every Brewer running the step reads them at once. Their `Argument`s must be `Sync`, so state they change should sit behind a `Mutex` or an atomic instead of a `Cell` or `RefCell`.
+
 ## Using rettle
 In your custom project you first need to define the custom "Tea" struct that will be created by the `Fill` Ingredient.
 
@@ -121,7 +126,7 @@ fn main() {
     }));
     
     // Process Tea
-    new_pot.brew(&brewery);
+    new_pot.brew(&brewery).unwrap();
     
     // Display information
     brewery.get_brewer_info();
@@ -130,20 +135,328 @@ fn main() {
 }
 ```
 
+### Filtering Tea
+A `Filter` drops Tea failing its predicate, and can brew the rejected Tea through side steps, such as a `Pour`. The side steps are flushed, finished and drawn like a branch, with their statistics recorded as `filter/step`. The `BrewReport` counts the Tea in, out and rejected at every step, so a sudden jump in rejected Tea shows up in the report.
+
+```ignore
+.add_ingredient(Box::new(Filter {
+    name: String::from("has_str_val"),
+    predicate: Box::new(|tea: &TextTea, _args| !tea.str_val.is_empty()),
+    params: None,
+    rejected: Vec::new(),
+}))
+```
+
+### Expanding Tea
+A `FlatMap` turns each Tea into any number of Tea, such as an order into its line items. The expanded Tea is split into batches of at most `batch_size` before the next step, so one large expansion does not leave the later steps with an oversized batch.
+
+```ignore
+.add_ingredient(Box::new(FlatMap {
+    name: String::from("split_words"),
+    computation: Box::new(|tea: TextTea, _args| {
+        tea.str_val.split_whitespace()
+            .map(|word| TextTea { str_val: String::from(word), ..tea.clone() })
+            .collect()
+    }),
+    params: None,
+    batch_size: 200,
+}))
+```
+
+### Aggregating Tea
+An `Aggregate` groups Tea by key across every batch of a brew and reduces each group with built-in `Reducer`s (`Count`, `Sum`, `Min`, `Max`, `Mean` and `Collect`). Each Brewer keeps its own partial groups, which are merged once every batch has been brewed. The finish function then builds one Tea per group, and those Tea flow on to the following steps.
+
+```ignore
+.add_ingredient(Box::new(Aggregate::new("totals", |tea: &TextTea| tea.str_val.clone(), |key, reduced| {
+    let total = match reduced[0] { Reduced::Sum(total) => total as i32, _ => 0 };
+    TextTea { x: total, str_val: String::from(key), y: false }
+})
+    .reduce(Reducer::Sum(|tea: &TextTea| tea.x as f64))))
+```
+
+### Windowing Streams
+For continuous sources, a `Window` aggregates Tea over tumbling, sliding or session windows of event time, using the same `Reducer`s as `Aggregate`. The watermark trails the latest event time seen by `watermark_delay`, and a window closes once the watermark passes its end by more than `allowed_lateness`. Its result is then sent on to the following steps as new Tea straight away. Tea arriving after its window has closed is dropped and counted as rejected in the `BrewReport`.
+
+```ignore
+.add_ingredient(Box::new(Window::new(
+    "errors_per_minute",
+    WindowKind::Tumbling { size: Duration::from_secs(60) },
+    |tea: &LogTea| tea.timestamp,
+    |tea: &LogTea| tea.level.clone(),
+    |level, bounds, reduced| LogTea::rollup(level, bounds.start, &reduced),
+)
+    .reduce(Reducer::Count)
+    .watermark_delay(Duration::from_secs(5))
+    .allowed_lateness(Duration::from_secs(30))))
+```
+
+### Removing Duplicates
+A `Dedupe` drops Tea whose key has already been seen, such as records delivered again by a source, and counts them as rejected in the `BrewReport`. Keys are kept in memory by default. For huge streams, an `LruStore` remembers only the most recent keys and a `BloomStore` holds every key in a fixed amount of memory. A `FileStore` also writes the keys to disk once the brew is done, so dedupe carries on where it left off after a restart. Keys of a batch that fails further down the recipe are forgotten, so the batch is let through when the source delivers it again, and a duplicate arriving while that batch is still brewing waits to see whether it fails. Keys of the batches that were brewed are saved even if others failed, unless the Pot writes to a `Sink`, whose output is only kept once the whole brew succeeds. Any `KeyStore` can be shared safely by every Brewer running the step.
+
+```ignore
+.add_ingredient(Box::new(Dedupe::new("dedupe", |tea: &TextTea| tea.x.to_string())
+    .store(Box::new(FileStore::open("seen.keys").unwrap()))))
+```
+
+### Sorting Tea
+A `Sort` puts all the Tea of a brew in order by a key or comparator, then sends it on in batches once every Order is done. When more Tea is held than its `memory_budget`, a sorted run is spilled to a temporary file and the runs are merged at the end, so a brew can sort more Tea than fits in memory. Spilled Tea is written as JSON, so the Tea must derive `Serialize` and `Deserialize`. A run that cannot be written stays in memory and is reported in the `BrewReport` errors.
+
+```ignore
+.add_ingredient(Box::new(Sort::by_key("sort", |tea: &TextTea| tea.x)
+    .memory_budget(50_000)
+    .spill_dir("/var/tmp")))
+```
+
+### Sampling Tea
+For dry runs against production sized sources, a `Limit` brews only the first N Tea. Once the limit is reached the sources are told to stop, so `Fill` loops checking `brewery.is_cancelled()` stop reading, and any sources not yet started are left out, without the brew counting as cancelled or incomplete. The `BrewReport` sets `limit_reached` instead, and the Limit lets through the first N Tea again on the next brew. Since a Limit stops every source of the Pot, `Pot::validate` rejects one in a branch, a `Filter`'s side steps or a Graph, which only some of the Tea reaches. A `Sample` instead keeps a random fraction of the Tea, or an even reservoir sample of a fixed size passed on at the end of the brew. Samples are picked with a seed, so a dry run on one Brewer picks the same Tea every time.
+
+```ignore
+.add_ingredient(Box::new(Limit::new("first_1000", 1000)))
+.add_ingredient(Box::new(Sample::new("one_percent", SampleKind::Fraction(0.01)).seed(42)))
+```
+
+### Tapping Tea
+To see the Tea passing between two steps, add a `Tap` instead of a debugging `Pour`. A Tap writes the first Tea it sees in each brew, pretty-printed with `Debug` or as JSON, to stdout, a file or a captured `Vec`, and hands the batch on untouched. Tea that cannot be written is reported as a `BrewError::StepFailed` for the Tap, without holding the batch back. Taps are off unless their name is listed in the `RETTLE_TAP` environment variable (`RETTLE_TAP=after_steep,before_pour` or `RETTLE_TAP=*`), or `enabled` is set from your own config, so they can stay in the recipe.
+
+```ignore
+.add_ingredient(Box::new(Tap::debug("after_steep").limit(5)))
+```
+
+### Branching Recipes
+A `Branch` sends a copy of each batch down several named sub-recipes, such as an archive copy and a cleaned copy of the same Tea, and then passes the batch on unchanged to the rest of the recipe. Branches run within the same Order as the batch, and held back Tea in a branch, such as an `Aggregate`, is sent on at the end of the brew. Steps in a branch show up in the `BrewReport` as `branch/step`, and a branch that panics is reported as a `BrewError::BranchFailed` while the other branches keep brewing. The Tea must be `Clone`.
+
+```ignore
+.add_ingredient(Box::new(Branch::new("fan_out")
+    .branch("archive", vec![Box::new(archive_pour)])
+    .branch("cleaned", vec![Box::new(clean_steep), Box::new(warehouse_pour)])))
+```
+
+### Routing Tea
+A `Router` splits each batch with a classifier that names the route of each Tea, and brews each part through the steps of its route within the same Order, for example valid Tea to a warehouse `Pour` and invalid Tea to a quarantine `Pour`. Tea named for a route that does not exist goes down the default route, or is counted as rejected if there is none. Like branches, route steps show up in the `BrewReport` as `route/step`.
+
+```ignore
+.add_ingredient(Box::new(Router::new("validate", |tea: &TextTea| {
+        if tea.y > 0 { String::from("valid") } else { String::from("invalid") }
+    })
+    .route("valid", vec![Box::new(warehouse_pour)])
+    .route("invalid", vec![Box::new(quarantine_pour)])
+    .default_route(vec![Box::new(unknown_pour)])))
+```
+
+### Brewing a Graph
+When a flat recipe is not enough, `Pot::add_graph` adds a `Graph` of named steps joined by edges, along with the sources feeding it. Each source feeds one step, a step with several edges out sends a copy of each batch down each (so the Tea must be `Clone`), and a step with several edges in merges them. The Graph is checked for cycles, unknown steps and steps with no input by `Pot::validate`, and steps run in topological order, including held back Tea flushed at the end of the brew. Sources feeding a Graph show up in the `BrewReport` as `source->step`. A Pot can have a recipe and Graphs side by side; when brewed in stages, only the recipe is pipelined, and each batch of a Graph is brewed by its source as an Order of its own.
+
+```ignore
+let graph = Graph::new()
+    .source(Box::new(orders_fill), "clean")
+    .source(Box::new(refunds_fill), "negate")
+    .node(Box::new(clean_steep))
+    .node(Box::new(negate_steep))
+    .node(Box::new(archive_pour))
+    .node(Box::new(warehouse_pour))
+    .edge("clean", "archive")
+    .edge("clean", "warehouse")
+    .edge("negate", "warehouse");
+let new_pot = Pot::new().add_graph(graph);
+```
+
+### Drawing a Pot
+`Pot::to_dot` and `Pot::to_mermaid` draw the Pot's sources, steps and the flow of Tea between them, in Graphviz DOT or as a Mermaid flowchart, for runbooks and reviews of recipe changes. Each step is labelled with its name and kind, from `Ingredient::kind`, and the sub-recipes of branches and routes are drawn with edges labelled by name, along with any Graphs.
+
+```ignore
+std::fs::write("pot.dot", new_pot.to_dot()).unwrap();
+println!("{}", new_pot.to_mermaid());
+```
+
+### Sharing Recipes
+A `Recipe` is a list of steps built once and added to many Pots, such as cleaning steps shared across a team or published in a crate of recipe fragments. `Pot::extend_recipe` adds its steps to the end of a Pot's recipe, and `Recipe::add_recipe` (or a `SubRecipe` step) nests one Recipe in another, recording its steps in the `BrewReport` as `recipe/step`. The steps are shared rather than copied, so steps holding Tea back across a brew, such as an `Aggregate`, belong in a single Pot. `Pot::merge` adds another Pot's sources and steps to a Pot, such as a Pot of sources built apart from its steps. The merged Pot keeps either Pot's checkpoint and lineage tracing, and Pots checkpointed to different pipelines cannot be merged.
+
+```ignore
+let cleaning = Recipe::new("cleaning")
+    .add_ingredient(Box::new(trim))
+    .add_ingredient(Box::new(lowercase));
+let new_pot = Pot::new()
+    .add_source(Box::new(fill))
+    .extend_recipe(&cleaning)
+    .add_ingredient(Box::new(pour));
+```
+
+### Committing Output
+A Pour writing straight to its output leaves half of it behind when a brew fails or the process crashes. A `SinkPour` writes Tea to a `Sink` in phases instead. The output is begun before the first batch and each batch is written to it. It is only committed once every batch has been brewed and flushed and the `BrewReport` is successful. A cancelled brew, a halted or failed step, or a failed write aborts it instead. `FileSink` writes each Tea as a line to a temporary file next to its path, and renames it over the path on commit, so consumers only ever see the output of a whole brew. A `SinkPour` counts as the recipe's Pour and passes its Tea on unchanged. Custom sinks, such as a database transaction, implement `Sink` with `begin`, `write`, `commit` and `abort`.
+
+```ignore
+let new_pot = Pot::new()
+    .add_source(Box::new(fill))
+    .add_ingredient(Box::new(steep))
+    .add_ingredient(Box::new(SinkPour::new("orders_out", Box::new(FileSink::json_lines("orders.jsonl")))));
+let report = new_pot.brew(&brewery).unwrap();
+if !report.is_successful() {
+    println!("orders.jsonl was left as it was");
+}
+```
+
+### Tracing Tea
+Brewing a `Pot<Envelope<T>>` wraps each Tea with `Metadata` on where it came from. This includes the source's name and location (`Fill::name` and `Fill::source`), its offset in the source, when it was collected, and the id of its batch. Sources wrap the Tea they collect with `Envelope::batch`. Steeps and Pours see the metadata alongside the Tea, so sinks can write provenance columns. `Pot::trace_lineage` also records every step each Tea is brewed through, so bad output can be traced back to its input.
+
+```ignore
+let new_pot = Pot::<Envelope<Order>>::new()
+    .trace_lineage()
+    .add_source(Box::new(Fill {
+        name: String::from("orders_csv"),
+        source: String::from("orders.csv"),
+        computation: Box::new(|_args, brewery, recipe| {
+            for (line, tea_batch) in read_batches("orders.csv", 0) {
+                let recipe = Arc::clone(&recipe);
+                let tea_batch = Envelope::batch(tea_batch, Some(&line.to_string()));
+                brewery.take_order(move || {
+                    make_tea(tea_batch, recipe);
+                });
+            }
+        }),
+        params: None,
+    }))
+    .add_ingredient(Box::new(Pour {
+        name: String::from("pour_orders"),
+        computation: Box::new(|tea_batch: Vec<Envelope<Order>>, _args| {
+            for envelope in &tea_batch {
+                println!("{:?} from {} via {:?}", envelope.tea, envelope.metadata.location, envelope.metadata.steps);
+            }
+            tea_batch
+        }),
+        params: None,
+    }));
+```
+
+### Acknowledging Batches
+Sources such as message queues should only commit a message once its Tea has been fully brewed. `Brewery::take_order_acked` sends a batch like `take_order` and returns an `AckHandle`. The handle completes with `Ack::Acked` once every step has brewed the batch. It completes with `Ack::Nacked` and a reason if the batch was dropped by a cancel, stopped part way, halted past a deadline, or failed in a branch. The source can then commit the message or redeliver it, giving at-least-once delivery.
+
+```ignore
+while let Some(message) = queue.receive() {
+    let recipe = Arc::clone(&recipe);
+    let tea_batch = vec![message.tea()];
+    let ack = brewery.take_order_acked(move || {
+        make_tea(tea_batch, recipe);
+    });
+    match ack.wait() {
+        Ack::Acked => queue.commit(message),
+        Ack::Nacked(_reason) => queue.redeliver(message),
+    }
+}
+```
+
+### Resuming a Brew
+A long brew that crashes part way through can pick up where it left off instead of starting over. `Pot::checkpoint` gives the Pot a pipeline id and a directory for its state file. Sources take each Order with `Brewery::take_order_at` and the position they would resume from after that batch, such as a file offset, line number or cursor token. Once a batch and every batch before it have cleared the recipe, the position is saved and synced to disk. A checkpoint that cannot be saved is reported as a `BrewError::CheckpointFailed`. When the Pot is brewed again with the same pipeline id, `Brewery::resume_position` returns the saved position, and the source skips ahead to it. Batches that were still brewing are brewed again, so Pours should tolerate seeing some Tea twice. The state file is removed once a brew finishes without being cancelled. A `SinkPour` only commits its output once the whole brew succeeds, so in a Pot writing to a Sink positions are not saved during the brew, and a stopped brew starts again from the last saved position instead of losing the output before it.
+
+```ignore
+let new_pot = Pot::new()
+    .checkpoint("nightly_orders", "/var/lib/rettle")
+    .add_source(Box::new(Fill {
+        name: String::from("orders_csv"),
+        source: String::from("orders.csv"),
+        computation: Box::new(|_args, brewery, recipe| {
+            let start: usize = brewery.resume_position().map_or(0, |line| line.parse().unwrap());
+            for (line, tea_batch) in read_batches("orders.csv", start) {
+                let recipe = Arc::clone(&recipe);
+                brewery.take_order_at(&line.to_string(), move || {
+                    make_tea(tea_batch, recipe);
+                });
+            }
+        }),
+        params: None,
+    }));
+```
+
+### Validating a Pot
+`Pot::validate` checks a Pot before it is brewed and returns every problem it finds at once: a Pot without sources or steps, sources or steps added twice, a recipe or Graph without a `Pour`, and sources, steps or params that fail their own `validate`. Custom `Argument`s and `Source`s can override `validate` to reject a bad configuration. `Pot::brew` and the other brew methods validate the Pot first and return the problems as an `Err` instead of brewing.
+
+```ignore
+if let Err(problems) = new_pot.validate() {
+    for problem in problems {
+        println!("Error: {}", problem);
+    }
+}
+```
+
+### Cancelling a Brew
+`Pot::brew` waits for the `Brewery` to finish the tea and returns a `BrewReport`. To stop a long running brew early, pass a `CancelToken` to `Pot::brew_with_token` and cancel it from another thread (or from a step). A `Graceful` cancel lets batches already being brewed finish, while an `Immediate` cancel also stops them between steps. Either way queued Orders are dropped, and `Fill` sources should check `brewery.is_cancelled()` between batches so they stop collecting tea. The report lists what was left unprocessed.
+
+With the `signals` feature enabled, `CancelToken::cancel_on_signal()` cancels the token gracefully on SIGINT/SIGTERM, and immediately on a second signal.
+
+```ignore
+let token = CancelToken::new();
+token.cancel_on_signal().unwrap();
+let report = new_pot.brew_with_token(&brewery, &token).unwrap();
+report.print();
+```
+
+### Sharing a Brewery
+Several Pots can brew on the same `Brewery` at once. Every Order carries a `Ticket` with the priority and tenant of the Pot that sent it. Orders with a higher priority are brewed first, and tenants of the same priority take turns, so a Pot flooding the Brewery with a large backfill cannot starve a small urgent job. Each Pot is its own tenant unless one is set, and `Brewery::set_tenant_weight` gives a tenant a bigger share of the Brewers.
+
+```ignore
+let brewery = Brewery::new(4);
+brewery.set_tenant_weight("reports", 2);
+let backfill = Pot::new().tenant("backfill");
+let urgent = Pot::new().tenant("reports").priority(9);
+```
+
+### Brewing in Stages
+By default every batch runs through the whole recipe on one Brewer. `Pot::brew_pipelined` instead turns each Ingredient into a stage with its own workers, linked by bounded queues. Slow sinks can be given more workers than CPU heavy transforms, and a backed up stage blocks the stages before it, and eventually the sources, rather than letting batches pile up in memory.
+
+```ignore
+let pipeline = Pipeline::new()
+    .stage_workers("pour1", 4)
+    .queue_capacity(16);
+let report = new_pot.brew_pipelined(&brewery, &pipeline).unwrap();
+```
+
 ### Ingredient Crates
-The community can add Ingredient crates that can be used along with this crate to simplify adding ingredients for common integrations or transformations. Some sample crates include:  
+The community can add Ingredient crates that can be used along with this crate to simplify adding ingredients for common integrations or transformations. Any type implementing the `Ingredient` trait can be added to a recipe, and `Ingredient::validate` lets it reject a configuration it cannot brew before the Pot is brewed. Some sample crates include:  
 - [cstea](https://crates.io/crates/cstea): Fill/Pour integrations for csv files
 - [elastictea](https://crates.io/crates/elastictea): Fill/Pour integrations for Elasticsearch
 - [logtea](https://crates.io/crates/logtea): Fill integration for log files
 */
 
+pub mod ack;
+pub mod aggregate;
+pub mod branch;
 pub mod brewery;
+pub mod cancel;
+mod checkpoint;
+mod context;
+pub mod dag;
+pub mod dedupe;
+pub mod envelope;
 pub mod ingredient;
+pub mod pipeline;
+pub mod recipe;
+pub mod sample;
+pub mod scheduler;
+pub mod sink;
+pub mod sort;
 pub mod source;
+pub mod tap;
+mod topology;
+pub mod window;
 pub mod pot;
 
 // Re-export main structs and Traits for API convenience.
 pub use self::pot::Pot;
-pub use self::brewery::{Brewery, make_tea};
-pub use self::ingredient::{Fill, Steep, Pour, Argument, Ingredient};
+pub use self::brewery::{Brewery, Autoscale, Watchdog, make_tea};
+pub use self::ingredient::{Fill, Steep, Pour, Filter, FlatMap, Argument, Ingredient};
 pub use self::source::Source;
+pub use self::cancel::{CancelToken, CancelMode};
+pub use self::ack::{Ack, AckHandle};
+pub use self::scheduler::Ticket;
+pub use self::pipeline::Pipeline;
+pub use self::aggregate::{Aggregate, Reducer, Reduced};
+pub use self::window::{Window, WindowKind, WindowBounds};
+pub use self::dedupe::{Dedupe, KeyStore, MemoryStore, LruStore, BloomStore, FileStore};
+pub use self::branch::{Branch, Router};
+pub use self::dag::Graph;
+pub use self::recipe::{Recipe, SubRecipe};
+pub use self::envelope::{Envelope, Metadata};
+pub use self::sort::Sort;
+pub use self::sink::{Sink, SinkPour, FileSink};
+pub use self::sample::{Limit, Sample, SampleKind};
+pub use self::tap::{Tap, TapOutput};
+pub use self::context::{BrewReport, BrewError, StepStats};
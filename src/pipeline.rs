@@ -0,0 +1,415 @@
+use crate::brewery::{Brewery, brew_step};
+use crate::context::{self, BrewContext, OrderGuard};
+use crate::ingredient::SharedRecipe;
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+
+///
+/// Batch of Tea moving between stages, holding its Order open until the last stage is done.
+type Batch<T> = (Vec<T>, Arc<OrderGuard>);
+
+///
+/// Settings for brewing a Pot as a pipeline with `Pot::brew_pipelined`.
+///
+/// Every Ingredient in the recipe becomes a stage with its own workers, and stages are linked by
+/// bounded queues. A slow stage fills the queue in front of it, which blocks the stages before it
+/// and eventually the sources, instead of letting batches pile up in memory.
+#[derive(Clone)]
+pub struct Pipeline {
+    /// Workers given to stages without their own count.
+    pub workers: usize,
+    /// Workers for individual stages, keyed by step name.
+    pub stage_workers: HashMap<String, usize>,
+    /// Batches each queue between stages holds before blocking the stage feeding it.
+    pub queue_capacity: usize,
+}
+
+impl Pipeline {
+    ///
+    /// Creates a Pipeline with one worker per stage and room for 8 batches between stages.
+    pub fn new() -> Pipeline {
+        Pipeline {
+            workers: 1,
+            stage_workers: HashMap::new(),
+            queue_capacity: 8,
+        }
+    }
+
+    ///
+    /// Sets the workers given to stages without their own count.
+    ///
+    /// # Arguments
+    ///
+    /// * `workers` - number of threads per stage
+    pub fn workers(mut self, workers: usize) -> Pipeline {
+        assert!(workers > 0);
+        self.workers = workers;
+        self
+    }
+
+    ///
+    /// Sets the workers for the named stage.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the step
+    /// * `workers` - number of threads brewing the step
+    pub fn stage_workers(mut self, name: &str, workers: usize) -> Pipeline {
+        assert!(workers > 0);
+        self.stage_workers.insert(String::from(name), workers);
+        self
+    }
+
+    ///
+    /// Sets how many batches each queue between stages holds.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - batches held before the upstream stage blocks
+    pub fn queue_capacity(mut self, capacity: usize) -> Pipeline {
+        self.queue_capacity = capacity;
+        self
+    }
+
+    ///
+    /// Returns the number of workers for the named stage.
+    pub fn get_workers(&self, name: &str) -> usize {
+        self.stage_workers.get(name).cloned().unwrap_or(self.workers)
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline::new()
+    }
+}
+
+///
+/// Entrance to the first stage, held by the brew's context while the sources fill.
+pub(crate) struct Feed<T: Send> {
+    sender: Option<Sender<Batch<T>>>,
+    recipe: SharedRecipe<T>,
+}
+
+impl<T: Send + 'static> Feed<T> {
+    ///
+    /// Returns true if batches sent for `recipe` belong in this pipeline, rather than a recipe of
+    /// their own such as a Graph's.
+    pub(crate) fn feeds(&self, recipe: &SharedRecipe<T>) -> bool {
+        Arc::ptr_eq(&self.recipe, recipe)
+    }
+
+    ///
+    /// Sends a batch into the pipeline, blocking while the first stage is backed up.
+    pub(crate) fn send(&self, tea_batch: Vec<T>, guard: Arc<OrderGuard>) {
+        match &self.sender {
+            // The stages hand the batch back if they have all stopped, dropping its Order.
+            Some(sender) => { let _ = sender.send((tea_batch, guard)); },
+            None => guard.brewed(),
+        }
+    }
+}
+
+///
+/// Runs `fill` with a pipeline built from `recipe` attached to `context`, returning once every
+/// batch has left the last stage.
+///
+/// # Arguments
+///
+/// * `pipeline` - workers and queue sizes of the stages
+/// * `brewery` - Brewery whose Watchdog watches the stage workers
+/// * `recipe` - steps run as stages, in order
+/// * `context` - brew the stages work for
+/// * `fill` - collects the sources, sending batches into the pipeline
+pub(crate) fn run<T, F>(pipeline: &Pipeline, brewery: &Brewery, recipe: SharedRecipe<T>, context: &Arc<BrewContext>, fill: F)
+    where T: Send + 'static, F: FnOnce()
+{
+    let names: Vec<String> = recipe.read().unwrap()
+        .iter()
+        .map(|step| String::from(step.get_name()))
+        .collect();
+
+    thread::scope(|scope| {
+        let mut next: Option<Sender<Batch<T>>> = None;
+        for (index, name) in names.iter().enumerate().rev() {
+            let (sender, receiver) = bounded::<Batch<T>>(pipeline.queue_capacity);
+            for _ in 0 .. pipeline.get_workers(name) {
+                let input = receiver.clone();
+                let output = next.clone();
+                let recipe = Arc::clone(&recipe);
+                scope.spawn(move || {
+                    let _shift = brewery.stage_shift();
+                    brew_stage(index, recipe, context, input, output)
+                });
+            }
+            next = Some(sender);
+        }
+
+        context.attach_feed(Arc::new(Feed { sender: next, recipe: Arc::clone(&recipe) }));
+        let _attached = Attached(context);
+        context::enter(context, fill);
+    });
+}
+
+///
+/// Closes the feed when the sources are done, even if they panic, letting each stage finish
+/// once the stage before it has.
+struct Attached<'a>(&'a BrewContext);
+
+impl Drop for Attached<'_> {
+    fn drop(&mut self) {
+        self.0.detach_feed();
+    }
+}
+
+///
+/// Worker brewing one stage until the stage before it closes.
+fn brew_stage<T: Send + 'static>(
+    index: usize,
+    recipe: SharedRecipe<T>,
+    context: &Arc<BrewContext>,
+    input: Receiver<Batch<T>>,
+    output: Option<Sender<Batch<T>>>,
+) {
+    let current = Some(Arc::clone(context));
+    for (tea_batch, guard) in input.iter() {
+        let (batches, failure) = context::failures(|| {
+            let recipe = recipe.read().unwrap();
+            context::enter(context, || {
+                guard.brewing(|| panic::catch_unwind(AssertUnwindSafe(|| brew_step(&*recipe[index], tea_batch, &current))))
+                    .map_err(|panic| context.order_panicked(&*panic))
+            })
+        });
+        if let Some(reason) = failure {
+            guard.failed(reason);
+        }
+        match (batches, &output) {
+            // A batch that panicked is dropped, like it is by a Brewer.
+            (Err(()), _) => {},
+            (Ok(Some(batches)), Some(output)) if !batches.is_empty() => {
+                for tea_batch in batches {
+                    let _ = output.send((tea_batch, Arc::clone(&guard)));
+                }
+            },
+            // Batches stopped part way, or expanded into nothing, were still brewed, as they are
+            // by a Brewer.
+            _ => guard.brewed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pipeline;
+    use super::super::pot::Pot;
+    use super::super::ingredient::{Fill, Steep, Pour, FlatMap, Argument};
+    use super::super::brewery::{Brewery, Watchdog, make_tea};
+    use super::super::context::BrewError;
+    use super::super::cancel::{CancelToken, CancelMode};
+    use std::any::Any;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, ThreadId};
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq, Default)]
+    struct TestTea {
+        x: i32,
+    }
+
+    struct StageArgs {
+        pub batches: usize,
+        pub delay: u64,
+        pub sent: Arc<AtomicUsize>,
+        pub poured: Arc<AtomicUsize>,
+        pub in_flight: Arc<AtomicUsize>,
+        pub pour_threads: Arc<Mutex<HashSet<ThreadId>>>,
+        pub token: CancelToken,
+    }
+
+    impl Argument for StageArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct Counters {
+        sent: Arc<AtomicUsize>,
+        poured: Arc<AtomicUsize>,
+        in_flight: Arc<AtomicUsize>,
+        pour_threads: Arc<Mutex<HashSet<ThreadId>>>,
+    }
+
+    fn stage_args(counters: &Counters, batches: usize, delay: u64, token: &CancelToken) -> StageArgs {
+        StageArgs {
+            batches,
+            delay,
+            sent: Arc::clone(&counters.sent),
+            poured: Arc::clone(&counters.poured),
+            in_flight: Arc::clone(&counters.in_flight),
+            pour_threads: Arc::clone(&counters.pour_threads),
+            token: token.clone(),
+        }
+    }
+
+    fn new_counters() -> Counters {
+        Counters {
+            sent: Arc::new(AtomicUsize::new(0)),
+            poured: Arc::new(AtomicUsize::new(0)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            pour_threads: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    fn slow_pour(counters: &Counters, delay: u64, token: &CancelToken) -> Box<Pour<TestTea>> {
+        Box::new(Pour {
+            name: String::from("slow_pour"),
+            computation: Box::new(|tea_batch, args| {
+                let args = args.as_ref().unwrap().as_any().downcast_ref::<StageArgs>().unwrap();
+                thread::sleep(Duration::from_millis(args.delay));
+                args.pour_threads.lock().unwrap().insert(thread::current().id());
+                if args.poured.fetch_add(1, Ordering::SeqCst) + 1 == 5 {
+                    args.token.cancel(CancelMode::Immediate);
+                }
+                tea_batch
+            }),
+            params: Some(Box::new(stage_args(counters, 0, delay, token))),
+        })
+    }
+
+    fn staged_pot(batches: usize, pour_delay: u64, token: &CancelToken) -> (Pot<TestTea>, Counters) {
+        let counters = new_counters();
+        let pot = Pot::new()
+            .add_source(Box::new(Fill {
+                name: String::from("staged_tea"),
+                source: String::from("hardcoded"),
+                computation: Box::new(|args, brewery, recipe| {
+                    let args = args.as_ref().unwrap().as_any().downcast_ref::<StageArgs>().unwrap();
+                    for x in 0 .. args.batches {
+                        if brewery.is_cancelled() {
+                            break;
+                        }
+                        let sent = args.sent.fetch_add(1, Ordering::SeqCst) + 1;
+                        let poured = args.poured.load(Ordering::SeqCst);
+                        args.in_flight.fetch_max(sent - poured, Ordering::SeqCst);
+                        let recipe = Arc::clone(&recipe);
+                        brewery.take_order(move || {
+                            make_tea(vec![TestTea { x: x as i32 }], recipe);
+                        });
+                    }
+                }),
+                params: Some(Box::new(stage_args(&counters, batches, 0, token))),
+            }))
+            .add_ingredient(Box::new(Steep {
+                name: String::from("double"),
+                computation: Box::new(|tea_batch: Vec<TestTea>, _args| {
+                    tea_batch.into_iter().map(|tea| TestTea { x: tea.x * 2 }).collect()
+                }),
+                params: None,
+            }))
+            .add_ingredient(slow_pour(&counters, pour_delay, token));
+        (pot, counters)
+    }
+
+    #[test]
+    fn stage_workers_default_to_pipeline_workers() {
+        let pipeline = Pipeline::new().workers(2).stage_workers("slow_pour", 4);
+        assert_eq!(pipeline.get_workers("double"), 2);
+        assert_eq!(pipeline.get_workers("slow_pour"), 4);
+    }
+
+    #[test]
+    #[should_panic]
+    fn stage_needs_workers() {
+        Pipeline::new().stage_workers("slow_pour", 0);
+    }
+
+    #[test]
+    fn pipelined_brew_runs_every_stage() {
+        let (pot, counters) = staged_pot(20, 1, &CancelToken::new());
+        let brewery = Brewery::new(1);
+        let report = pot.brew_pipelined(&brewery, &Pipeline::new().stage_workers("slow_pour", 3)).unwrap();
+        assert!(report.is_complete());
+        assert_eq!(report.orders_completed, 20);
+        assert_eq!(counters.poured.load(Ordering::SeqCst), 20);
+    }
+
+    #[test]
+    fn watchdog_flags_slow_stage() {
+        let (pot, _counters) = staged_pot(3, 60, &CancelToken::new());
+        let mut watchdog = Watchdog::new().step_timeout("slow_pour", Duration::from_millis(20));
+        watchdog.check_interval = Duration::from_millis(5);
+        let brewery = Brewery::new(1).watchdog(watchdog);
+        let report = pot.brew_pipelined(&brewery, &Pipeline::new()).unwrap();
+        assert!(report.is_complete());
+        assert!(report.overruns >= 1);
+        assert!(report.errors.iter().all(|error| matches!(error, BrewError::StepOverrun { step, .. } if step == "slow_pour")));
+    }
+
+    #[test]
+    fn slow_stage_runs_on_its_own_workers() {
+        let (pot, counters) = staged_pot(12, 20, &CancelToken::new());
+        let brewery = Brewery::new(1);
+        pot.brew_pipelined(&brewery, &Pipeline::new().stage_workers("slow_pour", 3)).unwrap();
+        assert_eq!(counters.pour_threads.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn slow_stage_applies_backpressure() {
+        let (pot, counters) = staged_pot(40, 5, &CancelToken::new());
+        let brewery = Brewery::new(1);
+        let report = pot.brew_pipelined(&brewery, &Pipeline::new().queue_capacity(1)).unwrap();
+        assert_eq!(report.orders_completed, 40);
+        // Two queues of one batch, one batch in each stage, and one waiting to be sent.
+        assert!(counters.in_flight.load(Ordering::SeqCst) <= 5);
+    }
+
+    #[test]
+    fn immediate_cancel_stops_pipeline() {
+        let token = CancelToken::new();
+        let (pot, counters) = staged_pot(1000, 1, &token);
+        let brewery = Brewery::new(1);
+        let report = pot.brew_pipelined_with_token(&brewery, &Pipeline::new(), &token).unwrap();
+        assert_eq!(report.cancelled, Some(CancelMode::Immediate));
+        assert_eq!(counters.poured.load(Ordering::SeqCst), 5);
+        assert!(counters.sent.load(Ordering::SeqCst) < 1000);
+        assert_eq!(report.orders_completed + report.orders_dropped, counters.sent.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn split_batches_keep_their_order_open() {
+        let counters = new_counters();
+        let pot = Pot::new()
+            .add_source(Box::new(Fill {
+                name: String::from("split_tea"),
+                source: String::from("hardcoded"),
+                computation: Box::new(|_args, brewery, recipe| {
+                    for _ in 0 .. 10 {
+                        let recipe = Arc::clone(&recipe);
+                        brewery.take_order(|| {
+                            make_tea(vec![TestTea { x: 1 }, TestTea { x: 2 }, TestTea { x: 3 }], recipe);
+                        });
+                    }
+                }),
+                params: None,
+            }))
+            .add_ingredient(Box::new(FlatMap {
+                name: String::from("explode"),
+                computation: Box::new(|tea: TestTea, _args| vec![TestTea { x: tea.x }, TestTea { x: tea.x + 1 }]),
+                params: None,
+                batch_size: 2,
+            }))
+            .add_ingredient(slow_pour(&counters, 0, &CancelToken::new()));
+        let brewery = Brewery::new(1);
+        let report = pot.brew_pipelined(&brewery, &Pipeline::new().stage_workers("slow_pour", 2)).unwrap();
+        assert!(report.is_complete());
+        assert_eq!(report.orders_completed, 10);
+        assert_eq!(report.steps["slow_pour"].batches, 30);
+        assert_eq!(counters.poured.load(Ordering::SeqCst), 30);
+    }
+}
@@ -1,24 +1,103 @@
-use crate::ingredient::{Ingredient, Fill};
+use crate::ingredient::{Ingredient, SharedRecipe, Steps};
+use crate::dag::{Graph, Dag, DagEntry};
+use crate::envelope::{Envelope, Stamp, stamp_envelopes};
+use crate::recipe::Recipe;
+use crate::topology::Topology;
 use crate::source::Source;
-use crate::brewery::Brewery;
+use crate::brewery::{Brewery, flush_steps};
+use crate::cancel::CancelToken;
+use crate::checkpoint::Checkpoints;
+use crate::context::{self, BrewContext, BrewReport, BrewError};
+use crate::scheduler::Ticket;
+use crate::sink::SinkPour;
+use crate::pipeline::{self, Pipeline};
 
+use std::any::Any;
+use std::collections::HashSet;
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 
 /// Data Structure that holds the recipe to brew tea (ETL data).
 pub struct Pot<T: Send> {
-    recipe:  Arc<RwLock<Vec<Box<dyn Ingredient<T> + Send + Sync>>>>,
+    recipe: SharedRecipe<T>,
     sources: Vec<Box<dyn Source<T>>>,
+    /// Recipe each source sends its batches to, if not the Pot's own, such as a Graph's.
+    feeds: Vec<Option<SharedRecipe<T>>>,
+    ticket: Ticket,
+    /// Pipeline id and directory of the state file the Pot's checkpoints are saved to.
+    checkpoint: Option<(String, PathBuf)>,
+    /// `Stamp` recording the steps each Tea is brewed through, if the Pot traces lineage.
+    lineage: Option<Arc<dyn Any + Send + Sync>>,
+    /// Problems found while building the Pot, such as a Graph that cannot be brewed, reported by
+    /// `validate`.
+    problems: Vec<String>,
 }
 
 impl<T: Send + 'static> Pot<T> {
     ///
-    /// Initializes Pot with an empty recipe and empty sources.
+    /// Initializes Pot with an empty recipe and empty sources. Each Pot is its own tenant in
+    /// the Brewery until given a tenant id.
     pub fn new() -> Pot<T> {
-        Pot { recipe: Arc::new(RwLock::new(Vec::new())), sources: Vec::new() }
+        Pot {
+            recipe: Arc::new(RwLock::new(Vec::new())),
+            sources: Vec::new(),
+            feeds: Vec::new(),
+            ticket: Ticket::unique(),
+            checkpoint: None,
+            lineage: None,
+            problems: Vec::new(),
+        }
+    }
+
+    ///
+    /// Sets the tenant the Pot's Orders are scheduled under. Pots sharing a tenant id share
+    /// its turns in the Brewery.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - pot or tenant id
+    pub fn tenant(mut self, tenant: &str) -> Pot<T> {
+        self.ticket.tenant = String::from(tenant);
+        self
+    }
+
+    ///
+    /// Sets the priority of the Pot's Orders. Orders with a higher priority are brewed before
+    /// those of lower priority Pots sharing the Brewery.
+    ///
+    /// # Arguments
+    ///
+    /// * `priority` - higher priorities are brewed first
+    pub fn priority(mut self, priority: u8) -> Pot<T> {
+        self.ticket.priority = priority;
+        self
     }
 
     ///
-    /// Adds Ingredient to recipe held by the Pot.
+    /// Saves how far each source has been brewed to a state file, so a brew that is stopped part
+    /// way through, such as by a crash, resumes where it left off when the Pot is brewed again
+    /// with the same pipeline id. Sources take Orders with `Brewery::take_order_at` to record
+    /// their position, and skip ahead to `Brewery::resume_position`. The state file is removed
+    /// once a brew finishes every batch it took without being cancelled.
+    ///
+    /// # Arguments
+    ///
+    /// * `pipeline_id` - names the state file
+    /// * `state_dir` - directory holding the state file
+    pub fn checkpoint<P: Into<PathBuf>>(mut self, pipeline_id: &str, state_dir: P) -> Pot<T> {
+        self.checkpoint = Some((String::from(pipeline_id), state_dir.into()));
+        self
+    }
+
+    ///
+    /// Returns the Ticket the Pot's Orders are scheduled with.
+    pub fn get_ticket(&self) -> &Ticket {
+        &self.ticket
+    }
+
+    ///
+    /// Adds Ingredient to recipe held by the Pot. A step that cannot be brewed is reported by
+    /// `validate`.
     ///
     /// # Arguments
     ///
@@ -28,6 +107,47 @@ impl<T: Send + 'static> Pot<T> {
         self
     }
 
+    ///
+    /// Adds a Recipe's steps to the end of the recipe held by the Pot. The steps are shared with
+    /// the Recipe, so it can be added to many Pots. Steps that cannot be brewed are reported by
+    /// `validate`, like those added with `add_ingredient`.
+    ///
+    /// # Arguments
+    ///
+    /// * `recipe` - the Recipe whose steps are added
+    pub fn extend_recipe(self, recipe: &Recipe<T>) -> Pot<T> {
+        self.recipe.write().unwrap().extend(recipe.to_steps());
+        self
+    }
+
+    ///
+    /// Adds another Pot's sources, Graphs and steps to this one, such as a Pot of sources and a
+    /// Pot of steps built separately. Its steps are added to the end of this Pot's recipe, so the
+    /// sources of both Pots brew through the whole recipe. This Pot's tenant and priority are
+    /// kept. The merged Pot is checkpointed if either Pot is, and traces lineage if either does.
+    /// Pots checkpointed to different pipeline ids or directories are reported by `validate`.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - the Pot to merge into this one
+    pub fn merge(mut self, other: Pot<T>) -> Pot<T> {
+        if let (Some(checkpoint), Some(other_checkpoint)) = (&self.checkpoint, &other.checkpoint) {
+            if checkpoint != other_checkpoint {
+                self.problems.push(format!(
+                    "merged pots are checkpointed to {} in {} and {} in {}",
+                    checkpoint.0, checkpoint.1.display(), other_checkpoint.0, other_checkpoint.1.display(),
+                ));
+            }
+        }
+        self.problems.extend(other.problems);
+        self.recipe.write().unwrap().extend(other.recipe.write().unwrap().drain(..));
+        self.sources.extend(other.sources);
+        self.feeds.extend(other.feeds);
+        self.checkpoint = self.checkpoint.or(other.checkpoint);
+        self.lineage = self.lineage.or(other.lineage);
+        self
+    }
+
     ///
     /// Adds Source to sources held by the Pot.
     ///
@@ -36,6 +156,37 @@ impl<T: Send + 'static> Pot<T> {
     /// * `source` - the source to add to the sources Array
     pub fn add_source(mut self, source: Box<dyn Source<T>>) -> Pot<T> {
         self.sources.push(source);
+        self.feeds.push(None);
+        self
+    }
+
+    ///
+    /// Adds a Graph of steps along with the sources feeding it. Its sources are brewed through
+    /// the Graph rather than the recipe, and are listed with the Pot's other sources. A Graph
+    /// that cannot be brewed, such as one with a cycle, is reported by `validate` instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `graph` - the Graph and its sources
+    pub fn add_graph(mut self, graph: Graph<T>) -> Pot<T> where T: Clone {
+        let built = match graph.build() {
+            Ok(built) => built,
+            Err(reason) => {
+                self.problems.push(format!("graph cannot be brewed: {}", reason));
+                return self;
+            },
+        };
+        let dag = built.dag;
+        for (entry, (source, node)) in built.sources.into_iter().enumerate() {
+            let feed: SharedRecipe<T> = Arc::new(RwLock::new(vec![Box::new(DagEntry {
+                name: format!("{}->{}", source.get_name(), dag.steps()[node].get_name()),
+                dag: Arc::clone(&dag),
+                node,
+                flushes: entry == 0,
+            })]));
+            self.sources.push(source);
+            self.feeds.push(Some(feed));
+        }
         self
     }
 
@@ -47,38 +198,397 @@ impl<T: Send + 'static> Pot<T> {
 
     /// 
     /// Returns the recipe held by the Pot.
-    pub fn get_recipe(&self) -> Arc<RwLock<Vec<Box<dyn Ingredient<T> + Send + Sync>>>> {
+    pub fn get_recipe(&self) -> SharedRecipe<T> {
         Arc::clone(&self.recipe)
     }
 
     ///
-    /// Iterates over sources to pull in data and send jobs to the Brewery for processing.
+    /// Returns the Pot's sources, steps and the flow of Tea between them in Graphviz DOT, such as
+    /// for a runbook or a review of a recipe change.
+    pub fn to_dot(&self) -> String {
+        self.topology().to_dot()
+    }
+
+    ///
+    /// Returns the Pot's sources, steps and the flow of Tea between them as a Mermaid flowchart.
+    pub fn to_mermaid(&self) -> String {
+        self.topology().to_mermaid()
+    }
+
+    ///
+    /// Returns each Graph added to the Pot.
+    fn graphs(&self) -> Vec<Arc<Dag<T>>> {
+        let mut graphs: Vec<Arc<Dag<T>>> = Vec::new();
+        for feed in self.feeds.iter().flatten() {
+            let feed = feed.read().unwrap();
+            if let Some(entry) = feed.first().and_then(|step| step.as_any().downcast_ref::<DagEntry<T>>()) {
+                if !graphs.iter().any(|dag| Arc::ptr_eq(dag, &entry.dag)) {
+                    graphs.push(Arc::clone(&entry.dag));
+                }
+            }
+        }
+        graphs
+    }
+
+    ///
+    /// Returns true if the recipe or a Graph writes to a Sink, whose output is only committed
+    /// once the whole brew succeeds.
+    fn has_sinks(&self) -> bool {
+        has_sink(&self.recipe.read().unwrap()) || self.graphs().iter().any(|dag| has_sink(dag.steps()))
+    }
+
+    ///
+    /// Lays out the sources, the recipe, any Graphs, and the Tea flowing between them.
+    fn topology(&self) -> Topology {
+        let mut topology = Topology::new();
+        let first = topology.steps(&self.recipe.read().unwrap());
+        let mut graphs: Vec<(Arc<Dag<T>>, Vec<usize>)> = Vec::new();
+        for (source, feed) in self.sources.iter().zip(&self.feeds) {
+            let id = topology.source(source.get_name(), source.kind());
+            let entry = match feed {
+                None => first,
+                Some(feed) => {
+                    let feed = feed.read().unwrap();
+                    feed.first().and_then(|step| step.as_any().downcast_ref::<DagEntry<T>>()).map(|entry| {
+                        let drawn = graphs.iter().position(|(dag, _)| Arc::ptr_eq(dag, &entry.dag));
+                        let drawn = drawn.unwrap_or_else(|| {
+                            graphs.push((Arc::clone(&entry.dag), draw_graph(&mut topology, &entry.dag)));
+                            graphs.len() - 1
+                        });
+                        graphs[drawn].1[entry.node]
+                    })
+                },
+            };
+            if let Some(entry) = entry {
+                topology.edge(id, entry, None);
+            }
+        }
+        topology
+    }
+
+    ///
+    /// Checks the Pot can be brewed, returning every problem found: missing sources or steps,
+    /// duplicate names, a recipe or Graph without a Pour, sources, steps or params that fail
+    /// their own validation, and problems found while building it, such as a Graph that cannot
+    /// be brewed. A custom sink Ingredient can return "Pour" from `kind` to count as
+    /// a Pour.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = self.problems.clone();
+        let recipe = self.recipe.read().unwrap();
+        let graphs = self.graphs();
+
+        if self.sources.is_empty() {
+            problems.push(String::from("pot has no sources"));
+        }
+        let mut names = HashSet::new();
+        for (source, feed) in self.sources.iter().zip(&self.feeds) {
+            if !names.insert(source.get_name()) {
+                problems.push(format!("source {} is added twice", source.get_name()));
+            }
+            if let Err(reason) = source.validate() {
+                problems.push(format!("source {}: {}", source.get_name(), reason));
+            }
+            if feed.is_none() && recipe.is_empty() && !graphs.is_empty() {
+                problems.push(format!("source {} has no steps to brew", source.get_name()));
+            }
+        }
+
+        if recipe.is_empty() && graphs.is_empty() {
+            problems.push(String::from("pot has no steps"));
+        }
+        if !recipe.is_empty() && !self.sources.is_empty() && self.feeds.iter().all(Option::is_some) {
+            problems.push(String::from("recipe has no sources"));
+        }
+        if let Some((pipeline_id, _)) = &self.checkpoint {
+            if pipeline_id.is_empty() {
+                problems.push(String::from("checkpoint has no pipeline id"));
+            }
+        }
+        if !recipe.is_empty() && !has_pour(&recipe) {
+            problems.push(String::from("recipe has no Pour"));
+        }
+        for dag in &graphs {
+            if !has_pour(dag.steps()) {
+                problems.push(String::from("graph has no Pour"));
+            }
+        }
+
+        let mut names = HashSet::new();
+        for step in recipe.iter().chain(graphs.iter().flat_map(|dag| dag.steps())) {
+            if !names.insert(step.get_name()) {
+                problems.push(format!("step {} is added twice", step.get_name()));
+            }
+            if let Err(reason) = step.validate() {
+                problems.push(format!("step {}: {}", step.get_name(), reason));
+            }
+        }
+        check_limits(&recipe, "", true, &mut problems);
+        for dag in &graphs {
+            check_limits(dag.steps(), "", false, &mut problems);
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    ///
+    /// Iterates over sources to pull in data and send jobs to the Brewery for processing, then
+    /// waits for the Brewery to finish brewing them. Returns every problem found by `validate`
+    /// instead if the Pot cannot be brewed.
+    ///
+    /// # Arguments
+    ///
+    /// * `brewery` - Brewery struct holding the receiver and Brewer Array to process Tea
+    pub fn brew(&self, brewery: &Brewery) -> Result<BrewReport, Vec<String>> {
+        self.brew_with_token(brewery, &CancelToken::new())
+    }
+
+    ///
+    /// Brews like `brew`, stopping early once `token` is cancelled. Sources are not collected
+    /// after the cancel, queued Orders are dropped, and an Immediate cancel also stops batches
+    /// between steps. The returned report describes what was left unprocessed.
     ///
     /// # Arguments
     ///
     /// * `brewery` - Brewery struct holding the receiver and Brewer Array to process Tea
-    pub fn brew(&self, brewery: &Brewery) {
+    /// * `token` - token used to cancel the brew
+    pub fn brew_with_token(&self, brewery: &Brewery, token: &CancelToken) -> Result<BrewReport, Vec<String>> {
+        self.validate()?;
+        let context = Arc::new(self.context(token)?);
         println!("Brewing Tea...");
-        for source in self.get_sources() {
+        let sources_skipped = context::enter(&context, || self.fill(brewery));
+
+        context.wait();
+        self.flush(&context);
+        let mut report = context.report(sources_skipped);
+        context::enter(&context, || self.finish(&mut report));
+        context.clear_checkpoints(&mut report);
+        Ok(report)
+    }
+
+    ///
+    /// Brews the Pot as a pipeline: each Ingredient becomes a stage with its own workers, linked
+    /// to the next stage by a bounded queue, so slow steps and fast steps overlap and a backed up
+    /// stage slows the sources down. The Brewery is only used to take the sources' Orders.
+    /// Returns every problem found by `validate` instead if the Pot cannot be brewed, or if the
+    /// Pipeline sets workers for a step not in the recipe.
+    ///
+    /// # Arguments
+    ///
+    /// * `brewery` - Brewery passed to the sources
+    /// * `pipeline` - workers and queue sizes of the stages
+    pub fn brew_pipelined(&self, brewery: &Brewery, pipeline: &Pipeline) -> Result<BrewReport, Vec<String>> {
+        self.brew_pipelined_with_token(brewery, pipeline, &CancelToken::new())
+    }
+
+    ///
+    /// Brews like `brew_pipelined`, stopping early once `token` is cancelled. Batches already in
+    /// the pipeline finish on a Graceful cancel, and stop at their next stage on an Immediate one.
+    ///
+    /// # Arguments
+    ///
+    /// * `brewery` - Brewery passed to the sources
+    /// * `pipeline` - workers and queue sizes of the stages
+    /// * `token` - token used to cancel the brew
+    pub fn brew_pipelined_with_token(
+        &self,
+        brewery: &Brewery,
+        pipeline: &Pipeline,
+        token: &CancelToken,
+    ) -> Result<BrewReport, Vec<String>> {
+        let mut problems = self.validate().err().unwrap_or_default();
+        let recipe = self.recipe.read().unwrap();
+        let mut stages: Vec<&String> = pipeline.stage_workers.keys().collect();
+        stages.sort();
+        for stage in stages {
+            if !recipe.iter().any(|step| step.get_name() == stage) {
+                problems.push(format!("pipeline sets workers for unknown step {}", stage));
+            }
+        }
+        drop(recipe);
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+
+        let context = Arc::new(self.context(token)?);
+        println!("Brewing Tea in stages...");
+        let mut sources_skipped = 0;
+        pipeline::run(pipeline, brewery, self.get_recipe(), &context, || {
+            sources_skipped = self.fill(brewery);
+        });
+
+        context.wait();
+        self.flush(&context);
+        let mut report = context.report(sources_skipped);
+        context::enter(&context, || self.finish(&mut report));
+        context.clear_checkpoints(&mut report);
+        Ok(report)
+    }
+
+    ///
+    /// Brews the Tea held back by steps, such as aggregates, through the rest of the recipe once
+    /// every Order is done. Steps are flushed in recipe order, so a later step holding Tea back
+    /// sees everything flushed before it.
+    fn flush(&self, context: &Arc<BrewContext>) {
+        let current = Some(Arc::clone(context));
+        context::enter(context, || {
+            flush_steps(&self.recipe.read().unwrap(), &current);
+            for feed in self.feeds.iter().flatten() {
+                flush_steps(&feed.read().unwrap(), &current);
+            }
+        });
+    }
+
+    ///
+    /// Commits the output of the sinks in the recipe and any Graphs once the brew has succeeded,
+    /// or aborts it otherwise, adding sinks that fail to commit to the report's errors.
+    fn finish(&self, report: &mut BrewReport) {
+        let success = report.is_successful();
+        let mut errors = Vec::new();
+        finish_steps(&self.recipe.read().unwrap(), "", success, &mut errors);
+        for dag in self.graphs() {
+            finish_steps(dag.steps(), "", success, &mut errors);
+        }
+        report.errors.extend(errors);
+    }
+
+    ///
+    /// Starts a brew, loading the Pot's checkpoints if it has any.
+    fn context(&self, token: &CancelToken) -> Result<BrewContext, Vec<String>> {
+        let checkpoints = match &self.checkpoint {
+            Some((pipeline_id, state_dir)) => Some(Checkpoints::load(pipeline_id, state_dir)
+                .map_err(|reason| vec![reason])?
+                .held(self.has_sinks())),
+            None => None,
+        };
+        Ok(BrewContext::new(token.clone(), self.ticket.clone())
+            .checkpoints(checkpoints)
+            .lineage(self.lineage.clone())
+            .output_held(self.has_sinks()))
+    }
+
+    ///
+    /// Collects each source in turn, returning how many were skipped after a cancel. Sources left
+    /// once a `Limit` was reached are not needed, so they are not counted as skipped.
+    fn fill(&self, brewery: &Brewery) -> usize {
+        let mut sources_skipped = 0;
+        for (source, feed) in self.sources.iter().zip(&self.feeds) {
+            if brewery.is_cancelled() {
+                if context::current().is_some_and(|context| context.token().is_cancelled()) {
+                    sources_skipped += 1;
+                }
+                continue;
+            }
             source.print();
-            let fill = source.as_any().downcast_ref::<Fill<T>>().unwrap();
-            fill.collect(brewery, self.get_recipe());
+            if let Some(context) = context::current() {
+                context.source_started(source.get_name(), source.get_source());
+            }
+            let recipe = feed.as_ref().map_or_else(|| self.get_recipe(), Arc::clone);
+            source.collect(brewery, recipe);
+        }
+        sources_skipped
+    }
+}
+
+impl<T: Send + 'static> Pot<Envelope<T>> {
+    ///
+    /// Records each step an envelope is brewed through in its `metadata.steps`, so bad output
+    /// can be traced back through the recipe.
+    pub fn trace_lineage(mut self) -> Pot<Envelope<T>> {
+        self.lineage = Some(Arc::new(Stamp(stamp_envelopes::<T>)));
+        self
+    }
+}
+
+///
+/// Returns true if the steps, or their sub-recipes, include a Pour.
+fn has_pour<T: Send>(steps: &Steps<T>) -> bool {
+    steps.iter().any(|step| {
+        step.kind() == "Pour" || step.sub_recipes().iter().any(|(_, sub_recipe)| has_pour(sub_recipe))
+    })
+}
+
+///
+/// Returns true if any step, or a step in a sub-recipe, is a SinkPour.
+fn has_sink<T: Send + 'static>(steps: &Steps<T>) -> bool {
+    steps.iter().any(|step| {
+        step.as_any().is::<SinkPour<T>>() || step.sub_recipes().iter().any(|(_, sub_recipe)| has_sink(sub_recipe))
+    })
+}
+
+///
+/// Adds a problem for each Limit in the steps, or their sub-recipes, that not every batch reaches.
+/// A Limit stops every source of the Pot, so it must be on the path of the whole recipe: a nested
+/// Recipe is, but branches, side steps and Graphs are not.
+fn check_limits<T: Send>(steps: &Steps<T>, scope: &str, on_path: bool, problems: &mut Vec<String>) {
+    for step in steps.iter() {
+        if step.kind() == "Limit" && !on_path {
+            problems.push(format!("step {}{}: Limit stops every source, so it must be in the recipe itself", scope, step.get_name()));
+        }
+        for (branch, sub_recipe) in step.sub_recipes() {
+            check_limits(sub_recipe, &format!("{}{}/", scope, branch), on_path && step.kind() == "Recipe", problems);
+        }
+    }
+}
+
+///
+/// Finishes the steps and their sub-recipes, recording steps that fail under their branch.
+fn finish_steps<T: Send>(steps: &Steps<T>, scope: &str, success: bool, errors: &mut Vec<BrewError>) {
+    for step in steps.iter() {
+        if let Err(reason) = step.finish(success) {
+            errors.push(BrewError::StepFailed { step: format!("{}{}", scope, step.get_name()), reason });
+        }
+        for (branch, sub_recipe) in step.sub_recipes() {
+            finish_steps(sub_recipe, &format!("{}{}/", scope, branch), success, errors);
+        }
+    }
+}
+
+///
+/// Adds a Graph's steps and edges to a diagram, returning the id of each step.
+fn draw_graph<T: Send>(topology: &mut Topology, dag: &Dag<T>) -> Vec<usize> {
+    let ids: Vec<usize> = dag.steps().iter().map(|step| topology.ingredient(&**step)).collect();
+    for (node, children) in dag.children().iter().enumerate() {
+        for child in children {
+            topology.edge(ids[node], ids[*child], None);
         }
     }
+    ids
+}
+
+impl<T: Send + 'static> Default for Pot<T> {
+    fn default() -> Self {
+        Pot::new()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::Pot;
-    use super::super::ingredient::{Fill, Steep, Pour, Argument};
+    use super::super::ingredient::{Fill, Steep, Pour, Filter, Argument, Ingredient};
+    use super::super::branch::Branch;
+    use super::super::brewery::{Brewery, Watchdog, make_tea};
+    use super::super::cancel::{CancelToken, CancelMode};
+    use super::super::context::BrewError;
+    use super::super::envelope::Envelope;
+    use super::super::pipeline::Pipeline;
     use std::any::Any;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
 
-    #[derive(Debug, PartialEq, Default)]
+    #[derive(Debug, PartialEq, Default, Clone)]
     struct TestTea {
         x: i32,
     }
 
     #[derive(Default)]
+    #[allow(dead_code)]
     struct TestArgs {
         pub val: i32
     }
@@ -188,14 +698,438 @@ mod tests {
         assert_eq!(new_pot.get_recipe().read().unwrap()[1].get_name(), "pour1");
     }
 
-    //TODO: Readd test after returning Result
-    //#[test]
-    //fn brew_recipe() {
-    //    let mut new_pot = Pot::new();
-    //    new_pot.add(Box::new(Fill));
-    //    new_pot.add(Box::new(Steep));
-    //    new_pot.add(Box::new(Pour));
-    //    assert_eq!(new_pot.brew(), 3);
-    //}
+    #[test]
+    fn add_unnamed_ingredient() {
+        let new_pot = Pot::new()
+            .add_source(counting_fill(1))
+            .add_ingredient(Box::new(Steep{
+                name: String::new(),
+                computation: Box::new(|_tea, _args| {
+                    vec![TestTea::default()]
+                }),
+                params: None,
+            }))
+            .add_ingredient(counting_pour(&Arc::new(AtomicUsize::new(0))));
+        assert_eq!(new_pot.validate(), Ok(()));
+    }
+
+    /// Ingredient missing the connection it needs to brew.
+    struct Unconnected;
+
+    impl Ingredient<TestTea> for Unconnected {
+        fn exec(&self, tea_batch: Vec<TestTea>) -> Vec<TestTea> {
+            tea_batch
+        }
+        fn print(&self) {
+            println!("Current Step: {}", self.get_name());
+        }
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+        fn get_name(&self) -> &str {
+            "unconnected"
+        }
+        fn validate(&self) -> Result<(), String> {
+            Err(String::from("no connection configured"))
+        }
+    }
+
+    #[test]
+    fn add_invalid_custom_ingredient() {
+        let new_pot = Pot::new()
+            .add_source(counting_fill(1))
+            .add_ingredient(Box::new(Unconnected))
+            .add_ingredient(counting_pour(&Arc::new(AtomicUsize::new(0))));
+        assert_eq!(new_pot.validate(), Err(vec![String::from("step unconnected: no connection configured")]));
+    }
+
+    struct FillArgs {
+        pub batches: usize,
+    }
+
+    impl Argument for FillArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct CancelArgs {
+        pub token: CancelToken,
+        pub mode: CancelMode,
+        pub after: usize,
+        pub seen: Arc<AtomicUsize>,
+    }
+
+    impl Argument for CancelArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn counting_fill(batches: usize) -> Box<Fill<TestTea>> {
+        Box::new(Fill {
+            name: String::from("counting_tea"),
+            source: String::from("hardcoded"),
+            computation: Box::new(|args, brewery, recipe| {
+                let batches = args.as_ref().unwrap().as_any().downcast_ref::<FillArgs>().unwrap().batches;
+                for _ in 0 .. batches {
+                    if brewery.is_cancelled() {
+                        break;
+                    }
+                    let recipe = Arc::clone(&recipe);
+                    brewery.take_order(|| {
+                        make_tea(vec![TestTea::default()], recipe);
+                    });
+                }
+            }),
+            params: Some(Box::new(FillArgs { batches })),
+        })
+    }
+
+    fn cancelling_steep(args: CancelArgs) -> Box<Steep<TestTea>> {
+        Box::new(Steep {
+            name: String::from("cancel_steep"),
+            computation: Box::new(|tea_batch, args| {
+                let args = args.as_ref().unwrap().as_any().downcast_ref::<CancelArgs>().unwrap();
+                if args.seen.fetch_add(1, Ordering::SeqCst) + 1 == args.after {
+                    args.token.cancel(args.mode);
+                }
+                tea_batch
+            }),
+            params: Some(Box::new(args)),
+        })
+    }
+
+    fn counting_pour(poured: &Arc<AtomicUsize>) -> Box<Pour<TestTea>> {
+        Box::new(Pour {
+            name: String::from("count_pour"),
+            computation: Box::new(|tea_batch, args| {
+                let args = args.as_ref().unwrap().as_any().downcast_ref::<CancelArgs>().unwrap();
+                args.seen.fetch_add(tea_batch.len(), Ordering::SeqCst);
+                tea_batch
+            }),
+            params: Some(Box::new(CancelArgs {
+                token: CancelToken::new(),
+                mode: CancelMode::Graceful,
+                after: 0,
+                seen: Arc::clone(poured),
+            })),
+        })
+    }
+
+    #[test]
+    fn brew_pot_to_completion() {
+        let poured = Arc::new(AtomicUsize::new(0));
+        let new_pot = Pot::new()
+            .add_source(counting_fill(20))
+            .add_ingredient(counting_pour(&poured));
+        let brewery = Brewery::new(2);
+        let report = new_pot.brew(&brewery).unwrap();
+        assert!(report.is_complete());
+        assert_eq!(report.orders_completed, 20);
+        assert_eq!(poured.load(Ordering::SeqCst), 20);
+    }
+
+    #[test]
+    fn merged_pot_brews_sources_through_both_recipes() {
+        let poured = Arc::new(AtomicUsize::new(0));
+        let sources = Pot::new()
+            .tenant("merged")
+            .add_source(counting_fill(5))
+            .add_ingredient(Box::new(Steep {
+                name: String::from("steep"),
+                computation: Box::new(|tea_batch, _args| tea_batch),
+                params: None,
+            }));
+        let steps = Pot::new().add_ingredient(counting_pour(&poured));
+        let new_pot = sources.merge(steps);
+        assert_eq!(new_pot.get_ticket().tenant, "merged");
+        assert_eq!(new_pot.get_recipe().read().unwrap()[1].get_name(), "count_pour");
+
+        let report = new_pot.brew(&Brewery::new(2)).unwrap();
+        assert_eq!(poured.load(Ordering::SeqCst), 5);
+        assert_eq!(report.steps["steep"].records_out, 5);
+    }
+
+    #[test]
+    fn merged_pot_keeps_checkpoint_and_lineage() {
+        let traced = Pot::<Envelope<TestTea>>::new().trace_lineage();
+        let new_pot = traced.merge(Pot::new().checkpoint("nightly", "/tmp"));
+        assert_eq!(new_pot.checkpoint, Some((String::from("nightly"), PathBuf::from("/tmp"))));
+        assert!(new_pot.lineage.is_some());
+    }
+
+    #[test]
+    fn merging_conflicting_checkpoints_is_invalid() {
+        let new_pot = Pot::<TestTea>::new().checkpoint("nightly", "/tmp").merge(Pot::new().checkpoint("hourly", "/tmp"));
+        let problems = new_pot.validate().unwrap_err();
+        assert_eq!(problems[0], "merged pots are checkpointed to nightly in /tmp and hourly in /tmp");
+    }
+
+    #[test]
+    fn panicking_step_drops_its_batch() {
+        let poured = Arc::new(AtomicUsize::new(0));
+        let new_pot = Pot::new()
+            .add_source(counting_fill(3))
+            .add_ingredient(Box::new(Steep {
+                name: String::from("panicky"),
+                computation: Box::new(|tea_batch, args| {
+                    let args = args.as_ref().unwrap().as_any().downcast_ref::<CancelArgs>().unwrap();
+                    if args.seen.fetch_add(1, Ordering::SeqCst) == 0 {
+                        panic!("bad tea");
+                    }
+                    tea_batch
+                }),
+                params: Some(Box::new(CancelArgs {
+                    token: CancelToken::new(),
+                    mode: CancelMode::Graceful,
+                    after: 0,
+                    seen: Arc::new(AtomicUsize::new(0)),
+                })),
+            }))
+            .add_ingredient(counting_pour(&poured));
+        let report = new_pot.brew(&Brewery::new(1)).unwrap();
+
+        assert_eq!(poured.load(Ordering::SeqCst), 2);
+        assert_eq!((report.orders_completed, report.orders_dropped), (2, 1));
+        assert_eq!(report.errors, vec![BrewError::StepFailed { step: String::from("panicky"), reason: String::from("bad tea") }]);
+        assert!(!report.is_complete());
+    }
+
+    #[test]
+    fn brew_reports_step_stats() {
+        let poured = Arc::new(AtomicUsize::new(0));
+        let new_pot = Pot::new()
+            .add_source(counting_fill(10))
+            .add_ingredient(Box::new(Filter {
+                name: String::from("drop_all"),
+                predicate: Box::new(|_tea: &TestTea, _args| false),
+                params: None,
+                rejected: Vec::new(),
+            }))
+            .add_ingredient(counting_pour(&poured));
+        let brewery = Brewery::new(2);
+        let report = new_pot.brew(&brewery).unwrap();
+        let filtered = &report.steps["drop_all"];
+        assert_eq!(filtered.batches, 10);
+        assert_eq!(filtered.records_in, 10);
+        assert_eq!(filtered.records_out, 0);
+        assert_eq!(filtered.records_rejected, 10);
+        assert_eq!(report.steps["count_pour"].records_in, 0);
+        assert_eq!(poured.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn brew_with_cancelled_token_skips_sources() {
+        let poured = Arc::new(AtomicUsize::new(0));
+        let new_pot = Pot::new()
+            .add_source(counting_fill(20))
+            .add_ingredient(counting_pour(&poured));
+        let brewery = Brewery::new(2);
+        let token = CancelToken::new();
+        token.cancel(CancelMode::Graceful);
+        let report = new_pot.brew_with_token(&brewery, &token).unwrap();
+        assert_eq!(report.sources_skipped, 1);
+        assert_eq!(report.orders_completed, 0);
+        assert_eq!(poured.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn graceful_cancel_stops_brew_early() {
+        let token = CancelToken::new();
+        let poured = Arc::new(AtomicUsize::new(0));
+        let new_pot = Pot::new()
+            .add_source(counting_fill(1000))
+            .add_ingredient(cancelling_steep(CancelArgs {
+                token: token.clone(),
+                mode: CancelMode::Graceful,
+                after: 10,
+                seen: Arc::new(AtomicUsize::new(0)),
+            }))
+            .add_ingredient(counting_pour(&poured));
+        let brewery = Brewery::new(1);
+        let report = new_pot.brew_with_token(&brewery, &token).unwrap();
+        assert_eq!(report.cancelled, Some(CancelMode::Graceful));
+        assert!(!report.is_complete());
+        assert_eq!(report.batches_interrupted, 0);
+        // Batches already brewing finish, so every order that ran was poured.
+        assert_eq!(poured.load(Ordering::SeqCst), report.orders_completed);
+        assert!(report.orders_completed < 1000);
+    }
+
+    #[test]
+    fn immediate_cancel_interrupts_batches() {
+        let token = CancelToken::new();
+        let poured = Arc::new(AtomicUsize::new(0));
+        let new_pot = Pot::new()
+            .add_source(counting_fill(1000))
+            .add_ingredient(cancelling_steep(CancelArgs {
+                token: token.clone(),
+                mode: CancelMode::Immediate,
+                after: 1,
+                seen: Arc::new(AtomicUsize::new(0)),
+            }))
+            .add_ingredient(counting_pour(&poured));
+        let brewery = Brewery::new(1);
+        let report = new_pot.brew_with_token(&brewery, &token).unwrap();
+        assert_eq!(report.cancelled, Some(CancelMode::Immediate));
+        assert_eq!(report.batches_interrupted, 1);
+        assert_eq!(report.records_interrupted, 1);
+        assert_eq!(poured.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn watchdog_halts_stuck_step() {
+        let poured = Arc::new(AtomicUsize::new(0));
+        let new_pot = Pot::new()
+            .add_source(Box::new(Fill {
+                name: String::from("slow_tea"),
+                source: String::from("hardcoded"),
+                computation: Box::new(|_args, brewery, recipe| {
+                    for _ in 0 .. 2 {
+                        let recipe = Arc::clone(&recipe);
+                        brewery.take_order(|| {
+                            make_tea(vec![TestTea::default()], recipe);
+                        });
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                }),
+                params: None,
+            }))
+            .add_ingredient(Box::new(Steep {
+                name: String::from("slow_steep"),
+                computation: Box::new(|tea_batch, args| {
+                    let args = args.as_ref().unwrap().as_any().downcast_ref::<CancelArgs>().unwrap();
+                    // Only the first batch gets stuck.
+                    if args.seen.fetch_add(1, Ordering::SeqCst) == 0 {
+                        thread::sleep(Duration::from_millis(300));
+                    }
+                    tea_batch
+                }),
+                params: Some(Box::new(CancelArgs {
+                    token: CancelToken::new(),
+                    mode: CancelMode::Graceful,
+                    after: 0,
+                    seen: Arc::new(AtomicUsize::new(0)),
+                })),
+            }))
+            .add_ingredient(counting_pour(&poured));
+
+        let mut watchdog = Watchdog::new().step_timeout("slow_steep", Duration::from_millis(20));
+        watchdog.halt_stuck_steps = true;
+        watchdog.check_interval = Duration::from_millis(5);
+        let brewery = Brewery::new(2).watchdog(watchdog);
+        let report = new_pot.brew(&brewery).unwrap();
+
+        assert_eq!(report.overruns, 1);
+        assert_eq!(report.batches_interrupted, 1);
+        assert_eq!(poured.load(Ordering::SeqCst), 1);
+        assert!(matches!(report.errors[0], BrewError::StepOverrun { ref step, .. } if step == "slow_steep"));
+        assert_eq!(report.errors[1], BrewError::StepHalted { step: String::from("slow_steep"), records: 1 });
+    }
+
+    fn diagram_pot() -> Pot<TestTea> {
+        let poured = Arc::new(AtomicUsize::new(0));
+        Pot::new()
+            .add_source(counting_fill(1))
+            .add_ingredient(Box::new(Steep {
+                name: String::from("steep \"raw\""),
+                computation: Box::new(|tea_batch, _args| tea_batch),
+                params: None,
+            }))
+            .add_ingredient(Box::new(Branch::new("fan_out").branch("archive", vec![counting_pour(&poured)])))
+            .add_ingredient(counting_pour(&poured))
+    }
+
+    #[test]
+    fn pot_to_dot() {
+        assert_eq!(diagram_pot().to_dot(), "\
+digraph pot {
+    rankdir=LR;
+    n0 [label=\"steep \\\"raw\\\"\\n(Steep)\", shape=box];
+    n1 [label=\"fan_out\\n(Branch)\", shape=box];
+    n2 [label=\"count_pour\\n(Pour)\", shape=box];
+    n3 [label=\"count_pour\\n(Pour)\", shape=box];
+    n4 [label=\"counting_tea\\n(Fill)\", shape=cylinder];
+    n1 -> n2 [label=\"archive\"];
+    n0 -> n1;
+    n1 -> n3;
+    n4 -> n0;
+}
+");
+    }
+
+    #[test]
+    fn pot_to_mermaid() {
+        assert_eq!(diagram_pot().to_mermaid(), "\
+flowchart LR
+    n0[\"steep #quot;raw#quot;<br/>(Steep)\"]
+    n1[\"fan_out<br/>(Branch)\"]
+    n2[\"count_pour<br/>(Pour)\"]
+    n3[\"count_pour<br/>(Pour)\"]
+    n4[(\"counting_tea<br/>(Fill)\")]
+    n1 -->|\"archive\"| n2
+    n0 --> n1
+    n1 --> n3
+    n4 --> n0
+");
+    }
+
+    /// Argument missing the file it needs to read.
+    struct MissingFileArgs;
+
+    impl Argument for MissingFileArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+        fn validate(&self) -> Result<(), String> {
+            Err(String::from("no file given"))
+        }
+    }
+
+    #[test]
+    fn validate_empty_pot() {
+        assert_eq!(Pot::<TestTea>::new().validate(), Err(vec![
+            String::from("pot has no sources"),
+            String::from("pot has no steps"),
+        ]));
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let poured = Arc::new(AtomicUsize::new(0));
+        let mut unreadable = counting_fill(1);
+        unreadable.params = Some(Box::new(MissingFileArgs));
+        let new_pot = Pot::new()
+            .add_source(counting_fill(1))
+            .add_source(unreadable)
+            .add_ingredient(counting_pour(&poured))
+            .add_ingredient(counting_pour(&poured));
+        assert_eq!(new_pot.validate(), Err(vec![
+            String::from("source counting_tea is added twice"),
+            String::from("source counting_tea: invalid params: no file given"),
+            String::from("step count_pour is added twice"),
+        ]));
+    }
+
+    #[test]
+    fn brew_refuses_recipe_without_pour() {
+        let new_pot = Pot::new()
+            .add_source(counting_fill(1))
+            .add_ingredient(Box::new(Steep {
+                name: String::from("steep"),
+                computation: Box::new(|tea_batch, _args| tea_batch),
+                params: None,
+            }));
+        let brewery = Brewery::new(1);
+        assert_eq!(new_pot.brew(&brewery).err(), Some(vec![String::from("recipe has no Pour")]));
+        assert_eq!(
+            new_pot.brew_pipelined(&brewery, &Pipeline::new().stage_workers("missing", 2)).err(),
+            Some(vec![
+                String::from("recipe has no Pour"),
+                String::from("pipeline sets workers for unknown step missing"),
+            ]),
+        );
+    }
 }
 
@@ -0,0 +1,360 @@
+use crate::brewery::{brew_into, flush_steps_into};
+use crate::branch::validate_branches;
+use crate::context;
+use crate::ingredient::{Ingredient, Steps};
+
+use std::any::Any;
+use std::iter;
+use std::sync::Arc;
+
+///
+/// Steps built once and added to many Pots, such as shared cleaning logic published in a crate.
+///
+/// A Recipe's steps are shared, not copied, by every Pot and Recipe they are added to, so steps
+/// holding Tea back across a brew, such as an Aggregate or Sort, should only be added to one Pot.
+/// `Pot::extend_recipe` adds the steps to the end of a Pot's recipe as they are, while
+/// `Recipe::add_recipe` nests another Recipe as a single step whose steps are recorded in logs
+/// and the `BrewReport` as `recipe/step`.
+pub struct Recipe<T: Send> {
+    pub name: String,
+    pub steps: Vec<Arc<dyn Ingredient<T> + Send + Sync>>,
+}
+
+impl<T: Send + 'static> Recipe<T> {
+    ///
+    /// Creates a Recipe without any steps.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - prefix given to the steps when the Recipe is nested
+    pub fn new(name: &str) -> Recipe<T> {
+        Recipe {
+            name: String::from(name),
+            steps: Vec::new(),
+        }
+    }
+
+    ///
+    /// Adds Ingredient to the end of the Recipe. Steps that cannot be brewed are reported by
+    /// `Pot::validate` once the Recipe is added to a Pot.
+    ///
+    /// # Arguments
+    ///
+    /// * `ingredient` - the ingredient to add to the Recipe
+    pub fn add_ingredient(mut self, ingredient: Box<dyn Ingredient<T> + Send + Sync>) -> Recipe<T> {
+        self.steps.push(Arc::from(ingredient));
+        self
+    }
+
+    ///
+    /// Nests another Recipe as a single step, named after it. A nested Recipe that cannot be
+    /// brewed, such as one without steps, is reported by `Pot::validate`.
+    ///
+    /// # Arguments
+    ///
+    /// * `recipe` - the Recipe to nest
+    pub fn add_recipe(self, recipe: &Recipe<T>) -> Recipe<T> {
+        self.add_ingredient(Box::new(SubRecipe::new(recipe)))
+    }
+
+    ///
+    /// Returns the Recipe's steps, shared with the Recipe, to be added to a recipe.
+    pub fn to_steps(&self) -> Steps<T> {
+        self.steps
+            .iter()
+            .map(|step| Box::new(Arc::clone(step)) as Box<dyn Ingredient<T> + Send + Sync>)
+            .collect()
+    }
+}
+
+impl<T: Send> Clone for Recipe<T> {
+    fn clone(&self) -> Recipe<T> {
+        Recipe {
+            name: self.name.clone(),
+            steps: self.steps.clone(),
+        }
+    }
+}
+
+///
+/// Ingredient used to brew Tea through a Recipe's steps as a single step of another recipe.
+///
+/// Batches passed on by the last of the steps are passed on to the rest of the recipe. Steps are
+/// recorded in the `BrewReport` as `recipe/step`, so the same Recipe can be nested more than once
+/// under different names.
+pub struct SubRecipe<T: Send> {
+    pub name: String,
+    pub steps: Steps<T>,
+}
+
+impl<T: Send + 'static> SubRecipe<T> {
+    ///
+    /// Creates a SubRecipe sharing a Recipe's steps, named after the Recipe.
+    ///
+    /// # Arguments
+    ///
+    /// * `recipe` - the Recipe to brew
+    pub fn new(recipe: &Recipe<T>) -> SubRecipe<T> {
+        SubRecipe {
+            name: recipe.name.clone(),
+            steps: recipe.to_steps(),
+        }
+    }
+
+    ///
+    /// Sets the name the steps are recorded under.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - prefix given to the steps
+    pub fn name(mut self, name: &str) -> SubRecipe<T> {
+        self.name = String::from(name);
+        self
+    }
+}
+
+impl<T: Send + 'static> Ingredient<T> for SubRecipe<T> {
+    fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
+        self.exec_batches(tea_batch).into_iter().flatten().collect()
+    }
+    fn exec_batches(&self, tea_batch: Vec<T>) -> Vec<Vec<T>> {
+        let context = context::current();
+        let mut brewed = Vec::new();
+        context::scoped(&self.name, || {
+            brew_into(0, vec![tea_batch], &self.steps, &context, &mut |tea_batch| brewed.push(tea_batch));
+        });
+        brewed
+    }
+    fn flush_into(&self, send: &mut dyn FnMut(Vec<T>)) {
+        let context = context::current();
+        context::scoped(&self.name, || {
+            flush_steps_into(&self.steps, &context, &mut |tea_batch| {
+                context::unscoped(&self.name, || send(tea_batch));
+            });
+        });
+    }
+    fn get_name(&self) -> &str {
+        &self.name[..]
+    }
+    fn kind(&self) -> &str {
+        "Recipe"
+    }
+    fn sub_recipes(&self) -> Vec<(&str, &Steps<T>)> {
+        vec![(&self.name[..], &self.steps)]
+    }
+    fn print(&self) {
+        println!("Current Step: {}", self.get_name());
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn validate(&self) -> Result<(), String> {
+        if self.steps.is_empty() {
+            return Err(String::from("recipe has no steps"));
+        }
+        validate_branches("recipe", iter::once((&self.name[..], &self.steps)))
+    }
+}
+
+///
+/// Lets a step be shared between recipes, such as the steps of a Recipe added to many Pots.
+impl<T: Send, I: Ingredient<T> + ?Sized> Ingredient<T> for Arc<I> {
+    fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
+        (**self).exec(tea_batch)
+    }
+    fn exec_batches(&self, tea_batch: Vec<T>) -> Vec<Vec<T>> {
+        (**self).exec_batches(tea_batch)
+    }
+    fn flush(&self) -> Vec<Vec<T>> {
+        (**self).flush()
+    }
+    fn flush_into(&self, send: &mut dyn FnMut(Vec<T>)) {
+        (**self).flush_into(send)
+    }
+    fn finish(&self, success: bool) -> Result<(), String> {
+        (**self).finish(success)
+    }
+    fn print(&self) {
+        (**self).print()
+    }
+    fn as_any(&self) -> &dyn Any {
+        (**self).as_any()
+    }
+    fn get_name(&self) -> &str {
+        (**self).get_name()
+    }
+    fn kind(&self) -> &str {
+        (**self).kind()
+    }
+    fn sub_recipes(&self) -> Vec<(&str, &Steps<T>)> {
+        (**self).sub_recipes()
+    }
+    fn validate(&self) -> Result<(), String> {
+        (**self).validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Recipe, SubRecipe};
+    use super::super::aggregate::{Aggregate, Reducer, Reduced};
+    use super::super::brewery::{Brewery, make_tea};
+    use super::super::context::BrewReport;
+    use super::super::ingredient::{Fill, Steep, Pour, FlatMap, Argument};
+    use super::super::pot::Pot;
+    use std::any::Any;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq, Default, Clone)]
+    struct TestTea {
+        id: u32,
+    }
+
+    struct PouredArgs {
+        pub poured: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl Argument for PouredArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct AddArgs {
+        pub amount: u32,
+    }
+
+    impl Argument for AddArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn fill() -> Box<Fill<TestTea>> {
+        Box::new(Fill {
+            name: String::from("tea"),
+            source: String::from("hardcoded"),
+            computation: Box::new(|_args, brewery, recipe| {
+                for x in 0 .. 5 {
+                    let recipe = Arc::clone(&recipe);
+                    let tea_batch = (x * 2 .. x * 2 + 2).map(|id| TestTea { id }).collect();
+                    brewery.take_order(|| {
+                        make_tea(tea_batch, recipe);
+                    });
+                }
+            }),
+            params: None,
+        })
+    }
+
+    fn add(name: &str, amount: u32) -> Box<Steep<TestTea>> {
+        Box::new(Steep {
+            name: String::from(name),
+            computation: Box::new(|tea_batch: Vec<TestTea>, args| {
+                let amount = args.as_ref().unwrap().as_any().downcast_ref::<AddArgs>().unwrap().amount;
+                tea_batch.into_iter().map(|tea| TestTea { id: tea.id + amount }).collect()
+            }),
+            params: Some(Box::new(AddArgs { amount })),
+        })
+    }
+
+    fn pour(poured: &Arc<Mutex<Vec<u32>>>) -> Box<Pour<TestTea>> {
+        Box::new(Pour {
+            name: String::from("pour"),
+            computation: Box::new(|tea_batch, args| {
+                let args = args.as_ref().unwrap().as_any().downcast_ref::<PouredArgs>().unwrap();
+                args.poured.lock().unwrap().extend(tea_batch.iter().map(|tea| tea.id));
+                tea_batch
+            }),
+            params: Some(Box::new(PouredArgs { poured: Arc::clone(poured) })),
+        })
+    }
+
+    fn brew(new_pot: Pot<TestTea>) -> BrewReport {
+        new_pot.brew(&Brewery::new(2)).unwrap()
+    }
+
+    fn sorted(poured: &Arc<Mutex<Vec<u32>>>) -> Vec<u32> {
+        let mut poured = poured.lock().unwrap().clone();
+        poured.sort_unstable();
+        poured
+    }
+
+    #[test]
+    fn recipe_is_added_to_many_pots() {
+        let cleaning = Recipe::new("cleaning")
+            .add_ingredient(add("add_ten", 10))
+            .add_ingredient(add("add_one", 1));
+        let first = Arc::new(Mutex::new(Vec::new()));
+        let second = Arc::new(Mutex::new(Vec::new()));
+
+        let report = brew(Pot::new().add_source(fill()).extend_recipe(&cleaning).add_ingredient(pour(&first)));
+        brew(Pot::new().add_source(fill()).extend_recipe(&cleaning).add_ingredient(pour(&second)));
+
+        assert_eq!(sorted(&first), (11 .. 21).collect::<Vec<u32>>());
+        assert_eq!(sorted(&second), (11 .. 21).collect::<Vec<u32>>());
+        assert_eq!(report.steps["add_ten"].records_in, 10);
+        assert_eq!(cleaning.steps[0].kind(), "Steep");
+    }
+
+    #[test]
+    fn nested_recipe_prefixes_its_steps() {
+        let cleaning = Recipe::new("cleaning").add_ingredient(add("add_ten", 10));
+        let shared = Recipe::new("shared")
+            .add_recipe(&cleaning)
+            .add_ingredient(add("add_one", 1));
+        let poured = Arc::new(Mutex::new(Vec::new()));
+        let report = brew(Pot::new()
+            .add_source(fill())
+            .extend_recipe(&shared)
+            .add_ingredient(Box::new(SubRecipe::new(&cleaning).name("again")))
+            .add_ingredient(pour(&poured)));
+
+        assert_eq!(sorted(&poured), (21 .. 31).collect::<Vec<u32>>());
+        assert_eq!(report.steps["cleaning"].records_out, 10);
+        assert_eq!(report.steps["cleaning/add_ten"].records_in, 10);
+        assert_eq!(report.steps["again/add_ten"].records_in, 10);
+        assert!(!report.steps.contains_key("add_ten"));
+    }
+
+    #[test]
+    fn nested_recipe_passes_on_flushed_tea() {
+        let count = Aggregate::new("count", |_tea: &TestTea| String::from("all"), |_key, reduced| {
+            match reduced[0] {
+                Reduced::Count(count) => TestTea { id: count as u32 },
+                _ => TestTea::default(),
+            }
+        }).reduce(Reducer::Count);
+        let totals = Recipe::new("totals")
+            .add_ingredient(Box::new(count))
+            .add_ingredient(add("add_one", 1));
+        let poured = Arc::new(Mutex::new(Vec::new()));
+        let report = brew(Pot::new()
+            .add_source(fill())
+            .add_ingredient(Box::new(SubRecipe::new(&totals)))
+            .add_ingredient(pour(&poured)));
+
+        assert_eq!(sorted(&poured), vec![11]);
+        assert_eq!(report.steps["totals/add_one"].records_in, 1);
+        assert_eq!(report.steps["pour"].records_in, 1);
+    }
+
+    #[test]
+    fn empty_recipe_cannot_be_nested() {
+        let outer = Recipe::<TestTea>::new("outer").add_recipe(&Recipe::new("empty"));
+        let new_pot = Pot::new().add_source(fill()).extend_recipe(&outer).add_ingredient(pour(&Arc::new(Mutex::new(Vec::new()))));
+        assert_eq!(new_pot.validate(), Err(vec![String::from("step empty: recipe has no steps")]));
+    }
+
+    #[test]
+    fn pot_validates_added_recipe() {
+        let recipe = Recipe::new("cleaning").add_ingredient(Box::new(FlatMap {
+            name: String::from("unbatched"),
+            computation: Box::new(|tea: TestTea, _args| vec![tea]),
+            params: None,
+            batch_size: 0,
+        }));
+        let new_pot = Pot::new().add_source(fill()).extend_recipe(&recipe).add_ingredient(pour(&Arc::new(Mutex::new(Vec::new()))));
+        assert_eq!(new_pot.validate(), Err(vec![String::from("step unbatched: batch_size must be greater than 0")]));
+    }
+}
@@ -0,0 +1,455 @@
+use crate::aggregate::rebatch;
+use crate::context;
+use crate::ingredient::Ingredient;
+
+use std::any::Any;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+///
+/// Ingredient used to brew only the first Tea of a brew, such as for a dry run.
+///
+/// Once `limit` Tea has reached the step, the sources are told to stop, so `Fill` loops checking
+/// `brewery.is_cancelled()` stop collecting Tea without the brew being cancelled. Tea from batches
+/// already sent is rejected. Since every source is stopped, `Pot::validate` only accepts a Limit
+/// in the Pot's recipe, not in a branch or Graph that only some of the Tea reaches.
+pub struct Limit {
+    pub name: String,
+    pub limit: usize,
+    taken: AtomicUsize,
+}
+
+impl Limit {
+    ///
+    /// Creates a Limit letting through the first `limit` Tea.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the step
+    /// * `limit` - number of Tea to brew
+    pub fn new(name: &str, limit: usize) -> Limit {
+        Limit {
+            name: String::from(name),
+            limit,
+            taken: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<T: Send + 'static> Ingredient<T> for Limit {
+    fn exec(&self, mut tea_batch: Vec<T>) -> Vec<T> {
+        let records_in = tea_batch.len();
+        let taken = self.taken.fetch_add(records_in, Ordering::SeqCst);
+        tea_batch.truncate(self.limit.saturating_sub(taken));
+
+        if let Some(context) = context::current() {
+            if taken + records_in >= self.limit {
+                context.stop_sources();
+            }
+            if tea_batch.len() < records_in {
+                context.records_rejected(&self.name, records_in - tea_batch.len());
+            }
+        }
+        tea_batch
+    }
+    fn flush(&self) -> Vec<Vec<T>> {
+        // The next brew lets through its own first Tea.
+        self.taken.store(0, Ordering::SeqCst);
+        Vec::new()
+    }
+    fn get_name(&self) -> &str {
+        &self.name[..]
+    }
+    fn kind(&self) -> &str {
+        "Limit"
+    }
+    fn print(&self) {
+        println!("Current Step: {}", self.name);
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn validate(&self) -> Result<(), String> {
+        if self.limit == 0 {
+            return Err(String::from("limit must be greater than 0"));
+        }
+        Ok(())
+    }
+}
+
+///
+/// How a Sample picks Tea.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleKind {
+    /// Keeps each Tea with the given chance, between 0 and 1, passing it on straight away.
+    Fraction(f64),
+    /// Keeps an even sample of this many Tea from the whole brew, passed on at the end.
+    Reservoir(usize),
+}
+
+///
+/// Tea kept by a reservoir Sample, along with how much Tea it has seen.
+struct Reservoir<T> {
+    seen: u64,
+    tea: Vec<T>,
+}
+
+///
+/// Ingredient used to brew a random sample of the Tea.
+///
+/// Picks are made from a seeded generator, so the same seed picks the same Tea as long as the Tea
+/// reaches the step in the same order, such as with a single Brewer.
+pub struct Sample<T: Send> {
+    pub name: String,
+    pub kind: SampleKind,
+    pub seed: u64,
+    /// Most Tea sent on in one batch by a reservoir Sample.
+    pub batch_size: usize,
+    seen: AtomicU64,
+    reservoir: Mutex<Reservoir<T>>,
+}
+
+impl<T: Send> Sample<T> {
+    ///
+    /// Creates a Sample with a seed of 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the step
+    /// * `kind` - fraction or reservoir sample
+    pub fn new(name: &str, kind: SampleKind) -> Sample<T> {
+        Sample {
+            name: String::from(name),
+            kind,
+            seed: 0,
+            batch_size: 1000,
+            seen: AtomicU64::new(0),
+            reservoir: Mutex::new(Reservoir { seen: 0, tea: Vec::new() }),
+        }
+    }
+
+    ///
+    /// Sets the seed of the generator picking the Tea.
+    ///
+    /// # Arguments
+    ///
+    /// * `seed` - seed of the sample
+    pub fn seed(mut self, seed: u64) -> Sample<T> {
+        self.seed = seed;
+        self
+    }
+
+    ///
+    /// Sets the most Tea sent on in one batch by a reservoir Sample.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch_size` - size of the sampled batches
+    pub fn batch_size(mut self, batch_size: usize) -> Sample<T> {
+        self.batch_size = batch_size;
+        self
+    }
+}
+
+///
+/// Returns the random number for the `index`th Tea seen, using SplitMix64.
+fn random(seed: u64, index: u64) -> u64 {
+    let mut z = seed.wrapping_add(index.wrapping_add(1).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+impl<T: Send + 'static> Ingredient<T> for Sample<T> {
+    fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
+        let records_in = tea_batch.len();
+        match self.kind {
+            SampleKind::Fraction(fraction) => {
+                let first = self.seen.fetch_add(records_in as u64, Ordering::SeqCst);
+                let kept: Vec<T> = tea_batch
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(index, _)| {
+                        // The top 53 bits give an even float in [0, 1).
+                        let chance = (random(self.seed, first + *index as u64) >> 11) as f64 / (1u64 << 53) as f64;
+                        chance < fraction
+                    })
+                    .map(|(_, tea)| tea)
+                    .collect();
+                if kept.len() < records_in {
+                    if let Some(context) = context::current() {
+                        context.records_rejected(&self.name, records_in - kept.len());
+                    }
+                }
+                kept
+            },
+            SampleKind::Reservoir(size) => {
+                let mut reservoir = self.reservoir.lock().unwrap();
+                for tea in tea_batch {
+                    let seen = reservoir.seen;
+                    reservoir.seen += 1;
+                    if reservoir.tea.len() < size {
+                        reservoir.tea.push(tea);
+                        continue;
+                    }
+                    let slot = (random(self.seed, seen) % (seen + 1)) as usize;
+                    if slot < size {
+                        reservoir.tea[slot] = tea;
+                    }
+                }
+                // Nothing is sent on until the brew ends.
+                Vec::new()
+            },
+        }
+    }
+    fn flush(&self) -> Vec<Vec<T>> {
+        // The next brew picks the same Tea from the same input.
+        self.seen.store(0, Ordering::SeqCst);
+        let tea = {
+            let mut reservoir = self.reservoir.lock().unwrap();
+            let seen = std::mem::take(&mut reservoir.seen);
+            let tea = std::mem::take(&mut reservoir.tea);
+            if let Some(context) = context::current() {
+                context.records_rejected(&self.name, seen as usize - tea.len());
+            }
+            tea
+        };
+        rebatch(tea, self.batch_size)
+    }
+    fn get_name(&self) -> &str {
+        &self.name[..]
+    }
+    fn kind(&self) -> &str {
+        "Sample"
+    }
+    fn print(&self) {
+        println!("Current Step: {}", self.get_name());
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn validate(&self) -> Result<(), String> {
+        match self.kind {
+            SampleKind::Fraction(fraction) if !(fraction > 0.0 && fraction <= 1.0) => {
+                Err(format!("sample fraction {} must be greater than 0 and at most 1", fraction))
+            },
+            SampleKind::Reservoir(0) => Err(String::from("reservoir size must be greater than 0")),
+            _ if self.batch_size == 0 => Err(String::from("batch_size must be greater than 0")),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limit, Sample, SampleKind};
+    use super::super::branch::Branch;
+    use super::super::dag::Graph;
+    use super::super::ingredient::{Fill, Pour, Argument, Ingredient};
+    use super::super::brewery::{Brewery, make_tea};
+    use super::super::recipe::{Recipe, SubRecipe};
+    use super::super::pot::Pot;
+    use std::any::Any;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq, Default, Clone)]
+    struct TestTea {
+        id: u32,
+    }
+
+    fn batch(ids: std::ops::Range<u32>) -> Vec<TestTea> {
+        ids.map(|id| TestTea { id }).collect()
+    }
+
+    fn ids(tea: Vec<TestTea>) -> Vec<u32> {
+        tea.into_iter().map(|tea| tea.id).collect()
+    }
+
+    #[test]
+    fn limit_lets_through_first_tea() {
+        let limit = Limit::new("limit", 5);
+        assert_eq!(ids(limit.exec(batch(0 .. 3))), vec![0, 1, 2]);
+        assert_eq!(ids(limit.exec(batch(3 .. 6))), vec![3, 4]);
+        assert!(limit.exec(batch(6 .. 9)).is_empty());
+        assert!(Ingredient::<TestTea>::flush(&limit).is_empty());
+        assert_eq!(ids(limit.exec(batch(6 .. 9))), vec![6, 7, 8]);
+    }
+
+    #[test]
+    fn fraction_sample_is_repeatable() {
+        let sample = |seed| {
+            let sample = Sample::new("sample", SampleKind::Fraction(0.1)).seed(seed);
+            (0 .. 100).flat_map(|x| ids(sample.exec(batch(x * 100 .. (x + 1) * 100)))).collect::<Vec<u32>>()
+        };
+        let picked = sample(7);
+        assert!(picked.len() > 900 && picked.len() < 1100, "picked {}", picked.len());
+        assert_eq!(picked, sample(7));
+        assert_ne!(picked, sample(8));
+
+        let sample = Sample::new("sample", SampleKind::Fraction(0.5)).seed(7);
+        let first = ids(sample.exec(batch(0 .. 100)));
+        assert!(sample.flush().is_empty());
+        assert_eq!(ids(sample.exec(batch(0 .. 100))), first);
+    }
+
+    #[test]
+    fn reservoir_sample_keeps_even_sample() {
+        let sample = |seed| {
+            let sample = Sample::new("sample", SampleKind::Reservoir(100)).seed(seed).batch_size(40);
+            for x in 0 .. 100 {
+                assert!(sample.exec(batch(x * 100 .. (x + 1) * 100)).is_empty());
+            }
+            sample.flush()
+        };
+        let tea_batches = sample(7);
+        assert_eq!(tea_batches.iter().map(|tea_batch| tea_batch.len()).collect::<Vec<usize>>(), vec![40, 40, 20]);
+        let picked: Vec<u32> = tea_batches.into_iter().flat_map(ids).collect();
+        // An even sample of 0..10000 has some Tea from each half.
+        assert!(picked.iter().filter(|id| **id < 5000).count() > 25);
+        assert!(picked.iter().filter(|id| **id >= 5000).count() > 25);
+        assert_eq!(picked, sample(7).into_iter().flat_map(ids).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn sample_validates_settings() {
+        let sample: Sample<TestTea> = Sample::new("sample", SampleKind::Fraction(0.0));
+        assert!(sample.validate().is_err());
+        let sample: Sample<TestTea> = Sample::new("sample", SampleKind::Fraction(1.5));
+        assert!(sample.validate().is_err());
+        let sample: Sample<TestTea> = Sample::new("sample", SampleKind::Reservoir(0));
+        assert_eq!(sample.validate(), Err(String::from("reservoir size must be greater than 0")));
+        let sample: Sample<TestTea> = Sample::new("sample", SampleKind::Reservoir(10)).batch_size(0);
+        assert_eq!(sample.validate(), Err(String::from("batch_size must be greater than 0")));
+        let sample: Sample<TestTea> = Sample::new("sample", SampleKind::Fraction(1.0));
+        assert_eq!(sample.validate(), Ok(()));
+        assert_eq!(Ingredient::<TestTea>::validate(&Limit::new("limit", 0)), Err(String::from("limit must be greater than 0")));
+    }
+
+    struct FillArgs {
+        pub read: Arc<AtomicUsize>,
+    }
+
+    impl Argument for FillArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct PouredArgs {
+        pub poured: Arc<Mutex<Vec<TestTea>>>,
+    }
+
+    impl Argument for PouredArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn limit_stops_sources_early() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let poured = Arc::new(Mutex::new(Vec::new()));
+        let new_pot = Pot::new()
+            .add_source(Box::new(Fill {
+                name: String::from("endless"),
+                source: String::from("hardcoded"),
+                computation: Box::new(|args, brewery, recipe| {
+                    let read = &args.as_ref().unwrap().as_any().downcast_ref::<FillArgs>().unwrap().read;
+                    for x in 0 .. 1000 {
+                        if brewery.is_cancelled() {
+                            break;
+                        }
+                        read.fetch_add(1, Ordering::SeqCst);
+                        let recipe = Arc::clone(&recipe);
+                        let tea_batch = batch(x * 10 .. (x + 1) * 10);
+                        brewery.take_order(|| {
+                            make_tea(tea_batch, recipe);
+                        });
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                }),
+                params: Some(Box::new(FillArgs { read: Arc::clone(&read) })),
+            }))
+            .add_source(Box::new(Fill {
+                name: String::from("skipped"),
+                source: String::from("hardcoded"),
+                computation: Box::new(|_args, _brewery, _recipe| panic!("source should be skipped")),
+                params: None,
+            }))
+            .add_ingredient(Box::new(Limit::new("limit", 25)))
+            .add_ingredient(Box::new(Pour {
+                name: String::from("pour_limited"),
+                computation: Box::new(|tea_batch: Vec<TestTea>, args| {
+                    let args = args.as_ref().unwrap().as_any().downcast_ref::<PouredArgs>().unwrap();
+                    args.poured.lock().unwrap().extend(tea_batch.iter().cloned());
+                    tea_batch
+                }),
+                params: Some(Box::new(PouredArgs { poured: Arc::clone(&poured) })),
+            }));
+        let brewery = Brewery::new(1);
+        let report = new_pot.brew(&brewery).unwrap();
+
+        assert_eq!(ids(poured.lock().unwrap().clone()), (0 .. 25).collect::<Vec<u32>>());
+        assert!(read.load(Ordering::SeqCst) < 100, "read {} batches", read.load(Ordering::SeqCst));
+        assert_eq!(report.cancelled, None);
+        assert_eq!(report.sources_skipped, 0);
+        assert!(report.limit_reached);
+        assert!(report.is_complete());
+        assert_eq!(report.steps["limit"].records_out, 25);
+
+        poured.lock().unwrap().clear();
+        let report = new_pot.brew(&brewery).unwrap();
+        assert_eq!(ids(poured.lock().unwrap().clone()), (0 .. 25).collect::<Vec<u32>>());
+        assert!(report.limit_reached);
+    }
+
+    fn fill(name: &str) -> Box<Fill<TestTea>> {
+        Box::new(Fill {
+            name: String::from(name),
+            source: String::from("hardcoded"),
+            computation: Box::new(|_args, brewery, recipe| {
+                brewery.take_order(move || make_tea(batch(0 .. 10), recipe));
+            }),
+            params: None,
+        })
+    }
+
+    fn pour(name: &str) -> Box<Pour<TestTea>> {
+        Box::new(Pour {
+            name: String::from(name),
+            computation: Box::new(|tea_batch: Vec<TestTea>, _args| tea_batch),
+            params: None,
+        })
+    }
+
+    #[test]
+    fn limit_off_the_recipe_path_is_invalid() {
+        let branched = Pot::new()
+            .add_source(fill("fill"))
+            .add_ingredient(Box::new(Branch::new("split")
+                .branch("sampled", vec![Box::new(Limit::new("first", 5)), pour("sample_pour")])))
+            .add_ingredient(pour("pour"));
+        assert_eq!(branched.validate(), Err(vec![
+            String::from("step sampled/first: Limit stops every source, so it must be in the recipe itself"),
+        ]));
+
+        let graph = Graph::new()
+            .source(fill("graph_fill"), "first")
+            .node(Box::new(Limit::new("first", 5)))
+            .node(pour("graph_pour"))
+            .edge("first", "graph_pour");
+        let graphed = Pot::new().add_graph(graph);
+        assert_eq!(graphed.validate(), Err(vec![
+            String::from("step first: Limit stops every source, so it must be in the recipe itself"),
+        ]));
+
+        let nested = Pot::new()
+            .add_source(fill("fill"))
+            .add_ingredient(Box::new(SubRecipe::new(&Recipe::new("dry_run").add_ingredient(Box::new(Limit::new("first", 5))))))
+            .add_ingredient(pour("pour"));
+        assert_eq!(nested.validate(), Ok(()));
+    }
+}
@@ -0,0 +1,282 @@
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// Counter used to give every Pot its own tenant by default.
+static NEXT_TENANT: AtomicUsize = AtomicUsize::new(0);
+
+/// Pass added to a tenant each time it is served, divided by the tenant's weight.
+const STRIDE: u64 = 1 << 20;
+
+///
+/// Scheduling information carried by every Order sent to a Brewery.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ticket {
+    /// Orders with a higher priority are brewed before any with a lower one.
+    pub priority: u8,
+    /// Pot or tenant the Order belongs to, shared fairly with other tenants of the same priority.
+    pub tenant: String,
+}
+
+impl Ticket {
+    ///
+    /// Creates a Ticket for the given tenant and priority.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - pot or tenant id the order belongs to
+    /// * `priority` - higher priorities are brewed first
+    pub fn new(tenant: &str, priority: u8) -> Ticket {
+        Ticket { priority, tenant: String::from(tenant) }
+    }
+
+    ///
+    /// Creates a Ticket for a tenant id no other Pot uses.
+    pub(crate) fn unique() -> Ticket {
+        Ticket::new(&format!("pot-{}", NEXT_TENANT.fetch_add(1, Ordering::SeqCst)), 0)
+    }
+}
+
+impl Default for Ticket {
+    fn default() -> Self {
+        Ticket::new("", 0)
+    }
+}
+
+///
+/// Channel queuing one tenant's Orders at one priority.
+struct TenantQueue<O> {
+    priority: u8,
+    tenant: String,
+    weight: AtomicUsize,
+    pass: AtomicU64,
+    sender: Sender<O>,
+    receiver: Receiver<O>,
+}
+
+///
+/// Queues releasing Orders by priority, then weighted fairly between tenants.
+///
+/// Tenants are served by stride scheduling: each tenant advances its pass every time it is
+/// served, by less the heavier it is weighted, and the tenant with the lowest pass goes next.
+/// A tenant flooding the Brewery therefore cannot starve the others. Each tenant's Orders sit
+/// in their own channel. Queuing an Order takes a read lock on the list of queues, or the write
+/// lock to add a queue for a new tenant. Brewers keep their own View of the list, so they only
+/// take the read lock to refresh it after a queue was added.
+pub(crate) struct Scheduler<O> {
+    queues: RwLock<Arc<Vec<Arc<TenantQueue<O>>>>>,
+    version: AtomicUsize,
+    weights: RwLock<HashMap<String, usize>>,
+    clock: AtomicU64,
+}
+
+///
+/// Copy of the Scheduler's queues held by one Brewer, refreshed when a queue is added.
+pub(crate) struct View<O> {
+    version: usize,
+    queues: Arc<Vec<Arc<TenantQueue<O>>>>,
+}
+
+impl<O> View<O> {
+    pub(crate) fn new() -> View<O> {
+        View { version: usize::MAX, queues: Arc::new(Vec::new()) }
+    }
+}
+
+impl<O> Scheduler<O> {
+    pub(crate) fn new() -> Scheduler<O> {
+        Scheduler {
+            queues: RwLock::new(Arc::new(Vec::new())),
+            version: AtomicUsize::new(0),
+            weights: RwLock::new(HashMap::new()),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    ///
+    /// Sets the share of Orders a tenant receives relative to the others.
+    pub(crate) fn set_weight(&self, tenant: &str, weight: usize) {
+        self.weights.write().unwrap().insert(String::from(tenant), weight);
+        for queue in self.queues.read().unwrap().iter().filter(|queue| queue.tenant == tenant) {
+            queue.weight.store(weight, Ordering::SeqCst);
+        }
+    }
+
+    ///
+    /// Queues an Order under its Ticket.
+    pub(crate) fn push(&self, ticket: &Ticket, order: O) {
+        // Orders are sent while the queues are locked, so a queue cannot be dropped as idle
+        // between being looked up and receiving the Order.
+        let queues = self.queues.read().unwrap();
+        if let Some(queue) = queues.iter().find(|queue| queue.serves(ticket)) {
+            self.send(queue, order);
+            return;
+        }
+        drop(queues);
+
+        let mut queues = self.queues.write().unwrap();
+        if let Some(queue) = queues.iter().find(|queue| queue.serves(ticket)) {
+            self.send(queue, order);
+            return;
+        }
+        let queue = self.add_queue(&mut queues, ticket);
+        self.send(&queue, order);
+    }
+
+    ///
+    /// Sends an Order to a tenant's queue.
+    fn send(&self, queue: &TenantQueue<O>, order: O) {
+        // A tenant returning from idle starts level with the others instead of cashing in the
+        // turns it did not need.
+        if queue.receiver.is_empty() {
+            queue.pass.fetch_max(self.clock.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        queue.sender.send(order).unwrap();
+    }
+
+    ///
+    /// Releases the next Order, if any are queued.
+    ///
+    /// # Arguments
+    ///
+    /// * `view` - the caller's copy of the queues, refreshed if out of date
+    pub(crate) fn pop(&self, view: &mut View<O>) -> Option<O> {
+        let version = self.version.load(Ordering::Acquire);
+        if view.version != version {
+            view.queues = Arc::clone(&self.queues.read().unwrap());
+            view.version = version;
+        }
+
+        let queues = &view.queues;
+        loop {
+            // Highest priority first, then the lowest pass within it.
+            let next = queues.iter()
+                .filter(|queue| !queue.receiver.is_empty())
+                .min_by_key(|queue| (u8::MAX - queue.priority, queue.pass.load(Ordering::Relaxed)))?;
+
+            // Another Brewer may have emptied the queue since it was picked.
+            if let Ok(order) = next.receiver.try_recv() {
+                let weight = next.weight.load(Ordering::Relaxed).max(1) as u64;
+                let pass = next.pass.fetch_add(STRIDE / weight, Ordering::Relaxed);
+                // The clock only needs to roughly track the pass being served.
+                if pass > self.clock.load(Ordering::Relaxed) {
+                    self.clock.store(pass, Ordering::Relaxed);
+                }
+                return Some(order);
+            }
+        }
+    }
+
+    ///
+    /// Adds a queue for a Ticket, dropping the queues of idle tenants so tenants that have
+    /// finished, such as Pots that were dropped, are not scanned for Orders forever. A queue is
+    /// only dropped once empty and no further ahead than a new queue would start, so a tenant
+    /// coming back loses nothing.
+    fn add_queue(&self, queues: &mut Arc<Vec<Arc<TenantQueue<O>>>>, ticket: &Ticket) -> Arc<TenantQueue<O>> {
+        let clock = self.clock.load(Ordering::Relaxed);
+        let weight = self.weights.read().unwrap().get(&ticket.tenant).cloned().unwrap_or(1);
+        let (sender, receiver) = unbounded();
+        let queue = Arc::new(TenantQueue {
+            priority: ticket.priority,
+            tenant: ticket.tenant.clone(),
+            weight: AtomicUsize::new(weight),
+            pass: AtomicU64::new(clock),
+            sender,
+            receiver,
+        });
+        let mut updated: Vec<Arc<TenantQueue<O>>> = queues.iter()
+            .filter(|queue| !queue.receiver.is_empty() || queue.pass.load(Ordering::Relaxed) > clock)
+            .cloned()
+            .collect();
+        updated.push(Arc::clone(&queue));
+        *queues = Arc::new(updated);
+        self.version.fetch_add(1, Ordering::Release);
+        queue
+    }
+
+    #[cfg(test)]
+    fn tenants(&self) -> usize {
+        self.queues.read().unwrap().len()
+    }
+}
+
+impl<O> TenantQueue<O> {
+    fn serves(&self, ticket: &Ticket) -> bool {
+        self.priority == ticket.priority && self.tenant == ticket.tenant
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Scheduler, Ticket, View};
+
+    fn drain(scheduler: &Scheduler<&'static str>) -> Vec<&'static str> {
+        let mut view = View::new();
+        let mut orders = Vec::new();
+        while let Some(order) = scheduler.pop(&mut view) {
+            orders.push(order);
+        }
+        orders
+    }
+
+    #[test]
+    fn tenants_take_turns() {
+        let scheduler = Scheduler::new();
+        for _ in 0 .. 3 {
+            scheduler.push(&Ticket::new("backfill", 0), "b");
+        }
+        scheduler.push(&Ticket::new("small", 0), "s");
+        assert_eq!(drain(&scheduler), vec!["b", "s", "b", "b"]);
+        assert_eq!(scheduler.pop(&mut View::new()), None);
+    }
+
+    #[test]
+    fn higher_priority_goes_first() {
+        let scheduler = Scheduler::new();
+        scheduler.push(&Ticket::new("backfill", 0), "b");
+        scheduler.push(&Ticket::new("urgent", 9), "u");
+        scheduler.push(&Ticket::new("backfill", 0), "b");
+        assert_eq!(drain(&scheduler), vec!["u", "b", "b"]);
+    }
+
+    #[test]
+    fn weights_share_orders() {
+        let scheduler = Scheduler::new();
+        scheduler.set_weight("heavy", 3);
+        for _ in 0 .. 6 {
+            scheduler.push(&Ticket::new("heavy", 0), "h");
+        }
+        for _ in 0 .. 2 {
+            scheduler.push(&Ticket::new("light", 0), "l");
+        }
+        assert_eq!(drain(&scheduler), vec!["h", "l", "h", "h", "h", "l", "h", "h"]);
+    }
+
+    #[test]
+    fn idle_tenant_does_not_bank_turns() {
+        let scheduler = Scheduler::new();
+        for _ in 0 .. 4 {
+            scheduler.push(&Ticket::new("busy", 0), "b");
+        }
+        assert_eq!(drain(&scheduler).len(), 4);
+
+        scheduler.push(&Ticket::new("busy", 0), "b");
+        scheduler.push(&Ticket::new("busy", 0), "b");
+        scheduler.push(&Ticket::new("late", 0), "l");
+        scheduler.push(&Ticket::new("late", 0), "l");
+        assert_eq!(drain(&scheduler), vec!["l", "b", "l", "b"]);
+    }
+
+    #[test]
+    fn idle_tenants_are_dropped() {
+        let scheduler = Scheduler::new();
+        for _ in 0 .. 100 {
+            let ticket = Ticket::unique();
+            scheduler.push(&ticket, "o");
+            scheduler.push(&ticket, "o");
+            assert_eq!(drain(&scheduler).len(), 2);
+        }
+        assert!(scheduler.tenants() <= 2);
+    }
+}
@@ -0,0 +1,463 @@
+use crate::context;
+use crate::ingredient::Ingredient;
+
+use serde::Serialize;
+use std::any::Any;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+///
+/// Formats a Tea as the line written by a FileSink, or returns why it cannot be written.
+pub type SinkFormat<T> = Box<dyn Fn(&T) -> Result<String, String> + Send + Sync>;
+
+///
+/// Output written in phases, so consumers never see half of it. The output is begun before the
+/// first batch and each batch is written to it, but it is only made visible on commit, once the
+/// whole brew has succeeded. A failed or cancelled brew aborts it instead. Add a Sink to a recipe
+/// with `SinkPour`.
+pub trait Sink<T> {
+    ///
+    /// Starts a new output, such as by creating a temporary file.
+    fn begin(&self) -> Result<(), String>;
+
+    ///
+    /// Writes a batch of Tea to the output begun.
+    ///
+    /// # Arguments
+    ///
+    /// * `tea_batch` - Tea to write
+    fn write(&self, tea_batch: &[T]) -> Result<(), String>;
+
+    ///
+    /// Makes the output visible to consumers, such as by renaming the temporary file over the
+    /// real one. Committing the same output twice must not duplicate it.
+    fn commit(&self) -> Result<(), String>;
+
+    ///
+    /// Throws away the output begun, leaving any output committed before in place.
+    fn abort(&self);
+}
+
+///
+/// Phase of the output a SinkPour is writing in the current brew.
+enum Phase {
+    /// No output has been begun.
+    Idle,
+    /// The output has been begun and every batch written.
+    Writing,
+    /// The output could not be begun or written, so it will be aborted.
+    Failed,
+}
+
+///
+/// Ingredient used to write Tea to a Sink, committing the output once the brew succeeds and
+/// aborting it otherwise. Tea is passed on unchanged, and the SinkPour counts as the recipe's Pour.
+pub struct SinkPour<T: Send> {
+    pub name: String,
+    pub sink: Box<dyn Sink<T> + Send + Sync>,
+    phase: Mutex<Phase>,
+}
+
+impl<T: Send> SinkPour<T> {
+    ///
+    /// Creates a SinkPour writing to a Sink.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the step
+    /// * `sink` - where the Tea is written
+    pub fn new(name: &str, sink: Box<dyn Sink<T> + Send + Sync>) -> SinkPour<T> {
+        SinkPour {
+            name: String::from(name),
+            sink,
+            phase: Mutex::new(Phase::Idle),
+        }
+    }
+
+    ///
+    /// Records a failure in the brew's errors.
+    fn failed(&self, reason: String) {
+        match context::current() {
+            Some(context) => context.step_failed(&self.name, reason),
+            None => println!("Error: step {} failed: {}", self.name, reason),
+        }
+    }
+}
+
+impl<T: Send + 'static> Ingredient<T> for SinkPour<T> {
+    fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
+        let mut phase = self.phase.lock().unwrap();
+        let written = match *phase {
+            Phase::Idle => self.sink.begin().and_then(|()| self.sink.write(&tea_batch)),
+            Phase::Writing => self.sink.write(&tea_batch),
+            Phase::Failed => return tea_batch,
+        };
+        match written {
+            Ok(()) => *phase = Phase::Writing,
+            Err(reason) => {
+                *phase = Phase::Failed;
+                self.failed(reason);
+            },
+        }
+        tea_batch
+    }
+    fn finish(&self, success: bool) -> Result<(), String> {
+        let phase = mem::replace(&mut *self.phase.lock().unwrap(), Phase::Idle);
+        let committed = match (phase, success) {
+            (Phase::Writing, true) => self.sink.commit(),
+            (Phase::Idle, true) => self.sink.begin().and_then(|()| self.sink.commit()),
+            (Phase::Idle, false) => Ok(()),
+            (_, _) => {
+                self.sink.abort();
+                Ok(())
+            },
+        };
+        committed.inspect_err(|_| self.sink.abort())
+    }
+    fn get_name(&self) -> &str {
+        &self.name[..]
+    }
+    fn kind(&self) -> &str {
+        "Pour"
+    }
+    fn print(&self) {
+        println!("Current Step: {}", self.get_name());
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+///
+/// Sink writing a line per Tea to a file. Lines are written to a temporary file next to it, which
+/// is renamed over the file on commit, so the file only ever holds the output of a whole brew.
+/// Temporary files left behind by a crashed brew are removed when the next brew begins, unless
+/// the process that wrote them is still running.
+pub struct FileSink<T> {
+    pub path: PathBuf,
+    pub format: SinkFormat<T>,
+    file: Mutex<Option<BufWriter<File>>>,
+}
+
+impl<T> FileSink<T> {
+    ///
+    /// Creates a FileSink writing each Tea with a format.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - file the output is committed to
+    /// * `format` - line written for each Tea
+    pub fn new<P, F>(path: P, format: F) -> FileSink<T>
+        where P: Into<PathBuf>, F: Fn(&T) -> Result<String, String> + Send + Sync + 'static
+    {
+        FileSink {
+            path: path.into(),
+            format: Box::new(format),
+            file: Mutex::new(None),
+        }
+    }
+
+    ///
+    /// Creates a FileSink writing each Tea as a line of JSON.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - file the output is committed to
+    pub fn json_lines<P: Into<PathBuf>>(path: P) -> FileSink<T> where T: Serialize {
+        FileSink::new(path, |tea| serde_json::to_string(tea).map_err(|error| error.to_string()))
+    }
+
+    ///
+    /// Returns the temporary file the output is written to until it is committed. It is kept in
+    /// the same directory as the file, so renaming it over the file is atomic.
+    pub fn temp_path(&self) -> PathBuf {
+        self.path.with_file_name(format!(".{}.{}.tmp", self.file_name(), std::process::id()))
+    }
+
+    fn file_name(&self) -> String {
+        self.path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+    }
+
+    fn dir(&self) -> &Path {
+        match self.path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        }
+    }
+
+    ///
+    /// Removes temporary files left next to the file by earlier brews that crashed before
+    /// committing or aborting. Files of processes still running, which may be brewing to the
+    /// same file, are left alone.
+    fn remove_stale(&self) {
+        let prefix = format!(".{}.", self.file_name());
+        let entries = match fs::read_dir(self.dir()) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let stale = name.strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix(".tmp"))
+                .and_then(|pid| pid.parse::<u32>().ok())
+                .is_some_and(|pid| !is_running(pid));
+            if stale {
+                if let Err(error) = fs::remove_file(entry.path()) {
+                    println!("Error: {} could not be removed: {}", entry.path().display(), error);
+                }
+            }
+        }
+    }
+
+    ///
+    /// Describes an error writing the file.
+    fn error(&self, error: io::Error) -> String {
+        format!("{} cannot be written: {}", self.path.display(), error)
+    }
+}
+
+impl<T> Sink<T> for FileSink<T> {
+    fn begin(&self) -> Result<(), String> {
+        self.remove_stale();
+        let file = File::create(self.temp_path()).map_err(|error| self.error(error))?;
+        *self.file.lock().unwrap() = Some(BufWriter::new(file));
+        Ok(())
+    }
+    fn write(&self, tea_batch: &[T]) -> Result<(), String> {
+        let mut file = self.file.lock().unwrap();
+        let file = file.as_mut().ok_or_else(|| format!("{} was not begun", self.path.display()))?;
+        for tea in tea_batch {
+            let line = (self.format)(tea)?;
+            writeln!(file, "{}", line).map_err(|error| self.error(error))?;
+        }
+        Ok(())
+    }
+    fn commit(&self) -> Result<(), String> {
+        let file = self.file.lock().unwrap().take();
+        let file = file.ok_or_else(|| format!("{} was not begun", self.path.display()))?;
+        let file = file.into_inner().map_err(|error| self.error(error.into_error()))?;
+        file.sync_all().map_err(|error| self.error(error))?;
+        fs::rename(self.temp_path(), &self.path).map_err(|error| self.error(error))?;
+        // The rename is only durable once the directory holding the file is synced too.
+        File::open(self.dir()).and_then(|dir| dir.sync_all()).map_err(|error| self.error(error))
+    }
+    fn abort(&self) {
+        self.file.lock().unwrap().take();
+        if let Err(error) = fs::remove_file(self.temp_path()) {
+            if error.kind() != io::ErrorKind::NotFound {
+                println!("Error: {} could not be removed: {}", self.temp_path().display(), error);
+            }
+        }
+    }
+}
+
+///
+/// Returns true if a process is running, or if that cannot be told on this platform.
+fn is_running(pid: u32) -> bool {
+    if cfg!(target_os = "linux") {
+        Path::new("/proc").join(pid.to_string()).exists()
+    } else {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sink, SinkPour, FileSink};
+    use super::super::brewery::{Brewery, make_tea};
+    use super::super::cancel::{CancelToken, CancelMode};
+    use super::super::context::BrewError;
+    use super::super::ingredient::{Fill, Steep, Filter, Argument};
+    use super::super::pot::Pot;
+    use super::super::sample::Limit;
+    use serde::Serialize;
+    use std::any::Any;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq, Default, Clone, Serialize)]
+    struct TestTea {
+        id: u32,
+    }
+
+    struct CancelArgs {
+        pub token: CancelToken,
+        pub at: u32,
+    }
+
+    impl Argument for CancelArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    ///
+    /// Sink recording each phase it is called in, failing to write the given id.
+    struct RecordingSink {
+        phases: Arc<Mutex<Vec<String>>>,
+        fail_on: u32,
+    }
+
+    impl Sink<TestTea> for RecordingSink {
+        fn begin(&self) -> Result<(), String> {
+            self.phases.lock().unwrap().push(String::from("begin"));
+            Ok(())
+        }
+        fn write(&self, tea_batch: &[TestTea]) -> Result<(), String> {
+            self.phases.lock().unwrap().push(String::from("write"));
+            if tea_batch.iter().any(|tea| tea.id == self.fail_on) {
+                return Err(String::from("disk full"));
+            }
+            Ok(())
+        }
+        fn commit(&self) -> Result<(), String> {
+            self.phases.lock().unwrap().push(String::from("commit"));
+            Ok(())
+        }
+        fn abort(&self) {
+            self.phases.lock().unwrap().push(String::from("abort"));
+        }
+    }
+
+    fn output_dir(test: &str) -> PathBuf {
+        let output_dir = env::temp_dir().join(format!("rettle_sink_{}_{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&output_dir);
+        fs::create_dir_all(&output_dir).unwrap();
+        output_dir
+    }
+
+    /// Source sending ids 0 to 5 in batches of 2.
+    fn ids_fill(name: &str) -> Box<Fill<TestTea>> {
+        Box::new(Fill {
+            name: String::from(name),
+            source: String::from("hardcoded"),
+            computation: Box::new(|_args, brewery, recipe| {
+                for id in (0 .. 6).step_by(2) {
+                    let recipe = Arc::clone(&recipe);
+                    brewery.take_order(move || {
+                        make_tea(vec![TestTea { id }, TestTea { id: id + 1 }], recipe);
+                    });
+                }
+            }),
+            params: None,
+        })
+    }
+
+    /// Pot sending ids 0 to 5, cancelling the brew immediately when it reaches the given id, then
+    /// writing them to a Sink.
+    fn sink_pot(sink: Box<dyn Sink<TestTea> + Send + Sync>, token: &CancelToken, cancel_at: u32) -> Pot<TestTea> {
+        Pot::new()
+            .add_source(ids_fill("ids"))
+            .add_ingredient(Box::new(Steep {
+                name: String::from("crash"),
+                computation: Box::new(|tea_batch: Vec<TestTea>, args| {
+                    let args = args.as_ref().unwrap().as_any().downcast_ref::<CancelArgs>().unwrap();
+                    if tea_batch[0].id == args.at {
+                        args.token.cancel(CancelMode::Immediate);
+                    }
+                    tea_batch
+                }),
+                params: Some(Box::new(CancelArgs { token: token.clone(), at: cancel_at })),
+            }))
+            .add_ingredient(Box::new(SinkPour::new("sink", sink)))
+    }
+
+    #[test]
+    fn file_sink_commits_whole_brew() {
+        let output_dir = output_dir("commit");
+        let path = output_dir.join("ids.jsonl");
+        // Left behind by a crashed brew of this sink, being written by a running brew of it, and
+        // left by another sink.
+        fs::write(output_dir.join(".ids.jsonl.999999999.tmp"), "stale").unwrap();
+        fs::write(output_dir.join(".ids.jsonl.1.tmp"), "running").unwrap();
+        fs::write(output_dir.join(".ids.jsonl.old.1.tmp"), "other").unwrap();
+        let token = CancelToken::new();
+        let report = sink_pot(Box::new(FileSink::json_lines(&path)), &token, 100).brew(&Brewery::new(1)).unwrap();
+
+        assert!(report.is_successful());
+        let mut lines: Vec<String> = fs::read_to_string(&path).unwrap().lines().map(String::from).collect();
+        lines.sort();
+        assert_eq!(lines, (0 .. 6).map(|id| format!("{{\"id\":{}}}", id)).collect::<Vec<String>>());
+        assert!(output_dir.join(".ids.jsonl.1.tmp").exists());
+        assert!(output_dir.join(".ids.jsonl.old.1.tmp").exists());
+        if cfg!(target_os = "linux") {
+            assert!(!output_dir.join(".ids.jsonl.999999999.tmp").exists());
+        }
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn limited_brew_commits_output() {
+        let output_dir = output_dir("limit");
+        let path = output_dir.join("ids.jsonl");
+        let new_pot = Pot::new()
+            .add_source(ids_fill("ids"))
+            .add_source(ids_fill("more_ids"))
+            .add_ingredient(Box::new(Limit::new("dry_run", 3)))
+            .add_ingredient(Box::new(SinkPour::new("sink", Box::new(FileSink::json_lines(&path)))));
+        let report = new_pot.brew(&Brewery::new(1)).unwrap();
+
+        assert!(report.is_successful());
+        assert_eq!((report.orders_dropped, report.sources_skipped), (0, 0));
+        let mut lines: Vec<String> = fs::read_to_string(&path).unwrap().lines().map(String::from).collect();
+        lines.sort();
+        assert_eq!(lines, (0 .. 3).map(|id| format!("{{\"id\":{}}}", id)).collect::<Vec<String>>());
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn rejected_side_sink_commits_output() {
+        let output_dir = output_dir("rejected");
+        let rejected = output_dir.join("odd.jsonl");
+        let new_pot = Pot::new()
+            .add_source(ids_fill("ids"))
+            .add_ingredient(Box::new(Filter {
+                name: String::from("evens"),
+                predicate: Box::new(|tea: &TestTea, _args| tea.id.is_multiple_of(2)),
+                params: None,
+                rejected: vec![Box::new(SinkPour::new("odd_sink", Box::new(FileSink::json_lines(&rejected))))],
+            }))
+            .add_ingredient(Box::new(SinkPour::new("even_sink", Box::new(FileSink::json_lines(output_dir.join("even.jsonl"))))));
+        assert!(new_pot.to_dot().contains("odd_sink"));
+        let report = new_pot.brew(&Brewery::new(1)).unwrap();
+
+        assert!(report.is_successful());
+        assert_eq!(report.steps["evens/odd_sink"].records_in, 3);
+        let mut lines: Vec<String> = fs::read_to_string(&rejected).unwrap().lines().map(String::from).collect();
+        lines.sort();
+        assert_eq!(lines, vec!["{\"id\":1}", "{\"id\":3}", "{\"id\":5}"]);
+        assert_eq!(fs::read_dir(&output_dir).unwrap().count(), 2);
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn cancelled_brew_keeps_last_output() {
+        let output_dir = output_dir("cancel");
+        let path = output_dir.join("ids.jsonl");
+        fs::write(&path, "{\"id\":42}\n").unwrap();
+        let token = CancelToken::new();
+        let report = sink_pot(Box::new(FileSink::json_lines(&path)), &token, 2).brew_with_token(&Brewery::new(1), &token).unwrap();
+
+        assert!(!report.is_successful());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\"id\":42}\n");
+        assert_eq!(fs::read_dir(&output_dir).unwrap().count(), 1);
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn failed_write_aborts_output() {
+        let phases = Arc::new(Mutex::new(Vec::new()));
+        let sink = RecordingSink { phases: Arc::clone(&phases), fail_on: 2 };
+        let token = CancelToken::new();
+        let report = sink_pot(Box::new(sink), &token, 100).brew(&Brewery::new(1)).unwrap();
+
+        assert!(report.is_complete());
+        assert_eq!(report.errors, vec![BrewError::StepFailed { step: String::from("sink"), reason: String::from("disk full") }]);
+        assert_eq!(*phases.lock().unwrap(), vec!["begin", "write", "write", "abort"]);
+    }
+}
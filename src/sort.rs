@@ -0,0 +1,470 @@
+use crate::context;
+use crate::ingredient::Ingredient;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::any::Any;
+use std::cmp::Ordering;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Lines, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Mutex;
+
+/// Counter keeping the run files of every Sort in the process apart.
+static NEXT_RUN: AtomicUsize = AtomicUsize::new(0);
+
+///
+/// Order Tea is sorted in.
+pub type Comparator<T> = Box<dyn Fn(&T, &T) -> Ordering + Send + Sync>;
+
+///
+/// Tea held by a Sort until the brew ends.
+struct Sorting<T> {
+    buffer: Vec<T>,
+    runs: Vec<PathBuf>,
+}
+
+///
+/// Ingredient used to sort all the Tea of a brew.
+///
+/// Tea is held back until every batch has been brewed, then sent on in order in batches of
+/// `batch_size`. Once more than `memory_budget` Tea is held in memory, it is sorted and spilled
+/// to a temporary run file, and the runs are merged as the sorted Tea is sent on. Spilled Tea is
+/// written as JSON, so the Tea must be serializable.
+pub struct Sort<T: Send> {
+    pub name: String,
+    pub compare: Comparator<T>,
+    /// Most Tea held in memory before it is spilled to disk.
+    pub memory_budget: usize,
+    /// Most Tea sent on in one batch.
+    pub batch_size: usize,
+    /// Directory the run files are written to.
+    pub spill_dir: PathBuf,
+    sorting: Mutex<Sorting<T>>,
+}
+
+impl<T: Send> Sort<T> {
+    ///
+    /// Creates a Sort ordering Tea with a comparator, holding up to 100000 Tea in memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the step
+    /// * `compare` - order Tea is sorted in
+    pub fn new<F>(name: &str, compare: F) -> Sort<T>
+        where F: Fn(&T, &T) -> Ordering + Send + Sync + 'static
+    {
+        Sort {
+            name: String::from(name),
+            compare: Box::new(compare),
+            memory_budget: 100_000,
+            batch_size: 1000,
+            spill_dir: env::temp_dir(),
+            sorting: Mutex::new(Sorting { buffer: Vec::new(), runs: Vec::new() }),
+        }
+    }
+
+    ///
+    /// Creates a Sort ordering Tea by a key.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the step
+    /// * `key` - key Tea is sorted by, smallest first
+    pub fn by_key<K: Ord + 'static>(name: &str, key: fn(&T) -> K) -> Sort<T> where T: 'static {
+        Sort::new(name, move |a, b| key(a).cmp(&key(b)))
+    }
+
+    ///
+    /// Sets the most Tea held in memory before it is spilled to disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory_budget` - number of Tea held in memory
+    pub fn memory_budget(mut self, memory_budget: usize) -> Sort<T> {
+        self.memory_budget = memory_budget;
+        self
+    }
+
+    ///
+    /// Sets the most Tea sent on in one batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch_size` - size of the sorted batches
+    pub fn batch_size(mut self, batch_size: usize) -> Sort<T> {
+        self.batch_size = batch_size;
+        self
+    }
+
+    ///
+    /// Sets the directory the run files are written to.
+    ///
+    /// # Arguments
+    ///
+    /// * `spill_dir` - directory for temporary files
+    pub fn spill_dir<P: Into<PathBuf>>(mut self, spill_dir: P) -> Sort<T> {
+        self.spill_dir = spill_dir.into();
+        self
+    }
+}
+
+impl<T: Send + Serialize + DeserializeOwned> Sort<T> {
+    ///
+    /// Sorts the Tea and writes it to a new run file, handing the Tea back if it cannot.
+    fn spill(&self, mut tea: Vec<T>) -> Result<PathBuf, (Vec<T>, io::Error)> {
+        tea.sort_by(|a, b| (self.compare)(a, b));
+        let path = self.spill_dir.join(format!(
+            "rettle_sort_{}_{}.run",
+            std::process::id(),
+            NEXT_RUN.fetch_add(1, AtomicOrdering::SeqCst),
+        ));
+        match write_run(&path, &tea) {
+            Ok(()) => Ok(path),
+            Err(error) => {
+                let _ = fs::remove_file(&path);
+                Err((tea, error))
+            },
+        }
+    }
+
+    ///
+    /// Records a failure in the brew's errors.
+    fn failed(&self, reason: String) {
+        match context::current() {
+            Some(context) => context.step_failed(&self.name, reason),
+            None => println!("Error: step {} failed: {}", self.name, reason),
+        }
+    }
+
+    ///
+    /// Merges the runs and the Tea left in memory, sending the sorted Tea on in batches.
+    fn merge(&self, mut buffer: Vec<T>, runs: &[PathBuf], send: &mut dyn FnMut(Vec<T>)) -> io::Result<()> {
+        buffer.sort_by(|a, b| (self.compare)(a, b));
+        let mut sources: Vec<Run<T>> = Vec::with_capacity(runs.len() + 1);
+        for path in runs {
+            sources.push(Run::open(path)?);
+        }
+        sources.push(Run::memory(buffer));
+
+        let mut heads = Vec::with_capacity(sources.len());
+        for source in sources.iter_mut() {
+            heads.push(source.next()?);
+        }
+
+        let mut tea_batch = Vec::with_capacity(self.batch_size);
+        loop {
+            // Runs are few next to the Tea in them, so the smallest head is found by a scan.
+            let mut smallest: Option<usize> = None;
+            for (index, head) in heads.iter().enumerate() {
+                if let Some(tea) = head {
+                    let is_smaller = match smallest.and_then(|smallest| heads[smallest].as_ref()) {
+                        Some(current) => (self.compare)(tea, current) == Ordering::Less,
+                        None => true,
+                    };
+                    if is_smaller {
+                        smallest = Some(index);
+                    }
+                }
+            }
+            let index = match smallest {
+                Some(index) => index,
+                None => break,
+            };
+
+            let next = sources[index].next()?;
+            tea_batch.push(std::mem::replace(&mut heads[index], next).unwrap());
+            if tea_batch.len() == self.batch_size {
+                send(std::mem::replace(&mut tea_batch, Vec::with_capacity(self.batch_size)));
+            }
+        }
+        if !tea_batch.is_empty() {
+            send(tea_batch);
+        }
+        Ok(())
+    }
+}
+
+///
+/// Writes sorted Tea to a run file, one JSON Tea per line.
+fn write_run<T: Serialize>(path: &PathBuf, tea: &[T]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for tea in tea {
+        serde_json::to_writer(&mut writer, tea)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()
+}
+
+///
+/// Sorted Tea read back one at a time, from a run file or memory.
+enum Run<T> {
+    File(Lines<BufReader<File>>),
+    Memory(std::vec::IntoIter<T>),
+}
+
+impl<T: DeserializeOwned> Run<T> {
+    fn open(path: &PathBuf) -> io::Result<Run<T>> {
+        Ok(Run::File(BufReader::new(File::open(path)?).lines()))
+    }
+
+    fn memory(tea: Vec<T>) -> Run<T> {
+        Run::Memory(tea.into_iter())
+    }
+
+    fn next(&mut self) -> io::Result<Option<T>> {
+        match self {
+            Run::File(lines) => match lines.next() {
+                Some(line) => Ok(Some(serde_json::from_str(&line?)?)),
+                None => Ok(None),
+            },
+            Run::Memory(tea) => Ok(tea.next()),
+        }
+    }
+}
+
+impl<T: Send + Serialize + DeserializeOwned + 'static> Ingredient<T> for Sort<T> {
+    fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
+        self.exec_batches(tea_batch);
+        Vec::new()
+    }
+    fn exec_batches(&self, tea_batch: Vec<T>) -> Vec<Vec<T>> {
+        let full = {
+            let mut sorting = self.sorting.lock().unwrap();
+            sorting.buffer.extend(tea_batch);
+            if sorting.buffer.len() > self.memory_budget {
+                Some(std::mem::take(&mut sorting.buffer))
+            } else {
+                None
+            }
+        };
+        // The run is sorted and written without holding up other Brewers.
+        if let Some(tea) = full {
+            match self.spill(tea) {
+                Ok(run) => self.sorting.lock().unwrap().runs.push(run),
+                // The Tea was sorted in place, so it can stay in memory and be merged later.
+                Err((tea, error)) => {
+                    self.failed(format!("could not spill to {:?}: {}", self.spill_dir, error));
+                    self.sorting.lock().unwrap().buffer.extend(tea);
+                },
+            }
+        }
+        // Nothing is sent on until the brew ends.
+        Vec::new()
+    }
+    fn flush(&self) -> Vec<Vec<T>> {
+        let mut tea_batches = Vec::new();
+        self.flush_into(&mut |tea_batch| tea_batches.push(tea_batch));
+        tea_batches
+    }
+    fn flush_into(&self, send: &mut dyn FnMut(Vec<T>)) {
+        let (buffer, runs) = {
+            let mut sorting = self.sorting.lock().unwrap();
+            (std::mem::take(&mut sorting.buffer), std::mem::take(&mut sorting.runs))
+        };
+        let merged = self.merge(buffer, &runs, send);
+        for run in &runs {
+            let _ = fs::remove_file(run);
+        }
+        if let Err(error) = merged {
+            self.failed(format!("could not merge sorted runs: {}", error));
+        }
+    }
+    fn get_name(&self) -> &str {
+        &self.name[..]
+    }
+    fn kind(&self) -> &str {
+        "Sort"
+    }
+    fn print(&self) {
+        println!("Current Step: {}", self.get_name());
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn validate(&self) -> Result<(), String> {
+        if self.batch_size == 0 {
+            return Err(String::from("batch_size must be greater than 0"));
+        }
+        if self.memory_budget == 0 {
+            return Err(String::from("memory_budget must be greater than 0"));
+        }
+        if !self.spill_dir.is_dir() {
+            return Err(format!("spill_dir {:?} is not a directory", self.spill_dir));
+        }
+        Ok(())
+    }
+}
+
+impl<T: Send> Drop for Sort<T> {
+    fn drop(&mut self) {
+        // Runs of a brew that never finished are not left behind.
+        if let Ok(sorting) = self.sorting.lock() {
+            for run in &sorting.runs {
+                let _ = fs::remove_file(run);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sort;
+    use super::super::ingredient::{Fill, Pour, Argument, Ingredient};
+    use super::super::brewery::{Brewery, make_tea};
+    use super::super::pot::Pot;
+    use serde::{Serialize, Deserialize};
+    use std::any::Any;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq, Default, Clone, Serialize, Deserialize)]
+    struct TestTea {
+        id: u32,
+        name: String,
+    }
+
+    fn tea(id: u32) -> TestTea {
+        TestTea { id, name: format!("tea_{}", id) }
+    }
+
+    fn ids(tea_batches: Vec<Vec<TestTea>>) -> Vec<Vec<u32>> {
+        tea_batches.into_iter().map(|tea_batch| tea_batch.into_iter().map(|tea| tea.id).collect()).collect()
+    }
+
+    fn spill_dir(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rettle_sort_{}_{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn run_files(dir: &PathBuf) -> usize {
+        fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn sort_holds_tea_until_flushed() {
+        let sort = Sort::by_key("sort", |tea: &TestTea| tea.id).batch_size(4);
+        assert!(sort.exec_batches(vec![tea(5), tea(1), tea(9)]).is_empty());
+        assert!(sort.exec_batches(vec![tea(3), tea(7), tea(2)]).is_empty());
+        assert_eq!(ids(sort.flush()), vec![vec![1, 2, 3, 5], vec![7, 9]]);
+        assert!(sort.flush().is_empty());
+    }
+
+    #[test]
+    fn sort_uses_comparator() {
+        let sort = Sort::new("sort", |a: &TestTea, b: &TestTea| b.id.cmp(&a.id));
+        sort.exec_batches(vec![tea(2), tea(8), tea(4)]);
+        assert_eq!(ids(sort.flush()), vec![vec![8, 4, 2]]);
+    }
+
+    #[test]
+    fn sort_spills_and_merges_runs() {
+        let dir = spill_dir("spill");
+        let sort = Sort::by_key("sort", |tea: &TestTea| tea.id)
+            .memory_budget(3)
+            .batch_size(5)
+            .spill_dir(&dir);
+        for chunk in (0 .. 20).rev().collect::<Vec<u32>>().chunks(2) {
+            sort.exec_batches(chunk.iter().map(|id| tea(*id)).collect());
+        }
+        let runs = sort.sorting.lock().unwrap().runs.len();
+        assert_eq!(runs, 5);
+        assert_eq!(run_files(&dir), 5);
+
+        let tea_batches = sort.flush();
+        assert_eq!(ids(tea_batches.clone()), vec![
+            vec![0, 1, 2, 3, 4],
+            vec![5, 6, 7, 8, 9],
+            vec![10, 11, 12, 13, 14],
+            vec![15, 16, 17, 18, 19],
+        ]);
+        assert_eq!(tea_batches[0][0], tea(0));
+        assert_eq!(run_files(&dir), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sort_removes_runs_when_dropped() {
+        let dir = spill_dir("drop");
+        let sort = Sort::by_key("sort", |tea: &TestTea| tea.id).memory_budget(1).spill_dir(&dir);
+        sort.exec_batches(vec![tea(2), tea(1)]);
+        assert_eq!(run_files(&dir), 1);
+        drop(sort);
+        assert_eq!(run_files(&dir), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sort_validates_settings() {
+        let sort: Sort<TestTea> = Sort::by_key("sort", |tea: &TestTea| tea.id).batch_size(0);
+        assert_eq!(sort.validate(), Err(String::from("batch_size must be greater than 0")));
+        let sort: Sort<TestTea> = Sort::by_key("sort", |tea: &TestTea| tea.id).memory_budget(0);
+        assert_eq!(sort.validate(), Err(String::from("memory_budget must be greater than 0")));
+        let sort: Sort<TestTea> = Sort::by_key("sort", |tea: &TestTea| tea.id).spill_dir("/rettle/not/a/dir");
+        assert!(sort.validate().is_err());
+        let sort: Sort<TestTea> = Sort::by_key("sort", |tea: &TestTea| tea.id);
+        assert_eq!(sort.validate(), Ok(()));
+    }
+
+    struct PouredArgs {
+        pub poured: Arc<Mutex<Vec<Vec<TestTea>>>>,
+    }
+
+    impl Argument for PouredArgs {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn sorted_tea_flows_to_later_steps() {
+        let dir = spill_dir("brew");
+        let poured = Arc::new(Mutex::new(Vec::new()));
+        let new_pot = Pot::new()
+            .add_source(Box::new(Fill {
+                name: String::from("unsorted"),
+                source: String::from("hardcoded"),
+                computation: Box::new(|_args, brewery, recipe| {
+                    for x in 0 .. 10 {
+                        let recipe = Arc::clone(&recipe);
+                        let tea_batch = (0 .. 10).map(|y| tea((y * 10 + x) * 7 % 100)).collect();
+                        brewery.take_order(|| {
+                            make_tea(tea_batch, recipe);
+                        });
+                    }
+                }),
+                params: None,
+            }))
+            .add_ingredient(Box::new(Sort::by_key("sort", |tea: &TestTea| tea.id)
+                .memory_budget(25)
+                .batch_size(30)
+                .spill_dir(&dir)))
+            .add_ingredient(Box::new(Pour {
+                name: String::from("pour_sorted"),
+                computation: Box::new(|tea_batch: Vec<TestTea>, args| {
+                    let args = args.as_ref().unwrap().as_any().downcast_ref::<PouredArgs>().unwrap();
+                    args.poured.lock().unwrap().push(tea_batch.clone());
+                    tea_batch
+                }),
+                params: Some(Box::new(PouredArgs { poured: Arc::clone(&poured) })),
+            }));
+        let brewery = Brewery::new(4);
+        let report = new_pot.brew(&brewery).unwrap();
+
+        let poured = poured.lock().unwrap();
+        assert_eq!(poured.iter().map(|tea_batch| tea_batch.len()).collect::<Vec<usize>>(), vec![30, 30, 30, 10]);
+        let sorted: Vec<u32> = poured.iter().flatten().map(|tea| tea.id).collect();
+        assert_eq!(sorted, (0 .. 100).collect::<Vec<u32>>());
+        assert_eq!(report.steps["sort"].records_in, 100);
+        assert_eq!(report.steps["sort"].records_out, 100);
+        assert!(report.errors.is_empty());
+        assert_eq!(run_files(&dir), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,7 @@
-use crate::ingredient::{Fill, Ingredient};
+use crate::ingredient::{Fill, SharedRecipe, validate_params};
 use crate::brewery::Brewery;
 
 use std::any::Any;
-use std::sync::{Arc, RwLock};
 
 ///
 /// Trait given to Box elements added to Pot for pulling in raw data.
@@ -14,7 +13,7 @@ pub trait Source<T: Send> {
     ///
     /// * `brewery` - Brewery that sends job to process Tea
     /// * `recipe` - clone of recipe to pass to Brewery
-    fn collect(&self, brewery: &Brewery, recipe: Arc<RwLock<Vec<Box<dyn Ingredient<T> + Send + Sync>>>>);
+    fn collect(&self, brewery: &Brewery, recipe: SharedRecipe<T>);
 
     ///
     /// Used to convert Box<dyn Ingredient> to Any to unwrap Ingredient. 
@@ -31,10 +30,23 @@ pub trait Source<T: Send> {
     ///
     /// Returns source given to Ingredient.
     fn get_source(&self) -> &str;
+
+    ///
+    /// Returns the kind of source, such as Fill, shown in diagrams of the Pot.
+    fn kind(&self) -> &str {
+        "Source"
+    }
+
+    ///
+    /// Checks the source can be collected, returning the reason if it cannot. Called when the
+    /// Pot is validated.
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
 }
 
 impl<T: Send + 'static> Source<T> for Fill<T> {
-    fn collect(&self, brewery: &Brewery, recipe: Arc<RwLock<Vec<Box<dyn Ingredient<T> + Send + Sync>>>>) {
+    fn collect(&self, brewery: &Brewery, recipe: SharedRecipe<T>) {
         (self.computation)(self.get_params(), brewery, recipe)
     }
     fn get_name(&self) -> &str {
@@ -43,6 +55,15 @@ impl<T: Send + 'static> Source<T> for Fill<T> {
     fn get_source(&self) -> &str {
         &self.source[..]
     }
+    fn kind(&self) -> &str {
+        "Fill"
+    }
+    fn validate(&self) -> Result<(), String> {
+        if self.get_name().is_empty() {
+            return Err(String::from("source has no name"));
+        }
+        validate_params(self.get_params())
+    }
     fn print(&self) {
         println!("Current Source: {}", self.get_name());
     }
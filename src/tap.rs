@@ -0,0 +1,328 @@
+use crate::context;
+use crate::ingredient::Ingredient;
+
+use serde::Serialize;
+use std::any::Any;
+use std::env;
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+///
+/// Environment variable listing the Taps to turn on, by name separated by commas, or `*` for all.
+pub const TAP_VAR: &str = "RETTLE_TAP";
+
+///
+/// Formats a Tea as the line written by a Tap.
+pub type TapFormat<T> = Box<dyn Fn(&T) -> String + Send + Sync>;
+
+///
+/// Where a Tap writes the Tea it sees.
+#[derive(Debug, Clone)]
+pub enum TapOutput {
+    /// Prints each line.
+    Stdout,
+    /// Appends each line to a file, such as a debug log.
+    File(PathBuf),
+    /// Keeps each line in memory, such as for a test to check.
+    Capture(Arc<Mutex<Vec<String>>>),
+}
+
+///
+/// Ingredient used to look at the Tea passing between two steps without changing it.
+///
+/// Writes the first `limit` Tea it sees in each brew, formatted with `Debug` or as JSON. A Tap is
+/// only turned on when its name is listed in the `RETTLE_TAP` environment variable (or it is
+/// `*`), or when `enabled` is set, so taps can be left in a recipe and switched on while
+/// debugging.
+pub struct Tap<T: Send> {
+    pub name: String,
+    pub format: TapFormat<T>,
+    pub output: TapOutput,
+    /// Most Tea written by the Tap.
+    pub limit: usize,
+    pub enabled: bool,
+    seen: AtomicUsize,
+    file: Mutex<Option<File>>,
+}
+
+impl<T: Send> Tap<T> {
+    ///
+    /// Creates a Tap printing the first 10 Tea with a format, turned on by `RETTLE_TAP`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the step
+    /// * `format` - line written for each Tea
+    pub fn new<F>(name: &str, format: F) -> Tap<T>
+        where F: Fn(&T) -> String + Send + Sync + 'static
+    {
+        Tap {
+            name: String::from(name),
+            format: Box::new(format),
+            output: TapOutput::Stdout,
+            limit: 10,
+            enabled: tap_enabled(name, env::var(TAP_VAR).ok().as_deref()),
+            seen: AtomicUsize::new(0),
+            file: Mutex::new(None),
+        }
+    }
+
+    ///
+    /// Creates a Tap writing Tea pretty-printed with `Debug`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the step
+    pub fn debug(name: &str) -> Tap<T> where T: Debug {
+        Tap::new(name, |tea| format!("{:#?}", tea))
+    }
+
+    ///
+    /// Creates a Tap writing Tea as JSON.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the step
+    pub fn json(name: &str) -> Tap<T> where T: Serialize {
+        Tap::new(name, |tea| {
+            serde_json::to_string(tea).unwrap_or_else(|error| format!("<not serializable: {}>", error))
+        })
+    }
+
+    ///
+    /// Sets where the Tap writes the Tea it sees.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - stdout, file or captured lines
+    pub fn output(mut self, output: TapOutput) -> Tap<T> {
+        self.output = output;
+        self
+    }
+
+    ///
+    /// Sets the most Tea written by the Tap.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - number of Tea to write
+    pub fn limit(mut self, limit: usize) -> Tap<T> {
+        self.limit = limit;
+        self
+    }
+
+    ///
+    /// Turns the Tap on or off, such as from a config, whatever `RETTLE_TAP` is set to.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - whether the Tap writes Tea
+    pub fn enabled(mut self, enabled: bool) -> Tap<T> {
+        self.enabled = enabled;
+        self
+    }
+
+    ///
+    /// Writes lines to the Tap's output.
+    fn write(&self, lines: Vec<String>) -> io::Result<()> {
+        match &self.output {
+            TapOutput::Stdout => {
+                for line in lines {
+                    println!("{}", line);
+                }
+            },
+            TapOutput::File(path) => {
+                let mut file = self.file.lock().unwrap();
+                if file.is_none() {
+                    *file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+                }
+                let file = file.as_mut().unwrap();
+                for line in lines {
+                    writeln!(file, "{}", line)?;
+                }
+            },
+            TapOutput::Capture(captured) => captured.lock().unwrap().extend(lines),
+        }
+        Ok(())
+    }
+
+    ///
+    /// Records a failure in the brew's errors.
+    fn failed(&self, reason: String) {
+        match context::current() {
+            Some(context) => context.step_failed(&self.name, reason),
+            None => println!("Error: step {} failed: {}", self.name, reason),
+        }
+    }
+}
+
+///
+/// Returns true if the `RETTLE_TAP` setting turns on the named Tap.
+fn tap_enabled(name: &str, setting: Option<&str>) -> bool {
+    setting.is_some_and(|setting| {
+        setting.split(',').map(str::trim).any(|tap| tap == "*" || tap == name)
+    })
+}
+
+impl<T: Send + 'static> Ingredient<T> for Tap<T> {
+    fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
+        if !self.enabled || self.seen.load(Ordering::Relaxed) >= self.limit {
+            return tea_batch;
+        }
+        let first = self.seen.fetch_add(tea_batch.len(), Ordering::SeqCst);
+        let lines: Vec<String> = tea_batch
+            .iter()
+            .take(self.limit.saturating_sub(first))
+            .enumerate()
+            .map(|(index, tea)| format!("[{} #{}] {}", self.name, first + index + 1, (self.format)(tea)))
+            .collect();
+        if let Err(error) = self.write(lines) {
+            self.failed(format!("tap output cannot be written: {}", error));
+        }
+        tea_batch
+    }
+    fn finish(&self, _success: bool) -> Result<(), String> {
+        // The next brew writes its own first Tea, to a freshly opened file.
+        self.seen.store(0, Ordering::SeqCst);
+        self.file.lock().unwrap().take();
+        Ok(())
+    }
+    fn get_name(&self) -> &str {
+        &self.name[..]
+    }
+    fn kind(&self) -> &str {
+        "Tap"
+    }
+    fn print(&self) {
+        println!("Current Step: {}", self.get_name());
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Tap, TapOutput, tap_enabled};
+    use super::super::brewery::{Brewery, make_tea};
+    use super::super::context::BrewError;
+    use super::super::ingredient::{Fill, Pour, Ingredient};
+    use super::super::pot::Pot;
+    use serde::Serialize;
+    use std::env;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq, Default, Clone, Serialize)]
+    struct TestTea {
+        id: u32,
+    }
+
+    fn batch(ids: std::ops::Range<u32>) -> Vec<TestTea> {
+        ids.map(|id| TestTea { id }).collect()
+    }
+
+    #[test]
+    fn tap_setting_names_taps() {
+        assert!(!tap_enabled("tap", None));
+        assert!(!tap_enabled("tap", Some("")));
+        assert!(!tap_enabled("tap", Some("other")));
+        assert!(tap_enabled("tap", Some("other, tap")));
+        assert!(tap_enabled("tap", Some("*")));
+    }
+
+    #[test]
+    fn tap_writes_first_tea_unchanged() {
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let tap = Tap::json("tap")
+            .enabled(true)
+            .limit(3)
+            .output(TapOutput::Capture(Arc::clone(&captured)));
+        assert_eq!(tap.exec(batch(0 .. 2)), batch(0 .. 2));
+        assert_eq!(tap.exec(batch(2 .. 4)), batch(2 .. 4));
+        assert_eq!(tap.exec(batch(4 .. 6)), batch(4 .. 6));
+        assert_eq!(*captured.lock().unwrap(), vec![
+            String::from("[tap #1] {\"id\":0}"),
+            String::from("[tap #2] {\"id\":1}"),
+            String::from("[tap #3] {\"id\":2}"),
+        ]);
+    }
+
+    #[test]
+    fn tap_pretty_prints_debug() {
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let tap = Tap::debug("tap").enabled(true).output(TapOutput::Capture(Arc::clone(&captured)));
+        tap.exec(batch(7 .. 8));
+        assert_eq!(*captured.lock().unwrap(), vec![String::from("[tap #1] TestTea {\n    id: 7,\n}")]);
+    }
+
+    #[test]
+    fn disabled_tap_writes_nothing() {
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let tap = Tap::json("tap").enabled(false).output(TapOutput::Capture(Arc::clone(&captured)));
+        assert_eq!(tap.exec(batch(0 .. 5)), batch(0 .. 5));
+        assert!(captured.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn tap_appends_to_file() {
+        let path = env::temp_dir().join(format!("rettle_tap_{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let tap = Tap::json("tap").enabled(true).output(TapOutput::File(path.clone()));
+        tap.exec(batch(0 .. 2));
+        tap.exec(batch(2 .. 3));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[tap #1] {\"id\":0}\n[tap #2] {\"id\":1}\n[tap #3] {\"id\":2}\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tap_writes_again_after_finish() {
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let tap = Tap::json("tap")
+            .enabled(true)
+            .limit(1)
+            .output(TapOutput::Capture(Arc::clone(&captured)));
+        tap.exec(batch(0 .. 2));
+        tap.finish(true).unwrap();
+        tap.exec(batch(5 .. 7));
+        assert_eq!(*captured.lock().unwrap(), vec![
+            String::from("[tap #1] {\"id\":0}"),
+            String::from("[tap #1] {\"id\":5}"),
+        ]);
+    }
+
+    #[test]
+    fn failed_tap_writes_are_reported() {
+        let new_pot = Pot::new()
+            .add_source(Box::new(Fill {
+                name: String::from("fill"),
+                source: String::from("hardcoded"),
+                computation: Box::new(|_args, brewery, recipe| {
+                    brewery.take_order(move || make_tea(batch(0 .. 2), recipe));
+                }),
+                params: None,
+            }))
+            // A directory cannot be opened as the Tap's file.
+            .add_ingredient(Box::new(Tap::json("tap").enabled(true).output(TapOutput::File(env::temp_dir()))))
+            .add_ingredient(Box::new(Pour {
+                name: String::from("pour"),
+                computation: Box::new(|tea_batch: Vec<TestTea>, _args| tea_batch),
+                params: None,
+            }));
+        let report = new_pot.brew(&Brewery::new(1)).unwrap();
+
+        assert_eq!(report.orders_completed, 1);
+        assert_eq!(report.errors.len(), 1);
+        match &report.errors[0] {
+            BrewError::StepFailed { step, reason } => {
+                assert_eq!(step, "tap");
+                assert!(reason.starts_with("tap output cannot be written: "));
+            },
+            error => panic!("unexpected error {:?}", error),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::ingredient::{Ingredient, Steps};
+
+///
+/// Sources, steps and the flow of Tea between them, drawn as a diagram of a Pot.
+pub(crate) struct Topology {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+}
+
+struct Node {
+    name: String,
+    kind: String,
+    source: bool,
+}
+
+struct Edge {
+    from: usize,
+    to: usize,
+    label: Option<String>,
+}
+
+impl Topology {
+    pub(crate) fn new() -> Topology {
+        Topology { nodes: Vec::new(), edges: Vec::new() }
+    }
+
+    ///
+    /// Adds a source, returning its id.
+    pub(crate) fn source(&mut self, name: &str, kind: &str) -> usize {
+        self.node(name, kind, true)
+    }
+
+    ///
+    /// Adds a step, returning its id.
+    pub(crate) fn step(&mut self, name: &str, kind: &str) -> usize {
+        self.node(name, kind, false)
+    }
+
+    fn node(&mut self, name: &str, kind: &str, source: bool) -> usize {
+        self.nodes.push(Node { name: String::from(name), kind: String::from(kind), source });
+        self.nodes.len() - 1
+    }
+
+    ///
+    /// Adds Tea flowing from one node to another, labelled with a branch or route if any.
+    pub(crate) fn edge(&mut self, from: usize, to: usize, label: Option<&str>) {
+        self.edges.push(Edge { from, to, label: label.map(String::from) });
+    }
+
+    ///
+    /// Adds steps brewed one after another, along with their sub-recipes, returning the id of the
+    /// first step, if any.
+    pub(crate) fn steps<T: Send>(&mut self, steps: &Steps<T>) -> Option<usize> {
+        let mut first = None;
+        let mut previous: Option<usize> = None;
+        for step in steps {
+            let id = self.ingredient(&**step);
+            match previous {
+                Some(previous) => self.edge(previous, id, None),
+                None => first = Some(id),
+            }
+            previous = Some(id);
+        }
+        first
+    }
+
+    ///
+    /// Adds a step along with its sub-recipes, returning its id.
+    pub(crate) fn ingredient<T: Send>(&mut self, step: &dyn Ingredient<T>) -> usize {
+        let id = self.step(step.get_name(), step.kind());
+        for (name, sub_recipe) in step.sub_recipes() {
+            if let Some(entry) = self.steps(sub_recipe) {
+                self.edge(id, entry, Some(name));
+            }
+        }
+        id
+    }
+
+    ///
+    /// Renders the diagram in Graphviz DOT.
+    pub(crate) fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph pot {\n    rankdir=LR;\n");
+        for (id, node) in self.nodes.iter().enumerate() {
+            let shape = if node.source { "cylinder" } else { "box" };
+            dot.push_str(&format!(
+                "    n{} [label=\"{}\\n({})\", shape={}];\n",
+                id, escape_dot(&node.name), escape_dot(&node.kind), shape,
+            ));
+        }
+        for edge in &self.edges {
+            match &edge.label {
+                Some(label) => dot.push_str(&format!("    n{} -> n{} [label=\"{}\"];\n", edge.from, edge.to, escape_dot(label))),
+                None => dot.push_str(&format!("    n{} -> n{};\n", edge.from, edge.to)),
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    ///
+    /// Renders the diagram as a Mermaid flowchart.
+    pub(crate) fn to_mermaid(&self) -> String {
+        let mut mermaid = String::from("flowchart LR\n");
+        for (id, node) in self.nodes.iter().enumerate() {
+            let label = format!("{}<br/>({})", escape_mermaid(&node.name), escape_mermaid(&node.kind));
+            if node.source {
+                mermaid.push_str(&format!("    n{}[(\"{}\")]\n", id, label));
+            } else {
+                mermaid.push_str(&format!("    n{}[\"{}\"]\n", id, label));
+            }
+        }
+        for edge in &self.edges {
+            match &edge.label {
+                Some(label) => mermaid.push_str(&format!("    n{} -->|\"{}\"| n{}\n", edge.from, escape_mermaid(label), edge.to)),
+                None => mermaid.push_str(&format!("    n{} --> n{}\n", edge.from, edge.to)),
+            }
+        }
+        mermaid
+    }
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_mermaid(text: &str) -> String {
+    text.replace('"', "#quot;")
+}
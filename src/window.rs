@@ -0,0 +1,490 @@
+use crate::aggregate::{self, GroupKey, Partial, Reducer, Reduced};
+use crate::context;
+use crate::ingredient::Ingredient;
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+///
+/// Event time of a Tea, in milliseconds since the Unix epoch.
+pub type EventTime<T> = fn(&T) -> u64;
+
+///
+/// Builds the Tea sent on for a closed window from its key, its bounds and the result of each
+/// Reducer, in the order the Reducers were added.
+pub type WindowFinish<T> = fn(&str, &WindowBounds, Vec<Reduced<T>>) -> T;
+
+///
+/// How Tea is split into windows by event time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WindowKind {
+    /// Back to back windows of a fixed size.
+    Tumbling { size: Duration },
+    /// Fixed size windows starting every `slide`, so each Tea can fall in several windows. When
+    /// `slide` is longer than `size`, Tea in the gap between windows falls in none and is rejected.
+    Sliding { size: Duration, slide: Duration },
+    /// Windows of activity per key, closed by a gap with no Tea.
+    Session { gap: Duration },
+}
+
+///
+/// Event time covered by a window, in milliseconds since the Unix epoch. The end is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WindowBounds {
+    pub start: u64,
+    pub end: u64,
+}
+
+///
+/// Window still collecting Tea.
+struct OpenWindow<T> {
+    bounds: WindowBounds,
+    partials: Vec<Partial<T>>,
+}
+
+///
+/// Open windows of every key, and the latest event time seen.
+struct Windows<T> {
+    open: HashMap<String, Vec<OpenWindow<T>>>,
+    max_event_time: Option<u64>,
+}
+
+///
+/// Ingredient used to aggregate Tea from continuous sources over windows of event time.
+///
+/// The watermark trails the latest event time seen by `watermark_delay`. A window closes once
+/// the watermark passes its end by more than `allowed_lateness`, and its result is sent on to
+/// the next steps as new Tea straight away. Tea arriving for a window that has already closed
+/// is dropped and counted as rejected. Windows still open when the brew ends are sent on then.
+pub struct Window<T: Send> {
+    pub name: String,
+    pub kind: WindowKind,
+    pub event_time: Box<EventTime<T>>,
+    pub key: Box<GroupKey<T>>,
+    pub reducers: Vec<Reducer<T>>,
+    pub finish: Box<WindowFinish<T>>,
+    /// How far the watermark trails the latest event time seen.
+    pub watermark_delay: Duration,
+    /// How long after the watermark passes a window it still accepts late Tea.
+    pub allowed_lateness: Duration,
+    /// Most Tea sent on in one batch.
+    pub batch_size: usize,
+    windows: Mutex<Windows<T>>,
+}
+
+impl<T: Send> Window<T> {
+    ///
+    /// Creates a Window with no Reducers, no watermark delay and no allowed lateness.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the step
+    /// * `kind` - tumbling, sliding or session windows
+    /// * `event_time` - event time of each Tea
+    /// * `key` - key Tea is grouped by within each window
+    /// * `finish` - builds the Tea sent on for each closed window
+    pub fn new(name: &str, kind: WindowKind, event_time: EventTime<T>, key: GroupKey<T>, finish: WindowFinish<T>) -> Window<T> {
+        Window {
+            name: String::from(name),
+            kind,
+            event_time: Box::new(event_time),
+            key: Box::new(key),
+            reducers: Vec::new(),
+            finish: Box::new(finish),
+            watermark_delay: Duration::from_millis(0),
+            allowed_lateness: Duration::from_millis(0),
+            batch_size: 1000,
+            windows: Mutex::new(Windows { open: HashMap::new(), max_event_time: None }),
+        }
+    }
+
+    ///
+    /// Adds a Reducer run over each window.
+    ///
+    /// # Arguments
+    ///
+    /// * `reducer` - the reduction to add
+    pub fn reduce(mut self, reducer: Reducer<T>) -> Window<T> {
+        self.reducers.push(reducer);
+        self
+    }
+
+    ///
+    /// Sets how far the watermark trails the latest event time seen.
+    ///
+    /// # Arguments
+    ///
+    /// * `delay` - expected out of orderness of the source
+    pub fn watermark_delay(mut self, delay: Duration) -> Window<T> {
+        self.watermark_delay = delay;
+        self
+    }
+
+    ///
+    /// Sets how long after the watermark passes a window it still accepts late Tea.
+    ///
+    /// # Arguments
+    ///
+    /// * `lateness` - time a window is held open past the watermark
+    pub fn allowed_lateness(mut self, lateness: Duration) -> Window<T> {
+        self.allowed_lateness = lateness;
+        self
+    }
+
+    ///
+    /// Sets the most Tea sent on in one batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch_size` - size of the batches of window results
+    pub fn batch_size(mut self, batch_size: usize) -> Window<T> {
+        self.batch_size = batch_size;
+        self
+    }
+
+    ///
+    /// Returns true once a window ending at `end` can no longer receive Tea.
+    fn is_closed(&self, end: u64, watermark: Option<u64>) -> bool {
+        watermark.is_some_and(|watermark| end + millis(self.allowed_lateness) <= watermark)
+    }
+
+    ///
+    /// Returns the bounds of the fixed windows a Tea at `time` falls in, if any.
+    fn fixed_windows(&self, time: u64) -> Vec<WindowBounds> {
+        let (size, slide) = match self.kind {
+            WindowKind::Tumbling { size } => (millis(size), millis(size)),
+            WindowKind::Sliding { size, slide } => (millis(size), millis(slide)),
+            WindowKind::Session { .. } => return Vec::new(),
+        };
+        let last = time - time % slide;
+        let mut bounds = Vec::new();
+        let mut start = last;
+        loop {
+            // Windows shorter than their slide leave a gap the Tea may fall in.
+            if time < start + size {
+                bounds.push(WindowBounds { start, end: start + size });
+            }
+            if start < slide || start - slide + size <= time {
+                break;
+            }
+            start -= slide;
+        }
+        bounds.reverse();
+        bounds
+    }
+
+    ///
+    /// Adds a Tea to the windows of its key, returning false if they have all closed.
+    fn add(&self, windows: &mut Windows<T>, tea: T, watermark: Option<u64>) -> bool {
+        let time = (self.event_time)(&tea);
+        let open = windows.open.entry((self.key)(&tea)).or_default();
+
+        if let WindowKind::Session { gap } = self.kind {
+            let mut session = OpenWindow {
+                bounds: WindowBounds { start: time, end: time + millis(gap) },
+                partials: self.reducers.iter().map(Partial::new).collect(),
+            };
+            if self.is_closed(session.bounds.end, watermark) {
+                return false;
+            }
+            // A Tea can bridge the gap between sessions, joining them into one.
+            let mut index = 0;
+            while index < open.len() {
+                let other = &open[index].bounds;
+                if other.start < session.bounds.end && session.bounds.start < other.end {
+                    let other = open.swap_remove(index);
+                    session.bounds.start = session.bounds.start.min(other.bounds.start);
+                    session.bounds.end = session.bounds.end.max(other.bounds.end);
+                    aggregate::merge(&mut session.partials, other.partials);
+                } else {
+                    index += 1;
+                }
+            }
+            aggregate::accumulate(&mut session.partials, &self.reducers, tea);
+            open.push(session);
+            return true;
+        }
+
+        let bounds: Vec<WindowBounds> = self.fixed_windows(time)
+            .into_iter()
+            .filter(|bounds| !self.is_closed(bounds.end, watermark))
+            .collect();
+        if bounds.is_empty() {
+            return false;
+        }
+        for bounds in bounds {
+            let index = match open.iter().position(|window| window.bounds == bounds) {
+                Some(index) => index,
+                None => {
+                    open.push(OpenWindow { bounds, partials: self.reducers.iter().map(Partial::new).collect() });
+                    open.len() - 1
+                },
+            };
+            for (partial, reducer) in open[index].partials.iter_mut().zip(&self.reducers) {
+                partial.add(reducer, &tea);
+            }
+        }
+        // Tumbling windows hold each Tea once, so it can be collected.
+        if let Some(window) = open.iter_mut().find(|window| window.bounds.start <= time && time < window.bounds.end) {
+            if let Some(Partial::Collect(collected)) = window.partials.iter_mut().find(|partial| matches!(partial, Partial::Collect(_))) {
+                collected.push(tea);
+            }
+        }
+        true
+    }
+
+    ///
+    /// Removes the windows closed by the watermark, or every window if `all` is true, returning
+    /// their results in order of window end.
+    fn close(&self, windows: &mut Windows<T>, watermark: Option<u64>, all: bool) -> Vec<T> {
+        let mut closed = Vec::new();
+        for (key, open) in windows.open.iter_mut() {
+            let mut index = 0;
+            while index < open.len() {
+                if all || self.is_closed(open[index].bounds.end, watermark) {
+                    closed.push((key.clone(), open.swap_remove(index)));
+                } else {
+                    index += 1;
+                }
+            }
+        }
+        windows.open.retain(|_, open| !open.is_empty());
+
+        closed.sort_by(|a, b| (a.1.bounds.end, a.1.bounds.start, &a.0).cmp(&(b.1.bounds.end, b.1.bounds.start, &b.0)));
+        closed
+            .into_iter()
+            .map(|(key, window)| {
+                let reduced = window.partials.into_iter().map(Partial::reduce).collect();
+                (self.finish)(&key, &window.bounds, reduced)
+            })
+            .collect()
+    }
+}
+
+///
+/// Whole milliseconds in a Duration.
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+impl<T: Send + 'static> Ingredient<T> for Window<T> {
+    fn exec(&self, tea_batch: Vec<T>) -> Vec<T> {
+        self.exec_batches(tea_batch).into_iter().flatten().collect()
+    }
+    fn exec_batches(&self, tea_batch: Vec<T>) -> Vec<Vec<T>> {
+        let mut windows = self.windows.lock().unwrap();
+        let watermark = |windows: &Windows<T>| {
+            windows.max_event_time.map(|time| time.saturating_sub(millis(self.watermark_delay)))
+        };
+
+        let mut late = 0;
+        for tea in tea_batch {
+            let time = (self.event_time)(&tea);
+            let current = watermark(&windows);
+            if self.add(&mut windows, tea, current) {
+                windows.max_event_time = Some(windows.max_event_time.map_or(time, |max| max.max(time)));
+            } else {
+                late += 1;
+            }
+        }
+        if late > 0 {
+            if let Some(context) = context::current() {
+                context.records_rejected(self.get_name(), late);
+            }
+        }
+
+        let current = watermark(&windows);
+        aggregate::rebatch(self.close(&mut windows, current, false), self.batch_size)
+    }
+    fn flush(&self) -> Vec<Vec<T>> {
+        let mut windows = self.windows.lock().unwrap();
+        let closed = self.close(&mut windows, None, true);
+        windows.max_event_time = None;
+        aggregate::rebatch(closed, self.batch_size)
+    }
+    fn get_name(&self) -> &str {
+        &self.name[..]
+    }
+    fn kind(&self) -> &str {
+        "Window"
+    }
+    fn print(&self) {
+        println!("Current Step: {}", self.get_name());
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn validate(&self) -> Result<(), String> {
+        if self.batch_size == 0 {
+            return Err(String::from("batch_size must be greater than 0"));
+        }
+        let collects = self.reducers.iter().filter(|reducer| matches!(reducer, Reducer::Collect)).count();
+        if collects > 1 {
+            return Err(String::from("only one Collect reducer can be used"));
+        }
+        match self.kind {
+            WindowKind::Tumbling { size } if millis(size) == 0 => Err(String::from("window size must be at least 1 ms")),
+            WindowKind::Sliding { size, slide } if millis(size) == 0 || millis(slide) == 0 => {
+                Err(String::from("window size and slide must be at least 1 ms"))
+            },
+            WindowKind::Sliding { .. } if collects > 0 => {
+                Err(String::from("sliding windows cannot Collect Tea, as each Tea falls in several windows"))
+            },
+            WindowKind::Session { gap } if millis(gap) == 0 => Err(String::from("session gap must be at least 1 ms")),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Window, WindowKind, WindowBounds};
+    use super::super::aggregate::{Reducer, Reduced};
+    use super::super::ingredient::{Fill, Pour, Ingredient};
+    use super::super::brewery::{Brewery, make_tea};
+    use super::super::pot::Pot;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq, Default, Clone)]
+    struct TestTea {
+        key: String,
+        time: u64,
+        value: f64,
+    }
+
+    fn tea(key: &str, time: u64) -> TestTea {
+        TestTea { key: String::from(key), time, value: 1.0 }
+    }
+
+    /// Window result carrying the window start as its time and the Tea counted as its value.
+    fn counting(kind: WindowKind) -> Window<TestTea> {
+        Window::new("counts", kind, |tea: &TestTea| tea.time, |tea: &TestTea| tea.key.clone(), |key, bounds, reduced| {
+            let value = match reduced[0] {
+                Reduced::Count(count) => count as f64,
+                _ => 0.0,
+            };
+            TestTea { key: format!("{}@{}-{}", key, bounds.start, bounds.end), time: bounds.start, value }
+        })
+            .reduce(Reducer::Count)
+    }
+
+    fn results(batches: Vec<Vec<TestTea>>) -> Vec<(String, f64)> {
+        batches.into_iter().flatten().map(|tea| (tea.key, tea.value)).collect()
+    }
+
+    fn owned(results: &[(&str, f64)]) -> Vec<(String, f64)> {
+        results.iter().map(|(key, value)| (String::from(*key), *value)).collect()
+    }
+
+    #[test]
+    fn tumbling_windows_close_on_watermark() {
+        let window = counting(WindowKind::Tumbling { size: Duration::from_millis(10) });
+        assert!(window.exec_batches(vec![tea("a", 1), tea("b", 3), tea("a", 9)]).is_empty());
+        assert_eq!(
+            results(window.exec_batches(vec![tea("a", 12)])),
+            owned(&[("a@0-10", 2.0), ("b@0-10", 1.0)])
+        );
+        assert_eq!(results(window.flush()), owned(&[("a@10-20", 1.0)]));
+    }
+
+    #[test]
+    fn late_tea_is_dropped() {
+        let window = counting(WindowKind::Tumbling { size: Duration::from_millis(10) });
+        window.exec_batches(vec![tea("a", 1), tea("a", 25)]);
+        assert!(window.exec_batches(vec![tea("a", 5)]).is_empty());
+        assert_eq!(results(window.flush()), owned(&[("a@20-30", 1.0)]));
+    }
+
+    #[test]
+    fn allowed_lateness_holds_windows_open() {
+        let window = counting(WindowKind::Tumbling { size: Duration::from_millis(10) })
+            .allowed_lateness(Duration::from_millis(10));
+        assert!(window.exec_batches(vec![tea("a", 1), tea("a", 15)]).is_empty());
+        assert!(window.exec_batches(vec![tea("a", 5)]).is_empty());
+        assert_eq!(results(window.exec_batches(vec![tea("a", 20)])), owned(&[("a@0-10", 2.0)]));
+    }
+
+    #[test]
+    fn watermark_delay_waits_for_out_of_order_tea() {
+        let window = counting(WindowKind::Tumbling { size: Duration::from_millis(10) })
+            .watermark_delay(Duration::from_millis(5));
+        assert!(window.exec_batches(vec![tea("a", 1), tea("a", 12), tea("a", 8)]).is_empty());
+        assert_eq!(results(window.exec_batches(vec![tea("a", 15)])), owned(&[("a@0-10", 2.0)]));
+    }
+
+    #[test]
+    fn sliding_windows_overlap() {
+        let window = counting(WindowKind::Sliding { size: Duration::from_millis(10), slide: Duration::from_millis(5) });
+        assert_eq!(window.fixed_windows(7), vec![WindowBounds { start: 0, end: 10 }, WindowBounds { start: 5, end: 15 }]);
+        assert_eq!(window.fixed_windows(3), vec![WindowBounds { start: 0, end: 10 }]);
+        window.exec_batches(vec![tea("a", 3), tea("a", 7)]);
+        assert_eq!(results(window.flush()), owned(&[("a@0-10", 2.0), ("a@5-15", 1.0)]));
+    }
+
+    #[test]
+    fn hopping_windows_skip_gaps() {
+        let window = counting(WindowKind::Sliding { size: Duration::from_millis(5), slide: Duration::from_millis(10) });
+        assert_eq!(window.fixed_windows(3), vec![WindowBounds { start: 0, end: 5 }]);
+        assert!(window.fixed_windows(7).is_empty());
+        assert_eq!(window.fixed_windows(12), vec![WindowBounds { start: 10, end: 15 }]);
+        assert_eq!(results(window.exec_batches(vec![tea("a", 3), tea("a", 7), tea("a", 12)])), owned(&[("a@0-5", 1.0)]));
+        assert_eq!(results(window.flush()), owned(&[("a@10-15", 1.0)]));
+    }
+
+    #[test]
+    fn sessions_join_across_gaps() {
+        let window = counting(WindowKind::Session { gap: Duration::from_millis(15) })
+            .watermark_delay(Duration::from_millis(30));
+        assert!(window.exec_batches(vec![tea("a", 0), tea("a", 25), tea("b", 3)]).is_empty());
+        // Bridges the two sessions of key a.
+        assert_eq!(
+            results(window.exec_batches(vec![tea("a", 12), tea("a", 80)])),
+            owned(&[("b@3-18", 1.0), ("a@0-40", 3.0)])
+        );
+        assert_eq!(results(window.flush()), owned(&[("a@80-95", 1.0)]));
+    }
+
+    #[test]
+    fn sliding_windows_cannot_collect() {
+        let window = counting(WindowKind::Sliding { size: Duration::from_millis(10), slide: Duration::from_millis(5) })
+            .reduce(Reducer::Collect);
+        assert!(window.validate().is_err());
+        let window = counting(WindowKind::Tumbling { size: Duration::from_millis(0) });
+        assert!(window.validate().is_err());
+    }
+
+    #[test]
+    fn late_tea_is_reported_as_rejected() {
+        let new_pot = Pot::new()
+            .add_source(Box::new(Fill {
+                name: String::from("log_tea"),
+                source: String::from("hardcoded"),
+                computation: Box::new(|_args, brewery, recipe| {
+                    let tea_batches: Vec<Vec<TestTea>> = vec![vec![tea("a", 1), tea("a", 25)], vec![tea("a", 5), tea("a", 26)]];
+                    for tea_batch in tea_batches {
+                        let recipe = Arc::clone(&recipe);
+                        brewery.take_order(|| {
+                            make_tea(tea_batch, recipe);
+                        });
+                    }
+                }),
+                params: None,
+            }))
+            .add_ingredient(Box::new(counting(WindowKind::Tumbling { size: Duration::from_millis(10) })))
+            .add_ingredient(Box::new(Pour {
+                name: String::from("pour_counts"),
+                computation: Box::new(|tea_batch, _args| tea_batch),
+                params: None,
+            }));
+        let brewery = Brewery::new(1);
+        let report = new_pot.brew(&brewery).unwrap();
+        let counts = &report.steps["counts"];
+        assert_eq!(counts.records_in, 4);
+        assert_eq!(counts.records_rejected, 1);
+        // a@0-10 closes while brewing, and a@20-30 when the brew ends.
+        assert_eq!(counts.records_out, 2);
+    }
+}